@@ -0,0 +1,371 @@
+use crate::caster::cast::{encode_event, encode_header};
+use crate::caster::crypt;
+use crate::caster::queue::GapInfo;
+use crate::caster::{CastEvent, CastReader, CastRecord, DecodeError, Metadata};
+use crate::models::unix_millis;
+use crate::pty::{CommandInfo, ExitInfo};
+use anyhow::{Context, bail};
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    io::{BufRead, Read, Write},
+    time::Duration,
+};
+
+// stands in for each byte of redacted input
+pub const REDACTED: &str = "*";
+
+// asciicast v2 header line, https://docs.asciinema.org/manual/asciicast/v2/
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Header {
+    pub version: u8,
+    pub width: u16,
+    pub height: u16,
+    // unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+// writes asciicast v2; `size` (rows, cols) is used when the recording does not carry its own.
+// a reader seeked to a keyframe exports from there, with events before `from` played at once.
+// returns the decode error that ended the recording early, if any
+pub fn export<R: Read>(
+    reader: CastReader<R>,
+    size: (u16, u16),
+    from: f64,
+    out: &mut impl Write,
+) -> anyhow::Result<Option<DecodeError>> {
+    let meta = reader.meta.clone();
+    let mut records = reader.peekable();
+
+    let (mut rows, mut cols) = (meta.rows.unwrap_or(size.0), meta.cols.unwrap_or(size.1));
+    match records.peek() {
+        Some(Ok(CastRecord {
+            event: CastEvent::Keyframe { rows: r, cols: c, .. },
+            ..
+        })) => (rows, cols) = (*r, *c),
+        // v1 recordings made since the size was recorded open with it
+        Some(Ok(CastRecord {
+            time,
+            event: CastEvent::Resize { rows: r, cols: c },
+            ..
+        })) if meta.rows.is_none() && *time == 0.0 => {
+            (rows, cols) = (*r, *c);
+            records.next();
+        }
+        _ => {}
+    }
+    let header = Header {
+        version: 2,
+        width: cols,
+        height: rows,
+        timestamp: Some((meta.timestamp / 1000) as u64),
+        command: (!meta.command.is_empty()).then(|| meta.command.join(" ")),
+        env: meta.term.iter().map(|term| ("TERM".to_owned(), term.clone())).collect(),
+    };
+    serde_json::to_writer(&mut *out, &header)?;
+    out.write_all(b"\n")?;
+
+    let (mut output, mut input) = (Utf8Carry::default(), Utf8Carry::default());
+    let mut last = 0.0;
+    let mut drawn = false;
+    for rec in records {
+        let rec = match rec {
+            Ok(rec) => rec,
+            Err(e) => return Ok(Some(e)),
+        };
+        let time = (rec.time - from).max(0.0);
+        last = time;
+        let (code, data) = match &rec.event {
+            // only the keyframe an export starts at is drawn; later ones repeat the output before them
+            CastEvent::Keyframe { screen, .. } if !drawn => ("o", output.push(screen)),
+            CastEvent::Keyframe { .. } | CastEvent::Checkpoint(_) => continue,
+            CastEvent::Output(data) => ("o", output.push(data)),
+            CastEvent::Input { data, .. } => ("i", input.push(data)),
+            CastEvent::Redacted { len, .. } => ("i", REDACTED.repeat(*len)),
+            CastEvent::Resize { rows, cols } => ("r", format!("{}x{}", cols, rows)),
+            // v2 has no exit event; a marker keeps it visible in players
+            CastEvent::Exit(info) => ("m", exit_label(info)),
+            CastEvent::Command(info) => ("m", command_label(info)),
+            CastEvent::Gap(gap) => ("m", gap_label(gap)),
+        };
+        drawn |= code == "o";
+        if !data.is_empty() {
+            write_event(out, time, code, &data)?;
+        }
+    }
+    // a recording cut off mid-character
+    for (code, rest) in [("o", output.finish()), ("i", input.finish())] {
+        if !rest.is_empty() {
+            write_event(out, last, code, &rest)?;
+        }
+    }
+    Ok(None)
+}
+
+// reads asciicast v2 into the binary format; input has no client, markers are dropped
+pub fn import(input: impl BufRead, out: &mut impl Write) -> anyhow::Result<()> {
+    let mut lines = input.lines();
+    let header = lines.next().context("empty asciicast file")??;
+    let header: Header = serde_json::from_str(&header).context("invalid asciicast header")?;
+    if header.version != 2 {
+        bail!("unsupported asciicast version {}", header.version);
+    }
+
+    let meta = Metadata {
+        timestamp: header.timestamp.map_or_else(unix_millis, |secs| secs as u128 * 1000),
+        command: header
+            .command
+            .iter()
+            .flat_map(|c| c.split_whitespace())
+            .map(str::to_owned)
+            .collect(),
+        rows: Some(header.height),
+        cols: Some(header.width),
+        term: header.env.get("TERM").cloned(),
+        ..Metadata::default()
+    };
+    out.write_all(&encode_header(&meta))?;
+
+    for (n, line) in lines.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let lineno = n + 2;
+        let (time, code, data): (f64, String, String) =
+            serde_json::from_str(&line).with_context(|| format!("invalid event on line {}", lineno))?;
+        let event = match code.as_str() {
+            "o" => CastEvent::Output(data.into_bytes()),
+            "i" => CastEvent::Input {
+                client: None,
+                data: data.into_bytes(),
+            },
+            "r" => {
+                let (cols, rows) = parse_size(&data).with_context(|| format!("invalid size on line {}", lineno))?;
+                CastEvent::Resize { rows, cols }
+            }
+            _ => continue,
+        };
+        let elapsed =
+            Duration::try_from_secs_f64(time.max(0.0)).with_context(|| format!("invalid time on line {}", lineno))?;
+        out.write_all(&encode_event(elapsed, &event))?;
+    }
+    Ok(())
+}
+
+// how a chunk line of the stdout and syslog sinks starts
+pub const CAST_LINE: &str = "[\"cast\",";
+
+// the `log_level = 2` stdout stream: `["cast", [timestamp, base64(zstd(bytes))]]` lines whose
+// bytes, joined in order, make up the recording of the session started at `timestamp`
+pub fn read_log(input: impl BufRead) -> anyhow::Result<BTreeMap<u128, Vec<u8>>> {
+    let mut casts: BTreeMap<u128, Vec<u8>> = BTreeMap::new();
+    for (n, line) in input.lines().enumerate() {
+        let line = line?;
+        // lines from the syslog sink come behind the syslog tag
+        let line = line.find(CAST_LINE).map_or(&line[..], |i| &line[i..]);
+        let Ok((kind, payload)) = serde_json::from_str::<(String, serde_json::Value)>(line) else {
+            continue;
+        };
+        if kind != "cast" {
+            continue;
+        }
+        let chunk = || -> anyhow::Result<(u128, Vec<u8>)> {
+            let (timestamp, b64): (u128, String) = serde_json::from_value(payload)?;
+            let zst = base64::engine::general_purpose::STANDARD.decode(b64)?;
+            if crypt::is_encrypted(&zst) {
+                bail!("encrypted chunk, run `xterm-rs decrypt` first");
+            }
+            Ok((timestamp, zstd::stream::decode_all(&zst[..])?))
+        };
+        let (timestamp, bytes) = chunk().with_context(|| format!("invalid cast chunk on line {}", n + 1))?;
+        casts.entry(timestamp).or_default().extend(bytes);
+    }
+    Ok(casts)
+}
+
+fn write_event(out: &mut impl Write, time: f64, code: &str, data: &str) -> anyhow::Result<()> {
+    // v1 stored f32 seconds; digits past the microsecond would only print its rounding error
+    let time = (time * 1e6).round() / 1e6;
+    serde_json::to_writer(&mut *out, &(time, code, data))?;
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
+fn exit_label(info: &ExitInfo) -> String {
+    match &info.signal {
+        Some(sig) => format!("exit ({})", sig),
+        None => format!("exit {}", info.code),
+    }
+}
+
+fn command_label(info: &CommandInfo) -> String {
+    match info.exit_code {
+        Some(code) => format!("$ {} (exit {})", info.command, code),
+        None => format!("$ {}", info.command),
+    }
+}
+
+fn gap_label(gap: &GapInfo) -> String {
+    match gap.events {
+        0 => format!("gap: {} bytes of output lost", gap.bytes),
+        n => format!("gap: {} events ({} bytes) dropped", n, gap.bytes),
+    }
+}
+
+// "COLSxROWS"
+fn parse_size(s: &str) -> Option<(u16, u16)> {
+    let (cols, rows) = s.split_once('x')?;
+    Some((cols.parse().ok()?, rows.parse().ok()?))
+}
+
+// events are cut wherever the pty read ended, sometimes inside a character; the
+// incomplete tail is held back for the next event
+#[derive(Default)]
+struct Utf8Carry(Vec<u8>);
+
+impl Utf8Carry {
+    fn push(&mut self, data: &[u8]) -> String {
+        let mut buf = std::mem::take(&mut self.0);
+        buf.extend_from_slice(data);
+        let mut text = String::with_capacity(buf.len());
+        let mut rest = &buf[..];
+        while !rest.is_empty() {
+            match std::str::from_utf8(rest) {
+                Ok(s) => {
+                    text.push_str(s);
+                    rest = &[];
+                }
+                Err(e) => {
+                    let (valid, after) = rest.split_at(e.valid_up_to());
+                    text.push_str(std::str::from_utf8(valid).unwrap_or_default());
+                    match e.error_len() {
+                        Some(n) => {
+                            text.push(char::REPLACEMENT_CHARACTER);
+                            rest = &after[n..];
+                        }
+                        None => {
+                            self.0 = after.to_vec();
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+        text
+    }
+
+    fn finish(&mut self) -> String {
+        String::from_utf8_lossy(&std::mem::take(&mut self.0)).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAST: &str = r#"{"version":2,"width":80,"height":24,"timestamp":1700000000,"command":"bash -l","env":{"TERM":"xterm"}}
+[0.5,"o","héllo\r\n"]
+[1.0,"i","ls\r"]
+[1.5,"r","100x30"]
+"#;
+
+    fn export_str(data: &[u8]) -> (String, Option<DecodeError>) {
+        let mut out = Vec::new();
+        let err = export(CastReader::new(data).unwrap(), (24, 80), 0.0, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), err)
+    }
+
+    fn recording(events: &[(f64, CastEvent)]) -> Vec<u8> {
+        let mut data = encode_header(&Metadata {
+            rows: Some(24),
+            cols: Some(80),
+            ..Metadata::default()
+        });
+        for (time, event) in events {
+            data.extend(encode_event(Duration::from_secs_f64(*time), event));
+        }
+        data
+    }
+
+    #[test]
+    fn import_export_round_trip() {
+        let mut data = Vec::new();
+        // markers have nowhere to go and blank lines are skipped
+        let input = format!("{CAST}[2.0,\"m\",\"note\"]\n\n");
+        import(input.as_bytes(), &mut data).unwrap();
+
+        let reader = CastReader::new(&data[..]).unwrap();
+        assert_eq!(reader.meta.timestamp, 1_700_000_000_000);
+        assert_eq!(reader.meta.command, ["bash", "-l"]);
+        assert_eq!(reader.meta.term.as_deref(), Some("xterm"));
+        assert_eq!((reader.meta.rows, reader.meta.cols), (Some(24), Some(80)));
+
+        let (out, err) = export_str(&data);
+        assert!(err.is_none());
+        assert_eq!(out, CAST);
+    }
+
+    #[test]
+    fn import_rejects_bad_input() {
+        let mut out = Vec::new();
+        assert!(import(&b""[..], &mut out).is_err());
+        assert!(import(&br#"{"version":1,"width":80,"height":24}"#[..], &mut out).is_err());
+        let bad_size = r#"{"version":2,"width":80,"height":24}
+[0.1,"r","80by24"]"#;
+        let err = import(bad_size.as_bytes(), &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "invalid size on line 2");
+        let bad_time = r#"{"version":2,"width":80,"height":24}
+[0.1,"o","x"]
+[1e30,"o","x"]"#;
+        let err = import(bad_time.as_bytes(), &mut out).unwrap_err();
+        assert_eq!(err.to_string(), "invalid time on line 3");
+    }
+
+    #[test]
+    fn export_joins_a_char_split_across_events() {
+        let data = recording(&[
+            (0.1, CastEvent::Output(b"h\xc3".to_vec())),
+            (0.2, CastEvent::Output(b"\xa9llo \xff".to_vec())),
+            // cut off for good in the middle of a euro sign
+            (0.3, CastEvent::Output(b"\xe2\x82".to_vec())),
+        ]);
+        let (out, _) = export_str(&data);
+        let events: Vec<_> = out.lines().skip(1).collect();
+        assert_eq!(
+            events,
+            [r#"[0.1,"o","h"]"#, r#"[0.2,"o","éllo �"]"#, r#"[0.3,"o","�"]"#]
+        );
+    }
+
+    #[test]
+    fn export_reports_a_truncated_recording() {
+        let mut data = recording(&[
+            (0.1, CastEvent::Output(b"one".to_vec())),
+            (0.2, CastEvent::Output(b"two".to_vec())),
+        ]);
+        data.truncate(data.len() - 1);
+        let (out, err) = export_str(&data);
+        assert_eq!(out.lines().count(), 2);
+        assert!(matches!(err, Some(DecodeError::Truncated(_))));
+    }
+
+    #[test]
+    fn utf8_carry() {
+        let mut carry = Utf8Carry::default();
+        assert_eq!(carry.push("€".as_bytes().split_at(1).0), "");
+        assert_eq!(carry.push(&"€".as_bytes()[1..2]), "");
+        assert_eq!(carry.push(&"€x".as_bytes()[2..]), "€x");
+        // an invalid byte is replaced rather than held back
+        assert_eq!(carry.push(b"a\x80b"), "a\u{fffd}b");
+        assert_eq!(carry.push(b"\xf0\x9f"), "");
+        assert_eq!(carry.finish(), "\u{fffd}");
+        assert_eq!(carry.finish(), "");
+    }
+}
@@ -1,212 +1,721 @@
-use crate::models::{buf_trim, logger};
-use base64::Engine as _;
-use std::sync::Arc;
+use crate::caster::CastEvent;
+use crate::caster::heartbeat::HeartbeatLog;
+use crate::caster::queue::{Backlog, GapInfo, QueueInfo, QueueOptions, QueuePolicy, QueueStats, SinkQueue};
+use crate::caster::rotate::{ActiveFiles, Rotation};
+use crate::caster::segment::Fsync;
+use crate::caster::ship::Shipper;
+use crate::caster::sink::{self, Health, SinkEvent, SinkSpec};
+use crate::models::logger;
+use crate::pty::{CommandInfo, ExitInfo, Position, PtyEvent, PtyManager, SessionSpec};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
 use std::{
-    fs::OpenOptions,
-    io::{BufWriter, Write},
-    time::{SystemTime, UNIX_EPOCH},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 use tokio::{
-    sync::mpsc,
+    sync::{
+        Notify,
+        broadcast::error::{RecvError, TryRecvError},
+        mpsc::{self, error::TrySendError},
+    },
+    task::JoinHandle,
     time::{self, Duration},
 };
 use unsigned_varint::encode as varint;
-use zstd::stream::encode_all;
+use x25519_dalek::PublicKey;
 
-const HEARTBEAT_FN: &str = "heartbeat.log";
+const HEARTBEAT_QUEUE: usize = 16;
 
+// v2 files open with the magic, the version byte and a varint-length json `Metadata`;
+// v1 files open with a bare u128 of unix millis
+pub(crate) const MAGIC: &[u8; 8] = b"\x89XTCAST\n";
+pub(crate) const VERSION: u8 = 2;
+
+// what the recording is of; only `timestamp` is known for v1 files
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Metadata {
+    // unix millis the session started at
+    pub timestamp: u128,
+    // position of this file among the recording's rotated segments
+    pub segment: u32,
+    pub command: Vec<String>,
+    pub rows: Option<u16>,
+    pub cols: Option<u16>,
+    pub term: Option<String>,
+    pub hostname: Option<String>,
+    pub image_version: Option<String>,
+    pub xterm_rs_version: Option<String>,
+    // hex public key of the server key signing the checkpoints
+    pub key_id: Option<String>,
+    // hex link of the previous segment's closing checkpoint, chaining the segments together
+    pub prev: Option<String>,
+}
+
+impl Metadata {
+    pub fn new(spec: &SessionSpec, timestamp: u128, rows: u16, cols: u16) -> Self {
+        Self {
+            timestamp,
+            segment: 0,
+            command: spec.argv(),
+            rows: Some(rows),
+            cols: Some(cols),
+            term: spec.env_var("TERM").map(str::to_owned),
+            hostname: nix::unistd::gethostname()
+                .ok()
+                .map(|h| h.to_string_lossy().into_owned()),
+            image_version: std::env::var("IMAGE_VERSION").ok(),
+            xterm_rs_version: Some(env!("CARGO_PKG_VERSION").to_owned()),
+            key_id: None,
+            prev: None,
+        }
+    }
+}
+
+pub fn encode_header(meta: &Metadata) -> Vec<u8> {
+    let json = serde_json::to_vec(meta).unwrap_or_default();
+    let mut len_buf = [0u8; 10];
+    let mut v = Vec::with_capacity(MAGIC.len() + 11 + json.len());
+    v.extend_from_slice(MAGIC);
+    v.push(VERSION);
+    v.extend_from_slice(varint::u64(json.len() as u64, &mut len_buf));
+    v.extend_from_slice(&json);
+    v
+}
+
+// the discriminant is the kind byte on disk
 #[derive(Clone, Copy, Debug)]
-enum EventKind {
-    Input,
-    Output,
-    Resize,
+pub enum EventKind {
+    // input of unknown origin: older recordings and imported ones
+    Input = 0,
+    Output = 1,
+    Resize = 2,
+    Exit = 3,
+    // payload is the varint client id followed by the input bytes
+    ClientInput = 4,
+    // payload is the size like resize, then a screen snapshot to draw on a reset terminal
+    Keyframe = 5,
+    // input typed while the terminal did not echo: the varint client id, then the varint byte count
+    Redacted = 6,
+    // payload is the json of the command info
+    Command = 7,
+    // payload is a `seal::Checkpoint`
+    Checkpoint = 8,
+    // payload is the json of a `queue::GapInfo`, what was dropped here
+    Gap = 9,
+}
+
+impl EventKind {
+    pub fn is_input(self) -> bool {
+        matches!(self, EventKind::Input | EventKind::ClientInput | EventKind::Redacted)
+    }
 }
 
 #[derive(Debug)]
 pub struct RawEvt {
-    elapsed: f32,
-    kind: EventKind,
-    payload: Vec<u8>,
+    // microseconds since the session started
+    pub elapsed: u64,
+    pub kind: EventKind,
+    pub payload: Vec<u8>,
 }
 
-fn encode_evt(e: &RawEvt) -> Vec<u8> {
-    // estimate 4(elapsed)+1(kind)+5(varint)+payload
-    let mut v = Vec::with_capacity(10 + e.payload.len());
-    v.extend_from_slice(&e.elapsed.to_le_bytes());
+pub fn encode_evt(e: &RawEvt) -> Vec<u8> {
+    // estimate 10(varint elapsed)+1(kind)+5(varint)+payload
+    let mut v = Vec::with_capacity(16 + e.payload.len());
+    let mut time_buf = [0u8; 10];
+    v.extend_from_slice(varint::u64(e.elapsed, &mut time_buf));
     v.push(e.kind as u8);
 
     let mut len_buf = [0u8; 5];
-    if matches!(e.kind, EventKind::Input | EventKind::Output) {
-        let var = varint::u32(e.payload.len() as u32, &mut len_buf);
-        v.extend_from_slice(var);
-    }
+    let var = varint::u32(e.payload.len() as u32, &mut len_buf);
+    v.extend_from_slice(var);
     v.extend_from_slice(&e.payload);
     v
 }
 
-fn write_binary(file: &mut BufWriter<std::fs::File>, bytes: &[u8]) -> std::io::Result<()> {
-    file.write_all(bytes)?;
-    file.flush()
+pub fn micros(elapsed: Duration) -> u64 {
+    elapsed.as_micros() as u64
+}
+
+// one event in the on-disk format, for tools that write recordings themselves
+pub fn encode_event(elapsed: Duration, event: &CastEvent) -> Vec<u8> {
+    let (kind, payload) = match event {
+        CastEvent::Input { client: Some(id), data } => (EventKind::ClientInput, client_payload(*id, data)),
+        CastEvent::Input { client: None, data } => (EventKind::Input, data.clone()),
+        CastEvent::Output(data) => (EventKind::Output, data.clone()),
+        CastEvent::Resize { rows, cols } => (EventKind::Resize, size_payload(*rows, *cols)),
+        CastEvent::Exit(info) => (EventKind::Exit, serde_json::to_vec(info).unwrap_or_default()),
+        CastEvent::Command(info) => (EventKind::Command, serde_json::to_vec(info).unwrap_or_default()),
+        CastEvent::Checkpoint(checkpoint) => (EventKind::Checkpoint, checkpoint.to_payload()),
+        CastEvent::Gap(gap) => (EventKind::Gap, serde_json::to_vec(gap).unwrap_or_default()),
+        CastEvent::Redacted { client, len } => (EventKind::Redacted, redacted_payload(*client, *len)),
+        CastEvent::Keyframe { rows, cols, screen } => {
+            let mut p = size_payload(*rows, *cols);
+            p.extend_from_slice(screen);
+            (EventKind::Keyframe, p)
+        }
+    };
+    encode_evt(&RawEvt {
+        elapsed: micros(elapsed),
+        kind,
+        payload,
+    })
+}
+
+fn client_payload(client: u64, bytes: &[u8]) -> Vec<u8> {
+    let mut id_buf = [0u8; 10];
+    let id = varint::u64(client, &mut id_buf);
+    let mut payload = Vec::with_capacity(id.len() + bytes.len());
+    payload.extend_from_slice(id);
+    payload.extend_from_slice(bytes);
+    payload
+}
+
+fn redacted_payload(client: u64, len: usize) -> Vec<u8> {
+    let mut len_buf = [0u8; 10];
+    client_payload(client, varint::u64(len as u64, &mut len_buf))
+}
+
+pub fn size_payload(rows: u16, cols: u16) -> Vec<u8> {
+    let mut p = Vec::with_capacity(4);
+    p.extend_from_slice(&rows.to_le_bytes());
+    p.extend_from_slice(&cols.to_le_bytes());
+    p
+}
+
+pub struct CastOptions {
+    pub log_dir: PathBuf,
+    pub keyframe_interval: Option<Duration>,
+    pub rotation: Rotation,
+    pub active: Arc<ActiveFiles>,
+    // signs a checkpoint this often, and as each file is closed
+    pub signing_key: Option<Arc<SigningKey>>,
+    pub checkpoint_interval: Duration,
+    // seals cast files and log chunks so only the holder of the private key reads them
+    pub encrypt_to: Option<PublicKey>,
+    // takes the chunks of http sinks
+    pub shipper: Option<Arc<Shipper>>,
+    pub sinks: Vec<SinkSpec>,
+    pub queue: QueueOptions,
+    pub fsync: Fsync,
+    pub heartbeat: Arc<HeartbeatLog>,
 }
 
 pub struct Caster {
-    cast_tx: mpsc::UnboundedSender<RawEvt>,
-    hb_tx: mpsc::UnboundedSender<u32>,
+    cast_tx: mpsc::Sender<RawEvt>,
+    hb_tx: mpsc::Sender<u32>,
+    queue: QueueOptions,
+    // shared with the caster task, which takes it in once the queue is empty
+    backlog: Arc<Mutex<Backlog>>,
+    stats: Arc<QueueStats>,
+    sinks: Vec<(String, Arc<QueueStats>)>,
+    closing: Arc<Notify>,
+    // held while closing, so a second close waits for the first
+    task: tokio::sync::Mutex<Option<JoinHandle<()>>>,
+    record: Mutex<Option<JoinHandle<()>>>,
+}
+
+// the session's cast queues, for the session listing
+#[derive(Debug, Serialize)]
+pub struct CastInfo {
+    pub policy: QueuePolicy,
+    pub queue_size: usize,
+    // output and events held aside by coalesce
+    pub held_bytes: usize,
+    // the caster's own queue first, then one per sink
+    pub queues: Vec<QueueInfo>,
+}
+
+// hands events to every sink, output gathered between ticks
+struct Fanout {
+    sinks: Vec<SinkQueue>,
+    policy: QueuePolicy,
+    start: Instant,
+    buf: Vec<u8>,
+    max_buffer: usize,
+}
+
+impl Fanout {
+    async fn event(&mut self, evt: RawEvt) {
+        match evt.kind {
+            EventKind::Output => {
+                self.buf.extend_from_slice(evt.payload.as_slice());
+                if self.buf.len() >= self.max_buffer {
+                    self.flush().await;
+                }
+            }
+            EventKind::Keyframe | EventKind::Checkpoint => {}
+            _ => self.send(evt).await,
+        }
+    }
+
+    async fn flush(&mut self) {
+        if self.buf.is_empty() {
+            return;
+        }
+        // all of it: the recording holds exactly what the pty wrote
+        let payload = std::mem::take(&mut self.buf);
+        self.send(RawEvt {
+            elapsed: micros(self.start.elapsed()),
+            kind: EventKind::Output,
+            payload,
+        })
+        .await;
+    }
+
+    async fn close(mut self) {
+        self.flush().await;
+        for sink in self.sinks {
+            sink.close().await;
+        }
+    }
+
+    // every sink gets every event, and keeps what it wants of them
+    async fn send(&mut self, evt: RawEvt) {
+        let event = Arc::new(SinkEvent::from(evt));
+        for sink in &mut self.sinks {
+            sink.send(&event, self.policy).await;
+        }
+    }
 }
 
 impl Caster {
-    pub fn new(
-        log_dir: std::path::PathBuf,
-        start: std::time::Instant,
-        timestamp: u128,
-        verbose_log: bool,
-        verbose_interval: u32,
-        stty_size: (u16, u16), // rows, cols
-    ) -> anyhow::Result<Arc<Self>> {
+    pub fn new(opts: &CastOptions, name: &str, start: Instant, meta: Metadata) -> anyhow::Result<Arc<Self>> {
+        let log_dir = opts.log_dir.clone();
         if log_dir.exists() && !log_dir.is_dir() {
             anyhow::bail!("'{}' exists and is not a directory", log_dir.display());
         }
         std::fs::create_dir_all(&log_dir)?;
 
-        let cast_path = log_dir.join(format!("{}.cast", timestamp));
-        let hb_path = log_dir.join(HEARTBEAT_FN);
-
-        let cast_file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&cast_path)?);
-        let hb_file = BufWriter::new(OpenOptions::new().create(true).append(true).open(&hb_path)?);
-
-        let (cast_tx, mut cast_rx) = mpsc::unbounded_channel::<RawEvt>();
-        let (hb_tx, mut hb_rx) = mpsc::unbounded_channel::<u32>();
+        let mut sinks = Vec::with_capacity(opts.sinks.len());
+        for spec in &opts.sinks {
+            let sink = spec
+                .open(opts, name, start, &meta)
+                .map_err(|e| anyhow::anyhow!("open {:?} cast sink: {}", spec.kind, e))?;
+            sinks.push(sink::spawn(sink, spec.flush(), opts.queue.size));
+        }
+        let sink_stats = sinks.iter().map(|s| (s.name.clone(), Arc::clone(&s.stats))).collect();
+        let heartbeat = Arc::clone(&opts.heartbeat);
 
-        tokio::spawn(async move {
-            let mut cast_file = cast_file;
-            let mut hb_file = hb_file;
+        let (cast_tx, mut cast_rx) = mpsc::channel::<RawEvt>(opts.queue.size);
+        let (hb_tx, mut hb_rx) = mpsc::channel::<u32>(HEARTBEAT_QUEUE);
+        let backlog = Arc::new(Mutex::new(Backlog::default()));
+        let stats = Arc::new(QueueStats::default());
 
-            let mut buf_disk: Vec<u8> = Vec::new();
-            let mut buf_stdout: Vec<u8> = Vec::new();
+        let mut fanout = Fanout {
+            sinks,
+            policy: opts.queue.policy,
+            start,
+            buf: Vec::new(),
+            max_buffer: opts.queue.max_buffer,
+        };
+        let (held, held_stats) = (Arc::clone(&backlog), Arc::clone(&stats));
+        let closing = Arc::new(Notify::new());
+        let close = Arc::clone(&closing);
+        let task = tokio::spawn(async move {
+            let mut hb_health = Health::new(format!("Heartbeat log {}", heartbeat.path().display()));
 
             let mut flush_disk = time::interval(Duration::from_millis(10));
             flush_disk.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
 
-            let mut flush_stdout = time::interval(Duration::from_secs(verbose_interval.into()));
-            flush_stdout.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
-
             // skip the first tick
             flush_disk.tick().await;
-            flush_stdout.tick().await;
-            write_binary(&mut cast_file, &timestamp.to_le_bytes()).ok();
-            if verbose_log {
-                buf_stdout.extend_from_slice(&timestamp.to_le_bytes());
-            }
-
-            let (mut rows, mut cols) = stty_size;
 
             loop {
                 tokio::select! {
-                    Some(evt) = cast_rx.recv() => {
-                        match evt.kind {
-                            EventKind::Input => {
-                                let bytes = encode_evt(&evt);
-                                write_binary(&mut cast_file, &bytes).ok();
-                            }
-                            EventKind::Resize => {
-                                let bytes = encode_evt(&evt);
-                                write_binary(&mut cast_file, &bytes).ok();
-                                rows = u16::from_le_bytes([evt.payload[0], evt.payload[1]]);
-                                cols = u16::from_le_bytes([evt.payload[2], evt.payload[3]]);
-                                if verbose_log {
-                                    buf_stdout.extend_from_slice(&bytes);
-                                }
-                            }
-                            EventKind::Output => {
-                                buf_disk.extend_from_slice(evt.payload.as_slice());
-                            }
-                        }
-                    }
+                    evt = cast_rx.recv() => match evt {
+                        Some(evt) => fanout.event(evt).await,
+                        None => break,
+                    },
 
-                    Some(ts)  = hb_rx.recv() => {
-                        hb_file.write_all(&ts.to_le_bytes()).unwrap();
-                        hb_file.flush().ok();
-                    }
+                    Some(ts) = hb_rx.recv() => hb_health.check("write", heartbeat.write(ts)),
 
                     _ = flush_disk.tick() => {
-                        if !buf_disk.is_empty() {
-                            let idx = buf_trim(&buf_disk, cols, rows as u32 + 20);
-                            let trimmed = &buf_disk[idx..];
-                            let evt = RawEvt {
-                                elapsed: start.elapsed().as_secs_f32(),
-                                kind: EventKind::Output,
-                                payload: trimmed.to_vec(),
-                            };
-                            let bytes = encode_evt(&evt);
-                            write_binary(&mut cast_file, &bytes).ok();
-                            if verbose_log {
-                                buf_stdout.extend_from_slice(&bytes);
-                            }
-                            buf_disk.clear();
-                        }
-                    }
-                    _ = flush_stdout.tick(), if verbose_log => {
-                        if !buf_stdout.is_empty() {
-                            match encode_all(&buf_stdout[..], 3) {
-                                Ok(cmp) => {
-                                    let b64 = base64::engine::general_purpose::STANDARD.encode(&cmp);
-                                    let payload = serde_json::json!([timestamp, b64]);
-                                    logger("cast", payload);
-                                }
-                                Err(e) => {
-                                    logger("error", serde_json::json!(format!("Error encoding cast data: {}", e.to_string())));
-                                }
-                            }
-                            buf_stdout.clear();
+                        // nothing new may come to push the backlog through; with the queue empty
+                        // it is next in line and taken in directly
+                        let events = match held.lock().unwrap() {
+                            mut backlog if !backlog.is_empty() && cast_rx.is_empty() => backlog.take(&held_stats),
+                            _ => Vec::new(),
+                        };
+                        for evt in events {
+                            fanout.event(evt).await;
                         }
+                        fanout.flush().await;
                     }
 
-                    else => break,
+                    _ = close.notified() => break,
                 }
             }
 
-            let _ = cast_file.flush();
-            let _ = hb_file.flush();
+            // what was queued before the close is still recorded, the backlog after it
+            cast_rx.close();
+            while let Some(evt) = cast_rx.recv().await {
+                fanout.event(evt).await;
+            }
+            let events = held.lock().unwrap().take(&held_stats);
+            for evt in events {
+                fanout.event(evt).await;
+            }
+            fanout.close().await;
         });
 
-        Ok(Arc::new(Self { cast_tx, hb_tx }))
+        Ok(Arc::new(Self {
+            cast_tx,
+            hb_tx,
+            queue: opts.queue,
+            backlog,
+            stats,
+            sinks: sink_stats,
+            closing,
+            task: tokio::sync::Mutex::new(Some(task)),
+            record: Mutex::new(None),
+        }))
     }
 
-    pub fn input(&self, elapsed: f32, bytes: Vec<u8>) {
-        self.cast_tx
-            .send(RawEvt {
-                elapsed,
-                kind: EventKind::Input,
-                payload: bytes,
-            })
-            .ok();
-    }
-    pub fn output(&self, elapsed: f32, bytes: Vec<u8>) {
-        self.cast_tx
-            .send(RawEvt {
-                elapsed,
-                kind: EventKind::Output,
-                payload: bytes,
-            })
-            .ok();
-    }
-    pub fn resize(&self, elapsed: f32, rows: u16, cols: u16) {
-        let mut p = Vec::with_capacity(4);
-        p.extend_from_slice(&rows.to_le_bytes());
-        p.extend_from_slice(&cols.to_le_bytes());
-        self.cast_tx
-            .send(RawEvt {
-                elapsed,
-                kind: EventKind::Resize,
-                payload: p,
-            })
-            .ok();
+    // records what is queued and closes the sinks, cast files behind a last checkpoint;
+    // events after it are dropped
+    pub async fn close(&self) {
+        self.closing.notify_one();
+        let mut task = self.task.lock().await;
+        if let Some(task) = task.take() {
+            task.await.ok();
+        }
+    }
+
+    // for a pty that is closing: lets the recording take in its last events, the exit among them,
+    // then closes it
+    pub async fn finish(&self, grace: Duration) {
+        let record = self.record.lock().unwrap().take();
+        if let Some(record) = record {
+            time::timeout(grace, record).await.ok();
+        }
+        self.close().await;
+    }
+
+    pub fn info(&self) -> CastInfo {
+        let queued = (self.cast_tx.max_capacity() - self.cast_tx.capacity()) as u64;
+        let mut queues = vec![self.stats.info("caster", Some(queued))];
+        queues.extend(self.sinks.iter().map(|(name, stats)| stats.info(name, None)));
+        CastInfo {
+            policy: self.queue.policy,
+            queue_size: self.queue.size,
+            held_bytes: self.backlog.lock().unwrap().held_bytes(),
+            queues,
+        }
+    }
+
+    // queues an event by the queue policy
+    async fn push(&self, evt: RawEvt) {
+        if self.queue.policy == QueuePolicy::Block {
+            self.cast_tx.send(evt).await.ok();
+            return;
+        }
+        let mut backlog = self.backlog.lock().unwrap();
+        // what did not fit before goes first, so the recording keeps its order
+        let evt = match backlog.drain(&self.cast_tx, &self.stats) {
+            true => match self.cast_tx.try_send(evt) {
+                Err(TrySendError::Full(evt)) => evt,
+                _ => return,
+            },
+            false => evt,
+        };
+        backlog.hold(evt, &self.queue);
+    }
+
+    pub async fn input(&self, elapsed: Duration, client: u64, bytes: &[u8]) {
+        self.push(RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::ClientInput,
+            payload: client_payload(client, bytes),
+        })
+        .await;
+    }
+    pub async fn redacted(&self, elapsed: Duration, client: u64, len: usize) {
+        self.push(RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::Redacted,
+            payload: redacted_payload(client, len),
+        })
+        .await;
+    }
+    pub async fn output(&self, elapsed: Duration, bytes: Vec<u8>) {
+        self.push(RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::Output,
+            payload: bytes,
+        })
+        .await;
+    }
+    pub async fn resize(&self, elapsed: Duration, rows: u16, cols: u16) {
+        self.push(RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::Resize,
+            payload: size_payload(rows, cols),
+        })
+        .await;
+    }
+    // payload is the json of the exit info
+    pub async fn exit(&self, elapsed: Duration, info: &ExitInfo) {
+        let payload = serde_json::to_vec(info).unwrap_or_default();
+        self.push(RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::Exit,
+            payload,
+        })
+        .await;
+    }
+    // payload is the json of the command info
+    pub async fn command(&self, elapsed: Duration, info: &CommandInfo) {
+        let payload = serde_json::to_vec(info).unwrap_or_default();
+        self.push(RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::Command,
+            payload,
+        })
+        .await;
+    }
+    // output that left the pty history before it was recorded
+    async fn lost(&self, elapsed: Duration, bytes: u64) {
+        let gap = GapInfo { events: 0, bytes };
+        self.stats.dropped(gap);
+        self.push(gap.event(micros(elapsed))).await;
+    }
+    // the session's single recording subscriber: output is captured once, as the pty produced
+    // it, whether zero or many clients are attached
+    pub fn record(self: &Arc<Self>, pty: Arc<PtyManager>, start: std::time::Instant) {
+        let caster = Arc::clone(self);
+        let record = tokio::spawn(async move {
+            let elapsed = || start.elapsed();
+            let from = Position {
+                epoch: pty.epoch(),
+                offset: 0,
+            };
+            let (mut rx, replay) = pty.subscribe(Some(from)).await;
+            if !replay.bytes.is_empty() {
+                caster.output(elapsed(), replay.bytes).await;
+            }
+            let mut next = replay.offset;
+            // the pty keeps a sender of its own, so the channel never closes; the recording ends
+            // with the reader instead, once what it sent before stopping is taken in
+            let mut closed = pty.closed();
+            loop {
+                let event = tokio::select! {
+                    biased;
+                    event = rx.recv() => event,
+                    _ = closed.wait_for(|c| *c) => break,
+                };
+                next = caster.take(&pty, next, event, elapsed()).await;
+            }
+            loop {
+                let event = match rx.try_recv() {
+                    Ok(event) => Ok(event),
+                    Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+                    Err(_) => break,
+                };
+                next = caster.take(&pty, next, event, elapsed()).await;
+            }
+            caster.close().await;
+        });
+        *self.record.lock().unwrap() = Some(record);
+    }
+
+    // one event of the pty; returns the new position
+    async fn take(&self, pty: &PtyManager, next: u64, event: Result<PtyEvent, RecvError>, elapsed: Duration) -> u64 {
+        match event {
+            Ok(PtyEvent::Output { offset, data }) => {
+                let mut next = next;
+                if offset > next {
+                    next = self.catch_up(pty, next, elapsed).await;
+                }
+                let end = offset + data.len() as u64;
+                if end > next {
+                    let skip = next.saturating_sub(offset) as usize;
+                    self.output(elapsed, data[skip..].to_vec()).await;
+                    next = end;
+                }
+                next
+            }
+            Ok(PtyEvent::Exit(info)) => {
+                self.exit(elapsed, &info).await;
+                next
+            }
+            Ok(PtyEvent::Command(info)) => {
+                self.command(elapsed, &info).await;
+                next
+            }
+            Err(RecvError::Lagged(_)) => self.catch_up(pty, next, elapsed).await,
+            Err(RecvError::Closed) => next,
+        }
+    }
+
+    // fetch what the subscriber missed from the pty history; returns the new position
+    async fn catch_up(&self, pty: &PtyManager, next: u64, elapsed: Duration) -> u64 {
+        let replay = pty
+            .replay(Some(Position {
+                epoch: pty.epoch(),
+                offset: next,
+            }))
+            .await;
+        if replay.reset {
+            logger("error", format!("Cast lost output {}..{}", next, replay.offset));
+            self.lost(elapsed, replay.offset.saturating_sub(next)).await;
+        } else {
+            self.output(elapsed, replay.bytes).await;
+        }
+        replay.offset
     }
+
     pub fn heartbeat(&self) {
         let ts_sec = SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .expect("time went backwards")
             .as_secs() as u32;
-        self.hb_tx.send(ts_sec).ok();
+        // a heartbeat that finds the queue full says nothing the next one will not
+        self.hb_tx.try_send(ts_sec).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caster::{CastReader, CastRecord, DecodeError};
+    use crate::pty::RespawnAction;
+    use std::io::Cursor;
+
+    fn meta() -> Metadata {
+        Metadata {
+            timestamp: 1_700_000_000_123,
+            segment: 2,
+            command: vec!["bash".to_owned(), "-l".to_owned()],
+            rows: Some(24),
+            cols: Some(80),
+            term: Some("xterm-256color".to_owned()),
+            ..Metadata::default()
+        }
+    }
+
+    fn recording(events: &[(u64, CastEvent)]) -> Vec<u8> {
+        let mut data = encode_header(&meta());
+        for (ms, event) in events {
+            data.extend(encode_event(Duration::from_millis(*ms), event));
+        }
+        data
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = encode_header(&meta());
+        let reader = CastReader::new(&header[..]).unwrap();
+        assert_eq!(reader.version, VERSION);
+        assert_eq!(
+            serde_json::to_value(&reader.meta).unwrap(),
+            serde_json::to_value(meta()).unwrap()
+        );
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let exit = ExitInfo {
+            code: 1,
+            signal: None,
+            respawn: RespawnAction::None,
+            delay_ms: None,
+        };
+        let data = recording(&[
+            (0, CastEvent::Output(b"$ ".to_vec())),
+            (
+                1,
+                CastEvent::Input {
+                    client: Some(300),
+                    data: b"ls\r".to_vec(),
+                },
+            ),
+            (1, CastEvent::Redacted { client: 300, len: 12 }),
+            (2, CastEvent::Resize { rows: 30, cols: 100 }),
+            (
+                3,
+                CastEvent::Keyframe {
+                    rows: 30,
+                    cols: 100,
+                    screen: b"\x1b[H$ ".to_vec(),
+                },
+            ),
+            (4_000_000, CastEvent::Exit(exit)),
+        ]);
+        let records: Vec<_> = CastReader::new(&data[..]).unwrap().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 6);
+        assert!(matches!(&records[0].event, CastEvent::Output(o) if o == b"$ "));
+        assert!(matches!(&records[1].event, CastEvent::Input { client: Some(300), data } if data == b"ls\r"));
+        assert!(matches!(records[2].event, CastEvent::Redacted { client: 300, len: 12 }));
+        assert!(matches!(records[3].event, CastEvent::Resize { rows: 30, cols: 100 }));
+        assert!(
+            matches!(&records[4].event, CastEvent::Keyframe { rows: 30, cols: 100, screen } if screen == b"\x1b[H$ ")
+        );
+        assert!(matches!(&records[5].event, CastEvent::Exit(ExitInfo { code: 1, .. })));
+        // microseconds survive where v1's f32 seconds would not
+        assert_eq!(records[1].time, 0.001);
+        assert_eq!(records[5].time, 4000.0);
+    }
+
+    #[test]
+    fn unknown_kinds_are_skipped() {
+        let mut data = recording(&[(0, CastEvent::Output(b"a".to_vec()))]);
+        let mut later = encode_evt(&RawEvt {
+            elapsed: 5,
+            kind: EventKind::Output,
+            payload: b"from a later version".to_vec(),
+        });
+        // the kind byte, after the one-byte elapsed varint
+        later[1] = 0x7f;
+        data.extend(later);
+        data.extend(encode_event(
+            Duration::from_millis(6),
+            &CastEvent::Output(b"b".to_vec()),
+        ));
+        let outputs: Vec<_> = CastReader::new(&data[..])
+            .unwrap()
+            .map(|rec| match rec.unwrap().event {
+                CastEvent::Output(o) => o,
+                other => panic!("{other:?}"),
+            })
+            .collect();
+        assert_eq!(outputs, [b"a", b"b"]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let header = encode_header(&meta());
+        let mut newer = header.clone();
+        newer[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(CastReader::new(&newer[..]), Err(DecodeError::Version(v)) if v == VERSION + 1));
+        for cut in [MAGIC.len(), MAGIC.len() + 1, header.len() - 1] {
+            assert!(
+                matches!(CastReader::new(&header[..cut]), Err(DecodeError::Header)),
+                "{cut}"
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_and_seek() {
+        let data = recording(&[
+            (0, CastEvent::Output(b"one".to_vec())),
+            (1, CastEvent::Output(b"two".to_vec())),
+        ]);
+        let mut reader = CastReader::new(Cursor::new(data.clone())).unwrap();
+        let first = reader.next().unwrap().unwrap();
+        let second = reader.next().unwrap().unwrap();
+        assert!(reader.next().is_none());
+
+        reader.seek(second.offset).unwrap();
+        assert!(matches!(reader.next(), Some(Ok(CastRecord { event: CastEvent::Output(o), .. })) if o == b"two"));
+        reader.seek(first.offset).unwrap();
+        assert_eq!(reader.count(), 2);
+
+        let mut reader = CastReader::new(&data[..data.len() - 2]).unwrap();
+        reader.next().unwrap().unwrap();
+        assert!(matches!(reader.next(), Some(Err(DecodeError::Truncated(o))) if o == second.offset));
     }
 }
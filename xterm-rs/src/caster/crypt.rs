@@ -0,0 +1,315 @@
+use chacha20poly1305::{
+    ChaCha20Poly1305, KeyInit,
+    aead::{
+        Payload,
+        stream::{DecryptorBE32, EncryptorBE32},
+    },
+};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant},
+};
+use x25519_dalek::{PublicKey, StaticSecret};
+
+// an encrypted file opens with the magic, the version byte, an ephemeral x25519 public key
+// and the STREAM nonce prefix; then come chunks of a u32 le length and the sealed zstd frame.
+// every chunk is bound to the header, the last one is marked so a cut-off file shows
+pub const MAGIC: &[u8; 8] = b"\x89XTCRYP\n";
+const VERSION: u8 = 1;
+const PREFIX_LEN: usize = 7;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 32 + PREFIX_LEN;
+const INFO: &[u8] = b"xterm-rs cast encryption v1";
+const ZSTD_LEVEL: i32 = 3;
+
+// plaintext is sealed once this much is waiting, or at the first flush this long after the last chunk
+const CHUNK_BYTES: usize = 64 << 10;
+const CHUNK_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecryptError {
+    #[error("not an encrypted recording")]
+    Header,
+    #[error("unsupported encryption version {0}")]
+    Version(u8),
+    #[error("chunk at offset {0} does not decrypt with this key, or was changed")]
+    Chunk(u64),
+    #[error("malformed chunk at offset {0}")]
+    Frame(u64),
+}
+
+fn cipher(shared: &[u8; 32], ephemeral: &PublicKey, recipient: &PublicKey) -> ChaCha20Poly1305 {
+    let salt = [ephemeral.as_bytes().as_slice(), recipient.as_bytes()].concat();
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(INFO, &mut key)
+        .expect("32 bytes is a valid hkdf-sha256 length");
+    ChaCha20Poly1305::new(&key.into())
+}
+
+fn random<const N: usize>() -> io::Result<[u8; N]> {
+    let mut bytes = [0u8; N];
+    getrandom::fill(&mut bytes).map_err(io::Error::other)?;
+    Ok(bytes)
+}
+
+pub fn generate_secret() -> io::Result<StaticSecret> {
+    Ok(StaticSecret::from(random::<32>()?))
+}
+
+// buffers plaintext and writes it out as sealed chunks; `flush` only seals once a chunk is
+// due, `close` (or drop) seals the rest as the last chunk
+pub struct Encryptor<W: Write> {
+    inner: W,
+    header: Vec<u8>,
+    stream: Option<EncryptorBE32<ChaCha20Poly1305>>,
+    buf: Vec<u8>,
+    sealed: Instant,
+}
+
+impl<W: Write> Encryptor<W> {
+    pub fn new(mut inner: W, recipient: &PublicKey) -> io::Result<Self> {
+        let secret = generate_secret()?;
+        let ephemeral = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(recipient);
+        let prefix = random::<PREFIX_LEN>()?;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.push(VERSION);
+        header.extend_from_slice(ephemeral.as_bytes());
+        header.extend_from_slice(&prefix);
+        inner.write_all(&header)?;
+
+        let cipher = cipher(shared.as_bytes(), &ephemeral, recipient);
+        Ok(Self {
+            inner,
+            header,
+            stream: Some(EncryptorBE32::from_aead(cipher, &prefix.into())),
+            buf: Vec::new(),
+            sealed: Instant::now(),
+        })
+    }
+
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let frame = zstd::stream::encode_all(&self.buf[..], ZSTD_LEVEL)?;
+        let payload = Payload {
+            msg: &frame,
+            aad: &self.header,
+        };
+        let sealed = match (last, &mut self.stream) {
+            (_, None) => return Err(io::Error::other("encrypted stream already closed")),
+            (false, Some(stream)) => stream.encrypt_next(payload),
+            (true, stream) => stream.take().unwrap().encrypt_last(payload),
+        }
+        .map_err(|_| io::Error::other("chunk too large to encrypt"))?;
+        self.inner.write_all(&(sealed.len() as u32).to_le_bytes())?;
+        self.inner.write_all(&sealed)?;
+        self.inner.flush()?;
+        self.buf.clear();
+        self.sealed = Instant::now();
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    // seals what is buffered now rather than once a chunk is due
+    pub fn seal_pending(&mut self) -> io::Result<()> {
+        match self.buf.is_empty() {
+            true => Ok(()),
+            false => self.seal(false),
+        }
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        match self.stream {
+            Some(_) => self.seal(true),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Write for Encryptor<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if self.stream.is_none() {
+            return Err(io::Error::other("encrypted stream already closed"));
+        }
+        self.buf.extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() && (self.buf.len() >= CHUNK_BYTES || self.sealed.elapsed() >= CHUNK_INTERVAL) {
+            self.seal(false)?;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Drop for Encryptor<W> {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+// a whole message as one single-chunk stream, for the stdout log
+pub fn seal_message(plain: &[u8], recipient: &PublicKey) -> io::Result<Vec<u8>> {
+    let mut enc = Encryptor::new(Vec::new(), recipient)?;
+    enc.write_all(plain)?;
+    enc.close()?;
+    Ok(std::mem::take(&mut enc.inner))
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+pub struct Decrypted {
+    pub data: Vec<u8>,
+    // false if the file ends before its last chunk, as a recording still being written does
+    pub complete: bool,
+}
+
+pub fn decrypt(data: &[u8], secret: &StaticSecret) -> Result<Decrypted, DecryptError> {
+    if data.len() < HEADER_LEN || !is_encrypted(data) {
+        return Err(DecryptError::Header);
+    }
+    let (header, mut rest) = data.split_at(HEADER_LEN);
+    if header[MAGIC.len()] != VERSION {
+        return Err(DecryptError::Version(header[MAGIC.len()]));
+    }
+    let ephemeral = PublicKey::from(<[u8; 32]>::try_from(&header[MAGIC.len() + 1..MAGIC.len() + 33]).unwrap());
+    let prefix: [u8; PREFIX_LEN] = header[MAGIC.len() + 33..].try_into().unwrap();
+    let shared = secret.diffie_hellman(&ephemeral);
+    let cipher = cipher(shared.as_bytes(), &ephemeral, &PublicKey::from(secret));
+    let mut stream = Some(DecryptorBE32::from_aead(cipher, &prefix.into()));
+
+    let mut out = Decrypted {
+        data: Vec::new(),
+        complete: false,
+    };
+    let mut offset = HEADER_LEN as u64;
+    while let Some(next) = &mut stream
+        && rest.len() >= 4
+    {
+        let len = u32::from_le_bytes(rest[..4].try_into().unwrap()) as usize;
+        let Some(chunk) = rest.get(4..4 + len) else {
+            break;
+        };
+        let payload = || Payload {
+            msg: chunk,
+            aad: header,
+        };
+        // the last chunk is only told apart by failing as a middle one
+        let (frame, last) = match next.decrypt_next(payload()) {
+            Ok(frame) => (frame, false),
+            Err(_) => {
+                let last = stream.take().unwrap();
+                (
+                    last.decrypt_last(payload()).map_err(|_| DecryptError::Chunk(offset))?,
+                    true,
+                )
+            }
+        };
+        out.data
+            .extend(zstd::stream::decode_all(&frame[..]).map_err(|_| DecryptError::Frame(offset))?);
+        rest = &rest[4 + len..];
+        offset += 4 + len as u64;
+        if last && !rest.is_empty() {
+            return Err(DecryptError::Frame(offset));
+        }
+        out.complete = last;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // chunks "one", "two" and "three", then the empty last one; with where the first three start
+    fn sealed(secret: &StaticSecret) -> (Vec<u8>, Vec<usize>) {
+        let mut enc = Encryptor::new(Vec::new(), &PublicKey::from(secret)).unwrap();
+        let mut starts = Vec::new();
+        for part in [&b"one"[..], b"two", b"three"] {
+            starts.push(enc.get_mut().len());
+            enc.write_all(part).unwrap();
+            enc.seal_pending().unwrap();
+        }
+        enc.close().unwrap();
+        assert!(enc.write(b"late").is_err());
+        (std::mem::take(enc.get_mut()), starts)
+    }
+
+    #[test]
+    fn message_round_trip() {
+        let secret = generate_secret().unwrap();
+        let data = seal_message(b"hello", &PublicKey::from(&secret)).unwrap();
+        assert!(is_encrypted(&data));
+        let plain = decrypt(&data, &secret).unwrap();
+        assert_eq!(plain.data, b"hello");
+        assert!(plain.complete);
+    }
+
+    #[test]
+    fn chunks_round_trip() {
+        let secret = generate_secret().unwrap();
+        let (data, _) = sealed(&secret);
+        let plain = decrypt(&data, &secret).unwrap();
+        assert_eq!(plain.data, b"onetwothree");
+        assert!(plain.complete);
+    }
+
+    #[test]
+    fn truncation_is_incomplete() {
+        let secret = generate_secret().unwrap();
+        let (data, starts) = sealed(&secret);
+        // a chunk cut short is left out, and without the last one the stream is not complete
+        for (cut, plain) in [
+            (HEADER_LEN, &b""[..]),
+            (starts[1], b"one"),
+            (starts[1] + 2, b"one"),
+            (starts[2] + 9, b"onetwo"),
+            (data.len() - 1, b"onetwothree"),
+        ] {
+            let out = decrypt(&data[..cut], &secret).unwrap();
+            assert_eq!(out.data, plain, "cut at {cut}");
+            assert!(!out.complete, "cut at {cut}");
+        }
+        assert!(matches!(
+            decrypt(&data[..HEADER_LEN - 1], &secret),
+            Err(DecryptError::Header)
+        ));
+    }
+
+    #[test]
+    fn tampering_is_caught() {
+        let secret = generate_secret().unwrap();
+        let (data, starts) = sealed(&secret);
+
+        let other = generate_secret().unwrap();
+        assert!(matches!(decrypt(&data, &other), Err(DecryptError::Chunk(o)) if o == HEADER_LEN as u64));
+
+        let mut flipped = data.clone();
+        flipped[starts[1] + 6] ^= 1;
+        assert!(matches!(decrypt(&flipped, &secret), Err(DecryptError::Chunk(o)) if o == starts[1] as u64));
+
+        // a chunk left out, or the header changed under the chunks
+        let dropped = [&data[..starts[1]], &data[starts[2]..]].concat();
+        assert!(matches!(decrypt(&dropped, &secret), Err(DecryptError::Chunk(_))));
+        let mut prefix = data.clone();
+        prefix[HEADER_LEN - 1] ^= 1;
+        assert!(matches!(decrypt(&prefix, &secret), Err(DecryptError::Chunk(_))));
+
+        let mut trailing = data.clone();
+        trailing.extend_from_slice(&data[starts[1]..starts[2]]);
+        assert!(matches!(decrypt(&trailing, &secret), Err(DecryptError::Frame(o)) if o == data.len() as u64));
+
+        let mut version = data;
+        version[MAGIC.len()] = VERSION + 1;
+        assert!(matches!(decrypt(&version, &secret), Err(DecryptError::Version(v)) if v == VERSION + 1));
+    }
+}
@@ -0,0 +1,375 @@
+use crate::caster::Metadata;
+use crate::caster::cast::{EventKind, MAGIC, VERSION};
+use crate::caster::queue::GapInfo;
+use crate::caster::seal::Checkpoint;
+use crate::pty::{CommandInfo, ExitInfo};
+use std::io::{self, Read, Seek, SeekFrom};
+
+// far above any single event the caster writes
+const MAX_PAYLOAD: usize = 64 << 20;
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodeError {
+    #[error("missing cast header")]
+    Header,
+    #[error("unsupported cast version {0}")]
+    Version(u8),
+    #[error("truncated event at offset {0}")]
+    Truncated(u64),
+    #[error("unknown event kind {kind} at offset {offset}")]
+    UnknownKind { kind: u8, offset: u64 },
+    #[error("malformed payload at offset {0}")]
+    Payload(u64),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl DecodeError {
+    // where the file stops decoding
+    pub fn offset(&self) -> Option<u64> {
+        match self {
+            DecodeError::Truncated(o) | DecodeError::Payload(o) => Some(*o),
+            DecodeError::UnknownKind { offset, .. } => Some(*offset),
+            DecodeError::Header | DecodeError::Version(_) => Some(0),
+            DecodeError::Io(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum CastEvent {
+    // `client` is none for input of unknown origin
+    Input { client: Option<u64>, data: Vec<u8> },
+    Output(Vec<u8>),
+    Resize { rows: u16, cols: u16 },
+    Exit(ExitInfo),
+    // the screen at this point, drawn on a reset terminal; playback from the start skips these
+    Keyframe { rows: u16, cols: u16, screen: Vec<u8> },
+    // `len` bytes of input typed while echo was off, content not recorded
+    Redacted { client: u64, len: usize },
+    // a shell command that finished, recorded when it did
+    Command(CommandInfo),
+    // the hash chain up to this event, see `seal`
+    Checkpoint(Checkpoint),
+    // events a full queue dropped, or output lost before it was recorded
+    Gap(GapInfo),
+}
+
+#[derive(Debug, Clone)]
+pub struct CastRecord {
+    // seconds since the session started
+    pub time: f64,
+    // file offset of the event, for error reports and seeking
+    pub offset: u64,
+    pub event: CastEvent,
+}
+
+// reads the binary formats written by `Caster`:
+// v1: a u128 unix-millis header, then events of f32 seconds, kind byte and (except resize) a varint length
+// v2: magic, version and metadata, then events of varint microseconds, kind byte and a varint length
+pub struct CastReader<R> {
+    inner: R,
+    offset: u64,
+    pub version: u8,
+    pub meta: Metadata,
+    done: bool,
+}
+
+impl<R: Read> CastReader<R> {
+    pub fn new(mut inner: R) -> Result<Self, DecodeError> {
+        let mut header = [0u8; 16];
+        if read_full(&mut inner, &mut header[..MAGIC.len()])? != MAGIC.len() {
+            return Err(DecodeError::Header);
+        }
+        let mut reader = Self {
+            inner,
+            offset: MAGIC.len() as u64,
+            version: 1,
+            meta: Metadata::default(),
+            done: false,
+        };
+        if header[..MAGIC.len()] != MAGIC[..] {
+            if read_full(&mut reader.inner, &mut header[MAGIC.len()..])? != MAGIC.len() {
+                return Err(DecodeError::Header);
+            }
+            reader.offset = header.len() as u64;
+            reader.meta.timestamp = u128::from_le_bytes(header);
+            return Ok(reader);
+        }
+
+        let mut version = [0u8; 1];
+        if read_full(&mut reader.inner, &mut version)? != 1 {
+            return Err(DecodeError::Header);
+        }
+        reader.offset += 1;
+        reader.version = version[0];
+        if reader.version < 2 || reader.version > VERSION {
+            return Err(DecodeError::Version(reader.version));
+        }
+        let len = reader.read_varint(0)?.ok_or(DecodeError::Header)?;
+        let json = reader.read_payload(0, len as usize).map_err(|_| DecodeError::Header)?;
+        reader.meta = serde_json::from_slice(&json).map_err(|_| DecodeError::Header)?;
+        Ok(reader)
+    }
+
+    // none at a clean end of file
+    pub fn read_event(&mut self) -> Result<Option<CastRecord>, DecodeError> {
+        loop {
+            let start = self.offset;
+            let read = match self.version {
+                1 => self.read_v1(start)?,
+                _ => self.read_v2(start)?,
+            };
+            let Some((time, kind, payload)) = read else {
+                return Ok(None);
+            };
+            match decode_event(kind, payload, start) {
+                Ok(event) => {
+                    return Ok(Some(CastRecord {
+                        time,
+                        offset: start,
+                        event,
+                    }));
+                }
+                // v2 events carry their length, so kinds added later can be stepped over
+                Err(DecodeError::UnknownKind { .. }) if self.version >= 2 => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read_v1(&mut self, start: u64) -> Result<Option<(f64, u8, Vec<u8>)>, DecodeError> {
+        let mut head = [0u8; 5];
+        match read_full(&mut self.inner, &mut head)? {
+            0 => return Ok(None),
+            5 => {}
+            _ => return Err(DecodeError::Truncated(start)),
+        }
+        self.offset += 5;
+        let time = f32::from_le_bytes([head[0], head[1], head[2], head[3]]) as f64;
+        let kind = head[4];
+
+        let payload = if kind == EventKind::Resize as u8 {
+            self.read_payload(start, 4)?
+        } else {
+            let len = self.read_varint(start)?.ok_or(DecodeError::Truncated(start))?;
+            self.read_payload(start, len as usize)?
+        };
+        Ok(Some((time, kind, payload)))
+    }
+
+    fn read_v2(&mut self, start: u64) -> Result<Option<(f64, u8, Vec<u8>)>, DecodeError> {
+        let Some(micros) = self.read_varint(start)? else {
+            return Ok(None);
+        };
+        let kind = self.read_payload(start, 1)?[0];
+        let len = self.read_varint(start)?.ok_or(DecodeError::Truncated(start))?;
+        let payload = self.read_payload(start, len as usize)?;
+        Ok(Some((micros as f64 / 1e6, kind, payload)))
+    }
+
+    // none at end of file before the first byte
+    fn read_varint(&mut self, start: u64) -> Result<Option<u64>, DecodeError> {
+        let mut buf = [0u8; 10];
+        for i in 0..buf.len() {
+            if read_full(&mut self.inner, &mut buf[i..=i])? == 0 {
+                return match i {
+                    0 => Ok(None),
+                    _ => Err(DecodeError::Truncated(start)),
+                };
+            }
+            self.offset += 1;
+            if buf[i] & 0x80 == 0 {
+                let (n, _) = unsigned_varint::decode::u64(&buf[..=i]).map_err(|_| DecodeError::Payload(start))?;
+                return Ok(Some(n));
+            }
+        }
+        Err(DecodeError::Payload(start))
+    }
+
+    fn read_payload(&mut self, start: u64, len: usize) -> Result<Vec<u8>, DecodeError> {
+        // a corrupt length should not turn into a huge allocation
+        if len > MAX_PAYLOAD {
+            return Err(DecodeError::Payload(start));
+        }
+        let mut payload = vec![0u8; len];
+        if read_full(&mut self.inner, &mut payload)? != len {
+            return Err(DecodeError::Truncated(start));
+        }
+        self.offset += len as u64;
+        Ok(payload)
+    }
+}
+
+fn decode_event(kind: u8, payload: Vec<u8>, start: u64) -> Result<CastEvent, DecodeError> {
+    let event = match kind {
+        k if k == EventKind::Input as u8 => CastEvent::Input {
+            client: None,
+            data: payload,
+        },
+        k if k == EventKind::ClientInput as u8 => {
+            let (client, data) = unsigned_varint::decode::u64(&payload).map_err(|_| DecodeError::Payload(start))?;
+            CastEvent::Input {
+                client: Some(client),
+                data: data.to_vec(),
+            }
+        }
+        k if k == EventKind::Redacted as u8 => {
+            let bad = |_| DecodeError::Payload(start);
+            let (client, rest) = unsigned_varint::decode::u64(&payload).map_err(bad)?;
+            let (len, _) = unsigned_varint::decode::usize(rest).map_err(bad)?;
+            CastEvent::Redacted { client, len }
+        }
+        k if k == EventKind::Output as u8 => CastEvent::Output(payload),
+        k if k == EventKind::Resize as u8 && payload.len() == 4 => CastEvent::Resize {
+            rows: u16::from_le_bytes([payload[0], payload[1]]),
+            cols: u16::from_le_bytes([payload[2], payload[3]]),
+        },
+        k if k == EventKind::Exit as u8 => {
+            CastEvent::Exit(serde_json::from_slice(&payload).map_err(|_| DecodeError::Payload(start))?)
+        }
+        k if k == EventKind::Command as u8 => {
+            CastEvent::Command(serde_json::from_slice(&payload).map_err(|_| DecodeError::Payload(start))?)
+        }
+        k if k == EventKind::Gap as u8 => {
+            CastEvent::Gap(serde_json::from_slice(&payload).map_err(|_| DecodeError::Payload(start))?)
+        }
+        k if k == EventKind::Checkpoint as u8 => {
+            CastEvent::Checkpoint(Checkpoint::from_payload(&payload).ok_or(DecodeError::Payload(start))?)
+        }
+        k if k == EventKind::Keyframe as u8 && payload.len() >= 4 => CastEvent::Keyframe {
+            rows: u16::from_le_bytes([payload[0], payload[1]]),
+            cols: u16::from_le_bytes([payload[2], payload[3]]),
+            screen: payload[4..].to_vec(),
+        },
+        k if k == EventKind::Resize as u8 || k == EventKind::Keyframe as u8 => return Err(DecodeError::Payload(start)),
+        kind => return Err(DecodeError::UnknownKind { kind, offset: start }),
+    };
+    Ok(event)
+}
+
+impl<R: Read + Seek> CastReader<R> {
+    // continue at an event boundary, such as a keyframe offset from the index
+    pub fn seek(&mut self, offset: u64) -> Result<(), DecodeError> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.offset = offset;
+        self.done = false;
+        Ok(())
+    }
+}
+
+impl<R: Read> Iterator for CastReader<R> {
+    type Item = Result<CastRecord, DecodeError>;
+
+    // stops after the first error; a recording cut off mid-write ends in `Truncated`
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.read_event().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+// like `read_exact`, but reports how much was read before end of file
+fn read_full(r: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match r.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(k) => n += k,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // a v1 recording as the first caster wrote it
+    fn v1(events: &[(f32, EventKind, &[u8])]) -> Vec<u8> {
+        let mut data = 1_700_000_000_000u128.to_le_bytes().to_vec();
+        for (time, kind, payload) in events {
+            data.extend_from_slice(&time.to_le_bytes());
+            data.push(*kind as u8);
+            if !matches!(kind, EventKind::Resize) {
+                data.push(payload.len() as u8);
+            }
+            data.extend_from_slice(payload);
+        }
+        data
+    }
+
+    fn sample() -> Vec<u8> {
+        v1(&[
+            (0.0, EventKind::Resize, &[24, 0, 80, 0]),
+            (0.5, EventKind::Output, b"$ "),
+            (1.25, EventKind::Input, b"ls\r"),
+        ])
+    }
+
+    #[test]
+    fn decodes_v1() {
+        let mut reader = CastReader::new(Cursor::new(sample())).unwrap();
+        assert_eq!(reader.version, 1);
+        assert_eq!(reader.meta.timestamp, 1_700_000_000_000);
+        let records: Vec<_> = reader.by_ref().collect::<Result<_, _>>().unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(matches!(records[0].event, CastEvent::Resize { rows: 24, cols: 80 }));
+        assert!(matches!(&records[1].event, CastEvent::Output(o) if o == b"$ "));
+        assert!(matches!(&records[2].event, CastEvent::Input { client: None, data } if data == b"ls\r"));
+        assert_eq!((records[1].time, records[2].time), (0.5, 1.25));
+        assert_eq!([records[0].offset, records[1].offset, records[2].offset], [16, 25, 33]);
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn truncated_header() {
+        assert!(matches!(CastReader::new(&[0u8; 3][..]), Err(DecodeError::Header)));
+        assert!(matches!(CastReader::new(&[0u8; 12][..]), Err(DecodeError::Header)));
+    }
+
+    #[test]
+    fn truncated_event_stops_the_iterator() {
+        let data = sample();
+        // cut inside the last payload, then inside the last event head
+        for cut in [data.len() - 1, 36] {
+            let mut reader = CastReader::new(&data[..cut]).unwrap();
+            assert!(reader.next().unwrap().is_ok());
+            assert!(reader.next().unwrap().is_ok());
+            let err = reader.next().unwrap().unwrap_err();
+            assert!(matches!(err, DecodeError::Truncated(33)), "{err}");
+            assert_eq!(err.offset(), Some(33));
+            assert!(reader.next().is_none());
+        }
+    }
+
+    #[test]
+    fn unknown_v1_kind_is_an_error() {
+        let mut data = sample();
+        data[25 + 4] = 0x7f;
+        let mut reader = CastReader::new(Cursor::new(data)).unwrap();
+        reader.next().unwrap().unwrap();
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(
+            matches!(err, DecodeError::UnknownKind { kind: 0x7f, offset: 25 }),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn seek_resumes_at_an_event() {
+        let mut reader = CastReader::new(Cursor::new(sample())).unwrap();
+        let all: Vec<_> = reader.by_ref().collect::<Result<_, _>>().unwrap();
+        reader.seek(all[2].offset).unwrap();
+        let rest: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(rest.len(), 1);
+        assert_eq!(rest[0].offset, 33);
+        assert!(matches!(&rest[0].event, CastEvent::Input { data, .. } if data == b"ls\r"));
+    }
+}
@@ -0,0 +1,328 @@
+use crate::models::unix_millis;
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+pub const HEARTBEAT_FN: &str = "heartbeat.log";
+
+// heartbeat logs open with the magic, a flags byte and the last link of the log rotated before
+// them; then records of the u32 unix seconds a client said it was alive, the chain link over it,
+// and the signature of the link if there is a key
+const MAGIC: &[u8; 8] = b"\x89XTBEAT\n";
+const HEAD_LEN: usize = MAGIC.len() + 1 + 32;
+const SIGNED: u8 = 1;
+// signed along with the link, so a signature means nothing outside a heartbeat log
+const DOMAIN: &[u8] = b"xterm-rs heartbeat\0";
+
+fn record_len(flags: u8) -> usize {
+    match flags & SIGNED {
+        0 => 4 + 32,
+        _ => 4 + 32 + 64,
+    }
+}
+
+// sha256 of the link before and the timestamp
+fn link(prev: &[u8; 32], ts: u32) -> [u8; 32] {
+    Sha256::new()
+        .chain_update(prev)
+        .chain_update(ts.to_le_bytes())
+        .finalize()
+        .into()
+}
+
+fn message(link: &[u8; 32]) -> Vec<u8> {
+    [DOMAIN, link].concat()
+}
+
+// the flags and the last link of a log this version wrote in full
+fn tail(data: &[u8]) -> Option<(u8, [u8; 32])> {
+    let head = data.get(..HEAD_LEN).filter(|head| head.starts_with(MAGIC))?;
+    let flags = head[MAGIC.len()];
+    let records = &data[HEAD_LEN..];
+    let len = record_len(flags);
+    if !records.len().is_multiple_of(len) {
+        return None;
+    }
+    let last = match records.len() {
+        0 => &head[MAGIC.len() + 1..],
+        n => &records[n - len + 4..n - len + 36],
+    };
+    Some((flags, last.try_into().ok()?))
+}
+
+struct Open {
+    file: BufWriter<File>,
+    len: u64,
+    link: [u8; 32],
+}
+
+// heartbeat.log, shared by every caster of the process so its records form one chain
+pub struct HeartbeatLog {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    signing_key: Option<Arc<SigningKey>>,
+    open: Mutex<Option<Open>>,
+}
+
+impl HeartbeatLog {
+    pub fn new(log_dir: &Path, max_bytes: Option<u64>, signing_key: Option<Arc<SigningKey>>) -> Self {
+        Self {
+            path: log_dir.join(HEARTBEAT_FN),
+            max_bytes,
+            signing_key,
+            open: Mutex::new(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn flags(&self) -> u8 {
+        match self.signing_key {
+            Some(_) => SIGNED,
+            None => 0,
+        }
+    }
+
+    // opened with the first heartbeat; moved aside once it reaches `max_bytes`
+    pub fn write(&self, ts: u32) -> io::Result<()> {
+        let mut open = self.open.lock().unwrap();
+        let log = match &mut *open {
+            Some(log) => log,
+            None => open.insert(self.reopen()?),
+        };
+        let link = link(&log.link, ts);
+        let mut record = Vec::with_capacity(record_len(self.flags()));
+        record.extend_from_slice(&ts.to_le_bytes());
+        record.extend_from_slice(&link);
+        if let Some(key) = &self.signing_key {
+            record.extend_from_slice(&key.sign(&message(&link)).to_bytes());
+        }
+        if let Err(e) = log.file.write_all(&record).and_then(|()| log.file.flush()) {
+            // what made it to the file is found by the next open, and moved aside if it is torn
+            *open = None;
+            return Err(e);
+        }
+        log.link = link;
+        log.len += record.len() as u64;
+        if self.max_bytes.is_some_and(|max| log.len >= max) {
+            fs::rename(&self.path, self.rotated_path())?;
+            *open = Some(self.create(link)?);
+        }
+        Ok(())
+    }
+
+    // carries on after the last record, unless the log is torn, signed differently or from
+    // before the chain; then it is moved aside and the new one starts from what it can link to
+    fn reopen(&self) -> io::Result<Open> {
+        let data = match fs::read(&self.path) {
+            Ok(data) if !data.is_empty() => data,
+            Ok(_) => return self.create([0; 32]),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return self.create([0; 32]),
+            Err(e) => return Err(e),
+        };
+        let prev = match tail(&data) {
+            Some((flags, link)) if flags == self.flags() => {
+                let file = OpenOptions::new().append(true).open(&self.path)?;
+                return Ok(Open {
+                    file: BufWriter::new(file),
+                    len: data.len() as u64,
+                    link,
+                });
+            }
+            Some((_, link)) => link,
+            None => [0; 32],
+        };
+        fs::rename(&self.path, self.rotated_path())?;
+        self.create(prev)
+    }
+
+    fn create(&self, prev: [u8; 32]) -> io::Result<Open> {
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        file.write_all(MAGIC)?;
+        file.write_all(&[self.flags()])?;
+        file.write_all(&prev)?;
+        file.flush()?;
+        Ok(Open {
+            file,
+            len: HEAD_LEN as u64,
+            link: prev,
+        })
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        self.path.with_file_name(format!("heartbeat-{}.log", unix_millis()))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub records: usize,
+    pub signed: bool,
+    // the last link of the log rotated before this one, and this one's
+    pub prev: [u8; 32],
+    pub last: [u8; 32],
+    // the first offset that cannot be trusted, and why
+    pub fault: Option<(u64, String)>,
+}
+
+// walks the chain of a heartbeat log; as with cast files, only a key shows it was not recomputed
+pub fn verify(data: &[u8], key: Option<&VerifyingKey>) -> Report {
+    let mut report = Report::default();
+    let Some(head) = data.get(..HEAD_LEN).filter(|head| head.starts_with(MAGIC)) else {
+        report.fault = Some((0, "not a chained heartbeat log".to_owned()));
+        return report;
+    };
+    let flags = head[MAGIC.len()];
+    report.signed = flags & SIGNED != 0;
+    report.prev.copy_from_slice(&head[MAGIC.len() + 1..]);
+    if key.is_some() && !report.signed {
+        report.fault = Some((0, "the heartbeats are not signed".to_owned()));
+        return report;
+    }
+
+    let mut prev = report.prev;
+    let len = record_len(flags);
+    let mut offset = HEAD_LEN;
+    while offset < data.len() {
+        let Some(record) = data.get(offset..offset + len) else {
+            report.fault = Some((offset as u64, "last record cut short".to_owned()));
+            return report;
+        };
+        let ts = u32::from_le_bytes(record[..4].try_into().unwrap());
+        let expected = link(&prev, ts);
+        if record[4..36] != expected {
+            report.fault = Some((offset as u64, "record changed, or one before it".to_owned()));
+            return report;
+        }
+        if let Some(key) = key {
+            let sig = Signature::from_bytes(record[36..].try_into().unwrap());
+            if key.verify_strict(&message(&expected), &sig).is_err() {
+                report.fault = Some((offset as u64, "signature does not match the key".to_owned()));
+                return report;
+            }
+        }
+        report.records += 1;
+        prev = expected;
+        offset += len;
+    }
+    report.last = prev;
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xterm-rs-heartbeat-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // the rotated logs in the order they were written, then the current one
+    fn logs(dir: &Path) -> Vec<Vec<u8>> {
+        let mut rotated: Vec<PathBuf> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.file_name().unwrap() != HEARTBEAT_FN)
+            .collect();
+        rotated.sort();
+        rotated.push(dir.join(HEARTBEAT_FN));
+        rotated.iter().map(|path| fs::read(path).unwrap()).collect()
+    }
+
+    #[test]
+    fn signed_chain() {
+        let dir = log_dir("signed");
+        let key = SigningKey::from_bytes(&[5; 32]);
+        let log = HeartbeatLog::new(&dir, None, Some(Arc::new(key.clone())));
+        for ts in 1..=3 {
+            log.write(ts).unwrap();
+        }
+        let data = fs::read(log.path()).unwrap();
+        let report = verify(&data, Some(&key.verifying_key()));
+        assert_eq!(report.fault, None);
+        assert_eq!((report.records, report.signed, report.prev), (3, true, [0; 32]));
+
+        let other = SigningKey::from_bytes(&[6; 32]).verifying_key();
+        let fault = verify(&data, Some(&other)).fault;
+        assert_eq!(
+            fault,
+            Some((HEAD_LEN as u64, "signature does not match the key".to_owned()))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn broken_link() {
+        let dir = log_dir("broken");
+        let log = HeartbeatLog::new(&dir, None, None);
+        for ts in 1..=3 {
+            log.write(ts).unwrap();
+        }
+        let mut data = fs::read(log.path()).unwrap();
+        // the link of the second record
+        let second = HEAD_LEN + record_len(0);
+        data[second + 4] ^= 1;
+        let report = verify(&data, None);
+        assert_eq!(report.records, 1);
+        assert_eq!(
+            report.fault,
+            Some((second as u64, "record changed, or one before it".to_owned()))
+        );
+
+        data.truncate(HEAD_LEN + 10);
+        let fault = verify(&data, None).fault;
+        assert_eq!(fault, Some((HEAD_LEN as u64, "last record cut short".to_owned())));
+        assert!(verify(b"heartbeat", None).fault.is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_logs_link_on() {
+        let dir = log_dir("rotated");
+        let log = HeartbeatLog::new(&dir, Some((HEAD_LEN + 2 * record_len(0)) as u64), None);
+        for ts in 1..=5 {
+            log.write(ts).unwrap();
+            // rotated logs are named by the millisecond
+            std::thread::sleep(std::time::Duration::from_millis(2));
+        }
+        let reports: Vec<Report> = logs(&dir).iter().map(|data| verify(data, None)).collect();
+        assert_eq!(reports.iter().map(|r| r.records).collect::<Vec<_>>(), [2, 2, 1]);
+        for pair in reports.windows(2) {
+            assert_eq!(pair[0].fault, None);
+            assert_eq!(pair[1].prev, pair[0].last);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reopen_carries_on() {
+        let dir = log_dir("reopen");
+        HeartbeatLog::new(&dir, None, None).write(1).unwrap();
+        // a restarted server appends to the same chain
+        HeartbeatLog::new(&dir, None, None).write(2).unwrap();
+        assert_eq!(logs(&dir).len(), 1);
+        assert_eq!(verify(&logs(&dir)[0], None).records, 2);
+
+        // a torn log is moved aside and the next one starts over
+        let path = dir.join(HEARTBEAT_FN);
+        let mut data = fs::read(&path).unwrap();
+        data.pop();
+        fs::write(&path, data).unwrap();
+        HeartbeatLog::new(&dir, None, None).write(3).unwrap();
+        let logs = logs(&dir);
+        assert_eq!(logs.len(), 2);
+        let report = verify(&logs[1], None);
+        assert_eq!((report.records, report.prev, report.fault), (1, [0; 32], None));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::caster::{CastEvent, CastReader, DecodeError};
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+// an entry of the sidecar `.idx` file: u64 le microseconds, then the u64 le file offset of
+// the keyframe event written at that time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keyframe {
+    pub micros: u64,
+    pub offset: u64,
+}
+
+impl Keyframe {
+    const SIZE: usize = 16;
+
+    pub fn to_bytes(self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[..8].copy_from_slice(&self.micros.to_le_bytes());
+        b[8..].copy_from_slice(&self.offset.to_le_bytes());
+        b
+    }
+
+    fn from_bytes(b: &[u8]) -> Self {
+        Self {
+            micros: u64::from_le_bytes(b[..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(b[8..16].try_into().unwrap()),
+        }
+    }
+
+    pub fn time(&self) -> f64 {
+        self.micros as f64 / 1e6
+    }
+}
+
+// `name.cast` and its compressed `name.cast.zst` share `name.idx`
+pub fn index_path(cast: &Path) -> PathBuf {
+    match cast.extension().is_some_and(|ext| ext == "zst") {
+        true => cast.with_extension("").with_extension("idx"),
+        false => cast.with_extension("idx"),
+    }
+}
+
+// a trailing partial entry, from a crash mid-write, is ignored
+pub fn read_index(path: &Path) -> io::Result<Vec<Keyframe>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+    Ok(data.chunks_exact(Keyframe::SIZE).map(Keyframe::from_bytes).collect())
+}
+
+// for recordings whose index is missing: every keyframe, found by decoding the whole file
+pub fn build_index<R: Read>(mut reader: CastReader<R>) -> Result<Vec<Keyframe>, DecodeError> {
+    let mut index = Vec::new();
+    while let Some(rec) = reader.read_event()? {
+        if let CastEvent::Keyframe { .. } = rec.event {
+            index.push(Keyframe {
+                micros: (rec.time * 1e6).round() as u64,
+                offset: rec.offset,
+            });
+        }
+    }
+    Ok(index)
+}
+
+// the last keyframe at or before `time`
+pub fn keyframe_before(index: &[Keyframe], time: f64) -> Option<Keyframe> {
+    let n = index.partition_point(|k| k.time() <= time);
+    n.checked_sub(1).map(|i| index[i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caster::Metadata;
+    use crate::caster::cast::{encode_event, encode_header};
+    use std::time::Duration;
+
+    fn at(secs: u64, offset: u64) -> Keyframe {
+        Keyframe {
+            micros: secs * 1_000_000,
+            offset,
+        }
+    }
+
+    #[test]
+    fn lookup() {
+        let index = [at(0, 40), at(60, 900), at(120, 2000)];
+        assert_eq!(keyframe_before(&index, 0.0), Some(index[0]));
+        assert_eq!(keyframe_before(&index, 59.9), Some(index[0]));
+        assert_eq!(keyframe_before(&index, 60.0), Some(index[1]));
+        assert_eq!(keyframe_before(&index, 1e9), Some(index[2]));
+        assert_eq!(keyframe_before(&index[1..], 30.0), None);
+        assert_eq!(keyframe_before(&[], 30.0), None);
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(index_path(Path::new("/l/1700.cast")), Path::new("/l/1700.idx"));
+        assert_eq!(index_path(Path::new("/l/1700.cast.zst")), Path::new("/l/1700.idx"));
+        assert_eq!(index_path(Path::new("/l/1700.3.cast.zst")), Path::new("/l/1700.3.idx"));
+    }
+
+    #[test]
+    fn written_and_rebuilt() {
+        let mut data = encode_header(&Metadata::default());
+        let mut written = Vec::new();
+        for secs in [0, 60] {
+            let keyframe = CastEvent::Keyframe {
+                rows: 24,
+                cols: 80,
+                screen: b"$ ".to_vec(),
+            };
+            written.push(at(secs, data.len() as u64));
+            data.extend(encode_event(Duration::from_secs(secs), &keyframe));
+            data.extend(encode_event(
+                Duration::from_secs(secs + 1),
+                &CastEvent::Output(b"ls\r\n".to_vec()),
+            ));
+        }
+        let rebuilt = build_index(CastReader::new(&data[..]).unwrap()).unwrap();
+        assert_eq!(rebuilt, written);
+
+        let path = std::env::temp_dir().join(format!("xterm-rs-index-{}.idx", std::process::id()));
+        let mut bytes: Vec<u8> = written.iter().flat_map(|k| k.to_bytes()).collect();
+        // cut off mid-entry
+        bytes.extend_from_slice(&[1, 2, 3]);
+        std::fs::write(&path, bytes).unwrap();
+        assert_eq!(read_index(&path).unwrap(), written);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,2 +1,14 @@
+pub mod asciicast;
 pub mod cast;
-pub use cast::Caster;
+pub mod crypt;
+pub mod decode;
+pub mod heartbeat;
+pub mod index;
+pub mod queue;
+pub mod rotate;
+pub mod seal;
+pub mod segment;
+pub mod ship;
+pub mod sink;
+pub use cast::{CastInfo, CastOptions, Caster, Metadata};
+pub use decode::{CastEvent, CastReader, CastRecord, DecodeError};
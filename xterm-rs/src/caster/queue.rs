@@ -0,0 +1,473 @@
+use crate::caster::cast::{EventKind, RawEvt};
+use crate::caster::sink::SinkEvent;
+use crate::models::logger;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+// what to do with an event when the queue it goes into is full
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueuePolicy {
+    // wait for room; the recording is complete, the session waits on a slow disk
+    Block,
+    // drop the event and record a gap in its place
+    Drop,
+    // hold events aside, output merged into one, and drop the oldest past the buffer limit
+    #[default]
+    Coalesce,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueueOptions {
+    pub policy: QueuePolicy,
+    // events queued for the caster, and for each sink
+    pub size: usize,
+    // bytes of output held back, by the caster between ticks and by coalesce
+    pub max_buffer: usize,
+}
+
+// the payload of a gap event: what a full queue dropped at this point of the recording
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GapInfo {
+    pub events: u64,
+    pub bytes: u64,
+}
+
+impl GapInfo {
+    // the gap as the event that goes in its place
+    pub fn event(self, elapsed: u64) -> RawEvt {
+        RawEvt {
+            elapsed,
+            kind: EventKind::Gap,
+            payload: serde_json::to_vec(&self).expect("two integers serialize"),
+        }
+    }
+}
+
+// events dropped since the last gap was recorded
+#[derive(Debug, Default)]
+struct Missed {
+    // when the first of them happened
+    since: u64,
+    gap: GapInfo,
+}
+
+impl Missed {
+    fn add(&mut self, elapsed: u64, bytes: usize) {
+        self.merge(
+            elapsed,
+            GapInfo {
+                events: 1,
+                bytes: bytes as u64,
+            },
+        );
+    }
+
+    fn merge(&mut self, elapsed: u64, gap: GapInfo) {
+        if self.is_empty() {
+            self.since = elapsed;
+        }
+        self.gap.events += gap.events;
+        self.gap.bytes += gap.bytes;
+    }
+
+    // a sink that misses a gap misses what the gap stood for
+    fn add_event(&mut self, evt: &SinkEvent) {
+        match evt.gap {
+            Some(gap) => self.merge(evt.elapsed, gap),
+            None => self.add(evt.elapsed, evt.payload.len()),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.gap.events == 0 && self.gap.bytes == 0
+    }
+
+    fn event(&self) -> RawEvt {
+        self.gap.event(self.since)
+    }
+}
+
+// counters of one queue, reported through the session listing
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    queued: AtomicU64,
+    dropped_events: AtomicU64,
+    dropped_bytes: AtomicU64,
+    gaps: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueInfo {
+    pub name: String,
+    pub queued: u64,
+    pub dropped_events: u64,
+    pub dropped_bytes: u64,
+    pub gaps: u64,
+}
+
+impl QueueStats {
+    // counted before the send, so the consumer never takes off more than was put on
+    fn enqueue(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // the send failed, or the consumer took one off the queue
+    pub fn dequeue(&self) {
+        self.queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self, gap: GapInfo) {
+        self.dropped_events.fetch_add(gap.events, Ordering::Relaxed);
+        self.dropped_bytes.fetch_add(gap.bytes, Ordering::Relaxed);
+        self.gaps.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // `queued` for a queue that counts its own depth
+    pub fn info(&self, name: &str, queued: Option<u64>) -> QueueInfo {
+        QueueInfo {
+            name: name.to_owned(),
+            queued: queued.unwrap_or_else(|| self.queued.load(Ordering::Relaxed)),
+            dropped_events: self.dropped_events.load(Ordering::Relaxed),
+            dropped_bytes: self.dropped_bytes.load(Ordering::Relaxed),
+            gaps: self.gaps.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn report(name: &str, gap: GapInfo) {
+    logger(
+        "error",
+        format!(
+            "Cast queue was full, {} recorded a gap of {} events ({} bytes)",
+            name, gap.events, gap.bytes
+        ),
+    );
+}
+
+// what did not fit in the caster queue under drop and coalesce, in order
+#[derive(Debug, Default)]
+pub struct Backlog {
+    missed: Missed,
+    held: VecDeque<RawEvt>,
+    held_bytes: usize,
+}
+
+impl Backlog {
+    pub fn is_empty(&self) -> bool {
+        self.missed.is_empty() && self.held.is_empty()
+    }
+
+    pub fn held_bytes(&self) -> usize {
+        self.held_bytes
+    }
+
+    // sends what it can of the backlog; true once it is empty and newer events may follow
+    pub fn drain(&mut self, tx: &mpsc::Sender<RawEvt>, stats: &QueueStats) -> bool {
+        if !self.missed.is_empty() {
+            match tx.try_send(self.missed.event()) {
+                Ok(()) => {
+                    stats.dropped(self.missed.gap);
+                    report("caster", self.missed.gap);
+                    self.missed = Missed::default();
+                }
+                Err(TrySendError::Full(_)) => return false,
+                Err(TrySendError::Closed(_)) => return true,
+            }
+        }
+        while let Some(evt) = self.held.pop_front() {
+            let len = evt.payload.len();
+            match tx.try_send(evt) {
+                Ok(()) => self.held_bytes -= len,
+                Err(TrySendError::Full(evt)) => {
+                    self.held.push_front(evt);
+                    return false;
+                }
+                Err(TrySendError::Closed(_)) => break,
+            }
+        }
+        true
+    }
+
+    // everything in the backlog, gap first, for the caster to take in directly
+    pub fn take(&mut self, stats: &QueueStats) -> Vec<RawEvt> {
+        let mut events = Vec::with_capacity(self.held.len() + 1);
+        if !self.missed.is_empty() {
+            events.push(self.missed.event());
+            stats.dropped(self.missed.gap);
+            report("caster", self.missed.gap);
+            self.missed = Missed::default();
+        }
+        events.extend(self.held.drain(..));
+        self.held_bytes = 0;
+        events
+    }
+
+    // an event the queue had no room for
+    pub fn hold(&mut self, evt: RawEvt, opts: &QueueOptions) {
+        if opts.policy != QueuePolicy::Coalesce {
+            self.missed.add(evt.elapsed, evt.payload.len());
+            return;
+        }
+        self.held_bytes += evt.payload.len();
+        match (self.held.back_mut(), evt.kind) {
+            (Some(last), EventKind::Output) if matches!(last.kind, EventKind::Output) => {
+                last.payload.extend_from_slice(&evt.payload);
+            }
+            _ => self.held.push_back(evt),
+        }
+        while self.held_bytes > opts.max_buffer
+            && let Some(old) = self.held.pop_front()
+        {
+            self.held_bytes -= old.payload.len();
+            self.missed.add(old.elapsed, old.payload.len());
+        }
+    }
+}
+
+// the caster's side of a sink's queue. under block the caster waits for the sink; otherwise a sink
+// that falls behind misses events, and a gap in their place once it has room again
+pub struct SinkQueue {
+    pub name: String,
+    tx: mpsc::Sender<Arc<SinkEvent>>,
+    pub stats: Arc<QueueStats>,
+    missed: Missed,
+    task: JoinHandle<()>,
+}
+
+impl SinkQueue {
+    pub fn new(name: String, tx: mpsc::Sender<Arc<SinkEvent>>, stats: Arc<QueueStats>, task: JoinHandle<()>) -> Self {
+        Self {
+            name,
+            tx,
+            stats,
+            missed: Missed::default(),
+            task,
+        }
+    }
+
+    // hands over a gap still waiting, then waits for the sink to close
+    pub async fn close(self) {
+        if !self.missed.is_empty() {
+            self.stats.dropped(self.missed.gap);
+            report(&self.name, self.missed.gap);
+            self.stats.enqueue();
+            if self
+                .tx
+                .send(Arc::new(SinkEvent::gap(self.missed.since, self.missed.gap)))
+                .await
+                .is_err()
+            {
+                self.stats.dequeue();
+            }
+        }
+        drop(self.tx);
+        self.task.await.ok();
+    }
+
+    pub async fn send(&mut self, evt: &Arc<SinkEvent>, policy: QueuePolicy) {
+        if policy == QueuePolicy::Block {
+            self.stats.enqueue();
+            if self.tx.send(Arc::clone(evt)).await.is_err() {
+                self.stats.dequeue();
+            }
+            return;
+        }
+        if !self.missed.is_empty() {
+            let gap = Arc::new(SinkEvent::gap(self.missed.since, self.missed.gap));
+            if !self.try_send(gap) {
+                return self.missed.add_event(evt);
+            }
+            self.stats.dropped(self.missed.gap);
+            report(&self.name, self.missed.gap);
+            self.missed = Missed::default();
+        }
+        if !self.try_send(Arc::clone(evt)) {
+            self.missed.add_event(evt);
+        }
+    }
+
+    // false if the sink had no room, or is gone
+    fn try_send(&self, evt: Arc<SinkEvent>) -> bool {
+        self.stats.enqueue();
+        let sent = self.tx.try_send(evt).is_ok();
+        if !sent {
+            self.stats.dequeue();
+        }
+        sent
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(elapsed: u64, data: &[u8]) -> RawEvt {
+        RawEvt {
+            elapsed,
+            kind: EventKind::Output,
+            payload: data.to_vec(),
+        }
+    }
+
+    fn opts(policy: QueuePolicy, max_buffer: usize) -> QueueOptions {
+        QueueOptions {
+            policy,
+            size: 1,
+            max_buffer,
+        }
+    }
+
+    fn gap_of(evt: &RawEvt) -> GapInfo {
+        assert!(matches!(evt.kind, EventKind::Gap), "{evt:?}");
+        serde_json::from_slice(&evt.payload).unwrap()
+    }
+
+    #[test]
+    fn coalesce_merges_output() {
+        let opts = opts(QueuePolicy::Coalesce, 100);
+        let mut backlog = Backlog::default();
+        backlog.hold(output(1, b"ab"), &opts);
+        backlog.hold(output(2, b"cd"), &opts);
+        backlog.hold(
+            RawEvt {
+                elapsed: 3,
+                kind: EventKind::Resize,
+                payload: vec![0; 4],
+            },
+            &opts,
+        );
+        backlog.hold(output(4, b"ef"), &opts);
+        assert_eq!(backlog.held_bytes(), 10);
+
+        let stats = QueueStats::default();
+        let events = backlog.take(&stats);
+        assert_eq!(events.len(), 3);
+        assert_eq!((events[0].elapsed, &events[0].payload[..]), (1, &b"abcd"[..]));
+        assert!(matches!(events[1].kind, EventKind::Resize));
+        assert_eq!(events[2].payload, b"ef");
+        assert!(backlog.is_empty());
+        assert_eq!(backlog.held_bytes(), 0);
+        assert_eq!(stats.info("caster", None).gaps, 0);
+    }
+
+    #[test]
+    fn coalesce_drops_the_oldest_past_the_buffer() {
+        let opts = opts(QueuePolicy::Coalesce, 4);
+        let mut backlog = Backlog::default();
+        backlog.hold(output(1, b"ab"), &opts);
+        backlog.hold(
+            RawEvt {
+                elapsed: 2,
+                kind: EventKind::Input,
+                payload: b"x".to_vec(),
+            },
+            &opts,
+        );
+        backlog.hold(output(3, b"cd"), &opts);
+        // over by one: the oldest event goes, though that frees more than needed
+        assert_eq!(backlog.held_bytes(), 3);
+        backlog.hold(output(4, b"efgh"), &opts);
+        assert_eq!(backlog.held_bytes(), 0);
+
+        let stats = QueueStats::default();
+        let events = backlog.take(&stats);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].elapsed, 1);
+        assert_eq!(gap_of(&events[0]), GapInfo { events: 3, bytes: 9 });
+        let info = stats.info("caster", None);
+        assert_eq!((info.dropped_events, info.dropped_bytes, info.gaps), (3, 9, 1));
+    }
+
+    #[test]
+    fn drop_only_counts() {
+        for policy in [QueuePolicy::Drop, QueuePolicy::Block] {
+            let opts = opts(policy, 100);
+            let mut backlog = Backlog::default();
+            backlog.hold(output(5, b"abc"), &opts);
+            backlog.hold(output(6, b"de"), &opts);
+            assert_eq!(backlog.held_bytes(), 0);
+            let events = backlog.take(&QueueStats::default());
+            assert_eq!(events.len(), 1);
+            assert_eq!(events[0].elapsed, 5);
+            assert_eq!(gap_of(&events[0]), GapInfo { events: 2, bytes: 5 });
+        }
+    }
+
+    #[test]
+    fn drain_stops_at_a_full_queue() {
+        let opts = opts(QueuePolicy::Coalesce, 3);
+        let mut backlog = Backlog::default();
+        backlog.hold(output(1, b"ab"), &opts);
+        backlog.hold(
+            RawEvt {
+                elapsed: 2,
+                kind: EventKind::Resize,
+                payload: vec![0; 4],
+            },
+            &opts,
+        );
+        backlog.hold(output(3, b"c"), &opts);
+
+        let stats = QueueStats::default();
+        let (tx, mut rx) = mpsc::channel(1);
+        tx.try_send(output(0, b"queued")).unwrap();
+        // no room even for the gap, which is only counted once it is sent
+        assert!(!backlog.drain(&tx, &stats));
+        assert_eq!(stats.info("caster", None).gaps, 0);
+        assert_eq!(rx.try_recv().unwrap().payload, b"queued");
+
+        assert!(!backlog.drain(&tx, &stats));
+        assert_eq!(gap_of(&rx.try_recv().unwrap()), GapInfo { events: 2, bytes: 6 });
+        assert_eq!(stats.info("caster", None).gaps, 1);
+        assert_eq!(backlog.held_bytes(), 1);
+
+        assert!(backlog.drain(&tx, &stats));
+        assert_eq!(rx.try_recv().unwrap().payload, b"c");
+        assert!(backlog.is_empty());
+        assert_eq!(backlog.held_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn sink_queue_records_a_gap_once_there_is_room() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let stats = Arc::new(QueueStats::default());
+        let mut queue = SinkQueue::new("test".to_owned(), tx, Arc::clone(&stats), tokio::spawn(async {}));
+        let evt = |elapsed, data: &[u8]| Arc::new(SinkEvent::from(output(elapsed, data)));
+
+        queue.send(&evt(1, b"a"), QueuePolicy::Drop).await;
+        queue.send(&evt(2, b"bc"), QueuePolicy::Drop).await;
+        // a gap the sink misses counts for everything it stood for
+        let gap = Arc::new(SinkEvent::gap(3, GapInfo { events: 4, bytes: 40 }));
+        queue.send(&gap, QueuePolicy::Drop).await;
+        assert_eq!(stats.info("test", None).queued, 1);
+
+        assert_eq!(rx.recv().await.unwrap().payload, b"a");
+        stats.dequeue();
+        queue.send(&evt(4, b"d"), QueuePolicy::Drop).await;
+        let gap = rx.recv().await.unwrap();
+        assert_eq!((gap.elapsed, gap.gap), (2, Some(GapInfo { events: 5, bytes: 42 })));
+        stats.dequeue();
+        let info = stats.info("test", None);
+        assert_eq!(
+            (info.queued, info.dropped_events, info.dropped_bytes, info.gaps),
+            (0, 5, 42, 1)
+        );
+
+        // the event behind the gap found no room either, and is handed over as a gap on close
+        queue.close().await;
+        let gap = rx.recv().await.unwrap();
+        assert_eq!((gap.elapsed, gap.gap), (4, Some(GapInfo { events: 1, bytes: 1 })));
+        assert!(rx.recv().await.is_none());
+    }
+}
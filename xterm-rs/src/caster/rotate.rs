@@ -0,0 +1,340 @@
+use crate::caster::crypt;
+use crate::models::logger;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::time;
+
+const RETENTION_PERIOD: Duration = Duration::from_secs(60);
+const ZSTD_LEVEL: i32 = 9;
+const ZSTD_MAGIC: &[u8] = b"\x28\xb5\x2f\xfd";
+
+// when a recording moves on to a new segment
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rotation {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    // zstd finished segments to `<segment>.cast.zst`
+    pub compress: bool,
+}
+
+// what may stay in the log directory; the oldest recordings go first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    pub max_bytes: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub max_files: Option<usize>,
+}
+
+impl Retention {
+    pub fn enabled(&self) -> bool {
+        self.max_bytes.is_some() || self.max_age.is_some() || self.max_files.is_some()
+    }
+}
+
+// files still being written or compressed, which retention leaves alone
+#[derive(Debug, Default)]
+pub struct ActiveFiles(Mutex<HashSet<PathBuf>>);
+
+impl ActiveFiles {
+    pub fn insert(&self, path: &Path) {
+        self.0.lock().unwrap().insert(path.to_path_buf());
+    }
+
+    pub fn remove(&self, path: &Path) {
+        self.0.lock().unwrap().remove(path);
+    }
+
+    fn contains(&self, path: &Path) -> bool {
+        self.0.lock().unwrap().contains(path)
+    }
+}
+
+// `<name>.cast`, then `<name>.1.cast`, `<name>.2.cast`, ...
+pub fn segment_path(log_dir: &Path, name: &str, number: u32) -> PathBuf {
+    match number {
+        0 => log_dir.join(format!("{}.cast", name)),
+        n => log_dir.join(format!("{}.{}.cast", name, n)),
+    }
+}
+
+// the recording and segment number of a file named by `segment_path`, compressed or not
+pub fn parse_segment_path(path: &Path) -> Option<(String, u32)> {
+    let name = path.file_name()?.to_str()?;
+    let stem = name.strip_suffix(".zst").unwrap_or(name).strip_suffix(".cast")?;
+    match stem.rsplit_once('.').map(|(name, n)| (name, n.parse())) {
+        Some((name, Ok(n))) if n > 0 => Some((name.to_owned(), n)),
+        _ => Some((stem.to_owned(), 0)),
+    }
+}
+
+// compresses a finished segment in the background; it stays active until it is replaced
+pub fn finish_segment(path: PathBuf, compress: bool, active: Arc<ActiveFiles>) {
+    if !compress {
+        active.remove(&path);
+        return;
+    }
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = compress_file(&path) {
+            logger("error", format!("Failed to compress {}: {}", path.display(), e));
+        }
+        active.remove(&path);
+    });
+}
+
+// reads a recording whether or not it was compressed on rotation
+pub fn open_cast(path: &Path) -> io::Result<Box<dyn Read>> {
+    let mut file = BufReader::new(File::open(path)?);
+    if crypt::is_encrypted(file.fill_buf()?) {
+        return Err(encrypted());
+    }
+    if file.fill_buf()?.starts_with(ZSTD_MAGIC) {
+        return Ok(Box::new(zstd::stream::Decoder::with_buffer(file)?));
+    }
+    Ok(Box::new(file))
+}
+
+pub fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    if crypt::is_encrypted(&data) {
+        return Err(encrypted());
+    }
+    match data.starts_with(ZSTD_MAGIC) {
+        true => zstd::stream::decode_all(&data[..]),
+        false => Ok(data),
+    }
+}
+
+fn encrypted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "encrypted recording, run `xterm-rs decrypt` first",
+    )
+}
+
+fn compress_file(path: &Path) -> io::Result<()> {
+    let mut zst = path.as_os_str().to_owned();
+    zst.push(".zst");
+    let mut tmp = zst.clone();
+    tmp.push(".tmp");
+
+    let mut input = File::open(path)?;
+    let output = File::create(&tmp)?;
+    let mut encoder = zstd::stream::Encoder::new(output, ZSTD_LEVEL)?;
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::rename(&tmp, &zst)?;
+    fs::remove_file(path)
+}
+
+pub fn spawn_retention(log_dir: PathBuf, policy: Retention, active: Arc<ActiveFiles>) {
+    tokio::spawn(async move {
+        let mut tick = time::interval(RETENTION_PERIOD);
+        tick.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+        loop {
+            tick.tick().await;
+            let (dir, active) = (log_dir.clone(), Arc::clone(&active));
+            match tokio::task::spawn_blocking(move || enforce(&dir, &policy, &active)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => logger("error", format!("Log retention failed: {}", e)),
+                Err(e) => logger("error", format!("Log retention panicked: {}", e)),
+            }
+        }
+    });
+}
+
+// a recording segment with its index, or a rotated heartbeat log
+#[derive(Default)]
+struct Group {
+    files: Vec<PathBuf>,
+    bytes: u64,
+    modified: Option<SystemTime>,
+    active: bool,
+}
+
+fn group_key(name: &str) -> Option<&str> {
+    for ext in [".cast.zst", ".cast", ".idx"] {
+        if let Some(stem) = name.strip_suffix(ext) {
+            return Some(stem);
+        }
+    }
+    (name.starts_with("heartbeat-") && name.ends_with(".log")).then_some(name)
+}
+
+fn enforce(log_dir: &Path, policy: &Retention, active: &ActiveFiles) -> io::Result<()> {
+    let entries = match fs::read_dir(log_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+
+    let mut groups: BTreeMap<String, Group> = BTreeMap::new();
+    // live files count against the byte budget even though they cannot go
+    let mut live_bytes = 0;
+    for entry in entries {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if !meta.is_file() {
+            continue;
+        }
+        let path = entry.path();
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(key) = group_key(&name) else {
+            live_bytes += meta.len();
+            continue;
+        };
+        let group = groups.entry(key.to_owned()).or_default();
+        group.bytes += meta.len();
+        group.modified = group.modified.max(meta.modified().ok());
+        group.active |= active.contains(&path);
+        group.files.push(path);
+    }
+
+    let mut groups: Vec<Group> = groups.into_values().collect();
+    groups.sort_by_key(|g| g.modified);
+    let (live, removable): (Vec<Group>, Vec<Group>) = groups.into_iter().partition(|g| g.active);
+    live_bytes += live.iter().map(|g| g.bytes).sum::<u64>();
+
+    let now = SystemTime::now();
+    let expired = |g: &Group| {
+        policy.max_age.is_some_and(|age| {
+            g.modified
+                .and_then(|m| now.duration_since(m).ok())
+                .is_some_and(|d| d > age)
+        })
+    };
+    let mut total = live_bytes + removable.iter().map(|g| g.bytes).sum::<u64>();
+    let mut kept = live.len() + removable.len();
+    let mut doomed = Vec::new();
+    for group in removable {
+        let over_bytes = policy.max_bytes.is_some_and(|max| total > max);
+        let over_files = policy.max_files.is_some_and(|max| kept > max);
+        if !(expired(&group) || over_bytes || over_files) {
+            continue;
+        }
+        total -= group.bytes;
+        kept -= 1;
+        doomed.push(group);
+    }
+
+    for group in doomed {
+        for path in group.files {
+            match fs::remove_file(&path) {
+                Ok(()) => logger("info", format!("Removed old log {}", path.display())),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => logger("error", format!("Failed to remove {}: {}", path.display(), e)),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_paths() {
+        let dir = Path::new("/logs");
+        assert_eq!(segment_path(dir, "1700", 0), Path::new("/logs/1700.cast"));
+        assert_eq!(segment_path(dir, "1700", 2), Path::new("/logs/1700.2.cast"));
+        for (path, name, n) in [
+            ("/logs/1700.cast", "1700", 0),
+            ("/logs/1700.2.cast", "1700", 2),
+            ("/logs/1700.2.cast.zst", "1700", 2),
+            ("main.0.cast", "main.0", 0),
+            ("a.b.cast", "a.b", 0),
+        ] {
+            assert_eq!(
+                parse_segment_path(Path::new(path)),
+                Some((name.to_owned(), n)),
+                "{path}"
+            );
+        }
+        assert_eq!(parse_segment_path(Path::new("/logs/1700.idx")), None);
+        assert_eq!(parse_segment_path(Path::new("/logs/heartbeat.log")), None);
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(group_key("1700.2.cast"), Some("1700.2"));
+        assert_eq!(group_key("1700.2.cast.zst"), Some("1700.2"));
+        assert_eq!(group_key("1700.2.idx"), Some("1700.2"));
+        assert_eq!(group_key("heartbeat-1700.log"), Some("heartbeat-1700.log"));
+        // the current heartbeat log is always being written
+        assert_eq!(group_key("heartbeat.log"), None);
+        assert_eq!(group_key("notes.txt"), None);
+    }
+
+    // files of `len` bytes last written `age` ago
+    fn old_file(dir: &Path, name: &str, len: usize, age: Duration) {
+        let path = dir.join(name);
+        fs::write(&path, vec![0; len]).unwrap();
+        let file = File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - age).unwrap();
+    }
+
+    fn left(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("xterm-rs-retention-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let day = Duration::from_secs(86400);
+        old_file(&dir, "1.cast.zst", 100, day * 4);
+        old_file(&dir, "1.idx", 16, day * 4);
+        old_file(&dir, "heartbeat-1.log", 50, day * 3);
+        old_file(&dir, "2.cast", 100, day * 2);
+        old_file(&dir, "3.cast", 100, day * 5);
+        old_file(&dir, "heartbeat.log", 50, Duration::ZERO);
+        dir
+    }
+
+    #[test]
+    fn oldest_go_first() {
+        let dir = log_dir("files");
+        let active = ActiveFiles::default();
+        // being written, so it stays though it is the oldest
+        active.insert(&dir.join("3.cast"));
+        let policy = Retention {
+            max_files: Some(3),
+            ..Retention::default()
+        };
+        enforce(&dir, &policy, &active).unwrap();
+        assert_eq!(left(&dir), ["2.cast", "3.cast", "heartbeat-1.log", "heartbeat.log"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_and_age() {
+        let dir = log_dir("size");
+        // 416 bytes in all; the live heartbeat.log counts but cannot go
+        let policy = Retention {
+            max_bytes: Some(300),
+            ..Retention::default()
+        };
+        enforce(&dir, &policy, &ActiveFiles::default()).unwrap();
+        assert_eq!(left(&dir), ["2.cast", "heartbeat-1.log", "heartbeat.log"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dir = log_dir("age");
+        let policy = Retention {
+            max_age: Some(Duration::from_secs(3 * 86400 + 60)),
+            ..Retention::default()
+        };
+        enforce(&dir, &policy, &ActiveFiles::default()).unwrap();
+        assert_eq!(left(&dir), ["2.cast", "heartbeat-1.log", "heartbeat.log"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,355 @@
+use crate::caster::{CastEvent, CastReader, Metadata};
+use anyhow::{Context, bail};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::{fs, path::Path};
+
+// signed along with the offset and link, so a signature means nothing outside a checkpoint
+const DOMAIN: &[u8] = b"xterm-rs checkpoint\0";
+
+const LAST: u8 = 1;
+const SIGNED: u8 = 2;
+
+// payload of a checkpoint event: a flags byte, the chain link over every byte of the file
+// before the event, then the signature of `message` if it is signed
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    // written as the file was closed; anything after it was added later
+    pub last: bool,
+    pub link: [u8; 32],
+    pub signature: Option<[u8; 64]>,
+}
+
+impl Checkpoint {
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.last {
+            flags |= LAST;
+        }
+        if self.signature.is_some() {
+            flags |= SIGNED;
+        }
+        let mut p = Vec::with_capacity(97);
+        p.push(flags);
+        p.extend_from_slice(&self.link);
+        if let Some(sig) = &self.signature {
+            p.extend_from_slice(sig);
+        }
+        p
+    }
+
+    pub fn from_payload(p: &[u8]) -> Option<Self> {
+        let (&flags, rest) = p.split_first()?;
+        let link = rest.get(..32)?.try_into().ok()?;
+        let signature = match flags & SIGNED {
+            0 if rest.len() == 32 => None,
+            0 => return None,
+            _ => Some(rest.get(32..)?.try_into().ok()?),
+        };
+        Some(Self {
+            last: flags & LAST != 0,
+            link,
+            signature,
+        })
+    }
+}
+
+fn message(offset: u64, link: &[u8; 32]) -> Vec<u8> {
+    [DOMAIN, &offset.to_le_bytes(), link].concat()
+}
+
+// running hash of a file: each link is sha256 of the previous one and the bytes written since;
+// the first starts from zeros and covers the header
+pub struct Chain {
+    block: Sha256,
+    pending: bool,
+}
+
+impl Default for Chain {
+    fn default() -> Self {
+        Self {
+            block: Sha256::new_with_prefix([0u8; 32]),
+            pending: false,
+        }
+    }
+}
+
+impl Chain {
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.block.update(bytes);
+        self.pending |= !bytes.is_empty();
+    }
+
+    // bytes were written since the last link
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    // closes the block; the next one starts from the returned link
+    pub fn link(&mut self) -> [u8; 32] {
+        let link: [u8; 32] = std::mem::take(&mut self.block).finalize().into();
+        self.block.update(link);
+        self.pending = false;
+        link
+    }
+
+    // the checkpoint for a file whose chain ends at `offset`, signed if there is a key
+    pub fn checkpoint(&mut self, offset: u64, key: Option<&SigningKey>, last: bool) -> Checkpoint {
+        let link = self.link();
+        Checkpoint {
+            last,
+            link,
+            signature: key.map(|key| key.sign(&message(offset, &link)).to_bytes()),
+        }
+    }
+}
+
+// a 32 byte key as hex, or a file holding it such as those `keygen` writes
+pub fn read_key(s: &str) -> anyhow::Result<[u8; 32]> {
+    if let Some(key) = unhex(s) {
+        return Ok(key);
+    }
+    let text = fs::read_to_string(s).with_context(|| format!("read {}", s))?;
+    match unhex(text.trim()) {
+        Some(key) => Ok(key),
+        None => bail!("{}: expected 64 hex digits", s),
+    }
+}
+
+pub fn load_signing_key(path: &Path) -> anyhow::Result<SigningKey> {
+    Ok(SigningKey::from_bytes(&read_key(&path.to_string_lossy())?))
+}
+
+pub fn parse_public_key(s: &str) -> anyhow::Result<VerifyingKey> {
+    VerifyingKey::from_bytes(&read_key(s)?).context("invalid public key")
+}
+
+pub fn generate_key() -> anyhow::Result<SigningKey> {
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed).map_err(|e| anyhow::anyhow!("random seed: {}", e))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<[u8; 32]> {
+    if s.len() != 64 || !s.is_ascii() {
+        return None;
+    }
+    let mut out = [0u8; 32];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(out)
+}
+
+#[derive(Debug, Default)]
+pub struct Report {
+    pub len: u64,
+    pub checkpoints: usize,
+    pub signed: usize,
+    // bytes before the last checkpoint that can be trusted: a signed one when checking a key
+    pub covered: u64,
+    // the first offset that cannot be trusted, and why
+    pub fault: Option<(u64, String)>,
+    // the header, for checking segments against each other
+    pub meta: Metadata,
+    // the link of the closing checkpoint, which the next segment names
+    pub closing: Option<[u8; 32]>,
+}
+
+// walks the chain of a whole recording; without a key only the links are checked, which
+// shows accidental damage but not edits by someone who recomputed them
+pub fn verify(data: &[u8], key: Option<&VerifyingKey>) -> Report {
+    let mut report = Report {
+        len: data.len() as u64,
+        ..Report::default()
+    };
+    let mut reader = match CastReader::new(data) {
+        Ok(reader) => reader,
+        Err(e) => {
+            report.fault = Some((0, e.to_string()));
+            return report;
+        }
+    };
+    if reader.version < 2 {
+        report.fault = Some((0, "v1 recordings carry no checkpoints".to_owned()));
+        return report;
+    }
+    report.meta = reader.meta.clone();
+
+    let mut chain = Chain::default();
+    let (mut start, mut closed) = (0u64, false);
+    loop {
+        let rec = match reader.read_event() {
+            Ok(Some(rec)) => rec,
+            Ok(None) => break,
+            Err(e) => {
+                report.fault = Some((e.offset().unwrap_or(start), e.to_string()));
+                return report;
+            }
+        };
+        if closed {
+            report.fault = Some((rec.offset, "data after the closing checkpoint".to_owned()));
+            return report;
+        }
+        let CastEvent::Checkpoint(checkpoint) = rec.event else {
+            continue;
+        };
+        chain.update(&data[start as usize..rec.offset as usize]);
+        let link = chain.link();
+        if link != checkpoint.link {
+            let reason = format!("bytes changed between here and the checkpoint at offset {}", rec.offset);
+            report.fault = Some((start, reason));
+            return report;
+        }
+        report.checkpoints += 1;
+        match (key, checkpoint.signature) {
+            (Some(key), Some(sig)) => {
+                if key
+                    .verify_strict(&message(rec.offset, &link), &Signature::from_bytes(&sig))
+                    .is_err()
+                {
+                    report.fault = Some((rec.offset, "checkpoint signature does not match the key".to_owned()));
+                    return report;
+                }
+                report.signed += 1;
+                report.covered = rec.offset;
+            }
+            (Some(_), None) => {}
+            (None, sig) => {
+                report.signed += sig.is_some() as usize;
+                report.covered = rec.offset;
+            }
+        }
+        closed = checkpoint.last;
+        if closed {
+            report.closing = Some(link);
+        }
+        start = rec.offset;
+    }
+    if !closed {
+        let reason = "no closing checkpoint: the file was cut short or the server did not stop cleanly";
+        report.fault = Some((report.covered, reason.to_owned()));
+    } else if key.is_some() && report.covered != start {
+        report.fault = Some((report.covered, "the closing checkpoint is not signed".to_owned()));
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caster::cast::{encode_event, encode_header};
+    use std::time::Duration;
+
+    // a recording with a checkpoint after each output, the last one closing it
+    fn recording(meta: &Metadata, key: Option<&SigningKey>, outputs: &[&[u8]]) -> (Vec<u8>, [u8; 32]) {
+        let mut data = encode_header(meta);
+        let (mut chain, mut start, mut link) = (Chain::default(), 0, [0; 32]);
+        for (i, output) in outputs.iter().enumerate() {
+            let elapsed = Duration::from_millis(i as u64);
+            data.extend(encode_event(elapsed, &CastEvent::Output(output.to_vec())));
+            chain.update(&data[start..]);
+            start = data.len();
+            let checkpoint = chain.checkpoint(data.len() as u64, key, i + 1 == outputs.len());
+            link = checkpoint.link;
+            data.extend(encode_event(elapsed, &CastEvent::Checkpoint(checkpoint)));
+        }
+        (data, link)
+    }
+
+    fn key(seed: u8) -> SigningKey {
+        SigningKey::from_bytes(&[seed; 32])
+    }
+
+    #[test]
+    fn checkpoint_payload() {
+        let signed = Checkpoint {
+            last: true,
+            link: [7; 32],
+            signature: Some([9; 64]),
+        };
+        let back = Checkpoint::from_payload(&signed.to_payload()).unwrap();
+        assert!(back.last);
+        assert_eq!((back.link, back.signature), (signed.link, signed.signature));
+        let unsigned = Checkpoint {
+            signature: None,
+            ..signed
+        };
+        assert_eq!(unsigned.to_payload().len(), 33);
+        assert!(Checkpoint::from_payload(&unsigned.to_payload()[..20]).is_none());
+    }
+
+    #[test]
+    fn good_chain() {
+        let key = key(1);
+        let (data, closing) = recording(&Metadata::default(), Some(&key), &[b"one", b"two", b"three"]);
+        let report = verify(&data, Some(&key.verifying_key()));
+        assert_eq!(report.fault, None);
+        assert_eq!((report.checkpoints, report.signed), (3, 3));
+        assert_eq!(report.len, data.len() as u64);
+        assert_eq!(report.closing, Some(closing));
+        // without the key the links still hold
+        assert_eq!(verify(&data, None).fault, None);
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let (data, _) = recording(&Metadata::default(), None, &[b"one", b"two", b"three"]);
+        let at = data.windows(3).position(|w| w == b"two").unwrap();
+        let mut bad = data.clone();
+        bad[at] ^= 1;
+        // reported from the start of the block holding it, which is the checkpoint before it
+        let first = CastReader::new(&data[..])
+            .unwrap()
+            .map(Result::unwrap)
+            .find(|rec| matches!(rec.event, CastEvent::Checkpoint(_)))
+            .unwrap();
+        let report = verify(&bad, None);
+        let (offset, reason) = report.fault.unwrap();
+        assert!(reason.starts_with("bytes changed"), "{reason}");
+        assert_eq!((offset, report.checkpoints), (first.offset, 1));
+        assert!(offset < at as u64);
+    }
+
+    #[test]
+    fn bad_signature() {
+        let (data, _) = recording(&Metadata::default(), Some(&key(1)), &[b"one", b"two"]);
+        let report = verify(&data, Some(&key(2).verifying_key()));
+        let (offset, reason) = report.fault.unwrap();
+        assert_eq!(reason, "checkpoint signature does not match the key");
+        assert_eq!((report.checkpoints, report.signed, report.covered), (1, 0, 0));
+        assert!(offset > 0);
+
+        // unsigned checkpoints do not pass for signed ones
+        let (data, _) = recording(&Metadata::default(), None, &[b"one"]);
+        let (_, reason) = verify(&data, Some(&key(1).verifying_key())).fault.unwrap();
+        assert_eq!(reason, "the closing checkpoint is not signed");
+    }
+
+    #[test]
+    fn cut_short_and_added_to() {
+        let (data, _) = recording(&Metadata::default(), None, &[b"one", b"two"]);
+        let (_, reason) = verify(&data[..data.len() - 1], None).fault.unwrap();
+        assert!(reason.starts_with("truncated"), "{reason}");
+
+        let mut more = data.clone();
+        more.extend(encode_event(Duration::ZERO, &CastEvent::Output(b"late".to_vec())));
+        assert_eq!(
+            verify(&more, None).fault,
+            Some((data.len() as u64, "data after the closing checkpoint".to_owned()))
+        );
+    }
+
+    #[test]
+    fn keys() {
+        let key = key(3);
+        let public = hex(key.verifying_key().as_bytes());
+        assert_eq!(parse_public_key(&public).unwrap(), key.verifying_key());
+        assert!(read_key("00").is_err());
+        assert!(read_key(&"zz".repeat(32)).is_err());
+    }
+}
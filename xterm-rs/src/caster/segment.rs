@@ -0,0 +1,609 @@
+use crate::caster::cast::{CastOptions, EventKind, Metadata, RawEvt, encode_evt, encode_header, micros, size_payload};
+use crate::caster::crypt::Encryptor;
+use crate::caster::index::{self, Keyframe};
+use crate::caster::rotate::{self, ActiveFiles, Rotation};
+use crate::caster::seal::{self, Chain};
+use crate::caster::sink::{CastSink, SinkEvent};
+use crate::term::{Emulator, clamp_size};
+use ed25519_dalek::SigningKey;
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use x25519_dalek::PublicKey;
+
+// shortest time between links of the hash chain
+const CHAIN_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncMode {
+    #[default]
+    None,
+    Interval,
+    Checkpoint,
+}
+
+// when cast files are synced to disk; they are written out every sink flush either way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    // left to the kernel
+    None,
+    Interval(Duration),
+    // with every link of the hash chain, so what a checkpoint covers survives a crash
+    Checkpoint,
+}
+
+impl Fsync {
+    pub fn new(mode: FsyncMode, interval: Duration) -> Self {
+        match mode {
+            FsyncMode::None => Fsync::None,
+            FsyncMode::Interval => Fsync::Interval(interval),
+            FsyncMode::Checkpoint => Fsync::Checkpoint,
+        }
+    }
+}
+
+// where a segment's bytes go
+pub enum Store {
+    Disk {
+        file: BufWriter<File>,
+        path: PathBuf,
+        index: Option<BufWriter<File>>,
+    },
+    // taken out by the http sink
+    Memory(Vec<u8>),
+}
+
+impl Store {
+    fn index(&mut self, entry: &Keyframe) -> io::Result<()> {
+        let Store::Disk { path, index, .. } = self else {
+            return Ok(());
+        };
+        let index = match index {
+            Some(index) => index,
+            None => index.insert(BufWriter::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(index::index_path(path))?,
+            )),
+        };
+        index.write_all(&entry.to_bytes())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let Store::Disk { file, index, .. } = self else {
+            return Ok(());
+        };
+        file.flush()?;
+        file.get_ref().sync_data()?;
+        if let Some(index) = index {
+            index.flush()?;
+            index.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for Store {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            Store::Disk { file, .. } => file.write(bytes),
+            Store::Memory(buf) => buf.write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Store::Disk { file, index, .. } => {
+                file.flush()?;
+                index.as_mut().map_or(Ok(()), |index| index.flush())
+            }
+            Store::Memory(_) => Ok(()),
+        }
+    }
+}
+
+// a segment's output: plain, or encrypted with offsets still counted in plaintext
+enum SegmentFile {
+    Plain(Store),
+    Sealed(Encryptor<Store>),
+}
+
+impl SegmentFile {
+    fn close(&mut self) -> io::Result<()> {
+        match self {
+            SegmentFile::Plain(store) => store.flush(),
+            SegmentFile::Sealed(store) => store.close(),
+        }
+    }
+
+    fn store(&mut self) -> &mut Store {
+        match self {
+            SegmentFile::Plain(store) => store,
+            SegmentFile::Sealed(store) => store.get_mut(),
+        }
+    }
+
+    // a sealed file seals what it holds first, or the sync would not cover it
+    fn sync(&mut self) -> io::Result<()> {
+        if let SegmentFile::Sealed(store) = self {
+            store.seal_pending()?;
+        }
+        self.store().sync()
+    }
+}
+
+impl Write for SegmentFile {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        match self {
+            SegmentFile::Plain(store) => store.write(bytes),
+            SegmentFile::Sealed(store) => store.write(bytes),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            SegmentFile::Plain(store) => store.flush(),
+            SegmentFile::Sealed(store) => store.flush(),
+        }
+    }
+}
+
+// one cast file of a recording; each opens with the header and, after the first, a keyframe
+// so it plays on its own
+pub struct Segment {
+    pub number: u32,
+    opened: Instant,
+    file: SegmentFile,
+    // bytes in the file, so keyframes can be indexed by offset
+    len: u64,
+    // length once the header and opening keyframe were written
+    head_len: u64,
+    // over every byte written, see `seal`
+    chain: Chain,
+}
+
+impl Segment {
+    fn new(store: Store, len: u64, number: u32, encrypt_to: Option<&PublicKey>) -> io::Result<Self> {
+        let file = match encrypt_to {
+            Some(recipient) => SegmentFile::Sealed(Encryptor::new(store, recipient)?),
+            None => SegmentFile::Plain(store),
+        };
+        Ok(Self {
+            number,
+            opened: Instant::now(),
+            file,
+            len,
+            head_len: len,
+            chain: Chain::default(),
+        })
+    }
+
+    // an encrypted segment always starts a new stream, so it replaces a file left at the path
+    pub fn disk(path: &Path, number: u32, encrypt_to: Option<&PublicKey>) -> io::Result<Self> {
+        let (file, len) = match encrypt_to {
+            Some(_) => (File::create(path)?, 0),
+            None => {
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                let len = file.metadata()?.len();
+                (file, len)
+            }
+        };
+        let store = Store::Disk {
+            file: BufWriter::new(file),
+            path: path.to_path_buf(),
+            index: None,
+        };
+        Self::new(store, len, number, encrypt_to)
+    }
+
+    pub fn memory(number: u32, encrypt_to: Option<&PublicKey>) -> io::Result<Self> {
+        Self::new(Store::Memory(Vec::new()), 0, number, encrypt_to)
+    }
+
+    pub fn store(&mut self) -> &mut Store {
+        self.file.store()
+    }
+
+    // buffered until the sink flushes
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.file.write_all(bytes)?;
+        self.len += bytes.len() as u64;
+        self.chain.update(bytes);
+        Ok(())
+    }
+
+    // links the chain up to here; a checkpoint right after the head counts as part of it
+    fn checkpoint(&mut self, elapsed: Duration, key: Option<&SigningKey>, last: bool) -> io::Result<[u8; 32]> {
+        let head = self.len == self.head_len;
+        let checkpoint = self.chain.checkpoint(self.len, key, last);
+        self.write(&encode_evt(&RawEvt {
+            elapsed: micros(elapsed),
+            kind: EventKind::Checkpoint,
+            payload: checkpoint.to_payload(),
+        }))?;
+        if head {
+            self.head_len = self.len;
+        }
+        Ok(checkpoint.link)
+    }
+
+    // an idle session does not leave a trail of empty segments
+    pub fn due(&self, rotation: &Rotation) -> bool {
+        self.len > self.head_len
+            && (rotation.max_bytes.is_some_and(|max| self.len >= max)
+                || rotation.max_age.is_some_and(|age| self.opened.elapsed() >= age))
+    }
+
+    // keep writing here and try the next one later
+    pub fn postpone(&mut self) {
+        self.opened = Instant::now();
+        self.head_len = self.len;
+    }
+}
+
+// what a sink writing cast files keeps of the recording: the header, the screen as a player of
+// its files sees it, which is what keyframes capture, and when the chain was linked and signed
+pub struct Recorder {
+    meta: Metadata,
+    start: Instant,
+    screen: Emulator,
+    rows: u16,
+    cols: u16,
+    keyframe_interval: Option<Duration>,
+    last_keyframe: Duration,
+    signing_key: Option<Arc<SigningKey>>,
+    checkpoint_interval: Duration,
+    last_link: Instant,
+    last_signed: Instant,
+    fsync: Fsync,
+    last_sync: Instant,
+    // the closing link of the last segment finished, named in the header of the next
+    prev: Option<[u8; 32]>,
+    pub encrypt_to: Option<PublicKey>,
+}
+
+impl Recorder {
+    pub fn new(opts: &CastOptions, start: Instant, mut meta: Metadata) -> Self {
+        meta.key_id = opts
+            .signing_key
+            .as_ref()
+            .map(|key| seal::hex(key.verifying_key().as_bytes()));
+        let (rows, cols) = clamp_size(meta.rows.unwrap_or(24), meta.cols.unwrap_or(80));
+        Self {
+            meta,
+            start,
+            screen: Emulator::new(rows, cols, 0),
+            rows,
+            cols,
+            keyframe_interval: opts.keyframe_interval,
+            last_keyframe: start.elapsed(),
+            signing_key: opts.signing_key.clone(),
+            checkpoint_interval: opts.checkpoint_interval,
+            last_link: Instant::now(),
+            last_signed: Instant::now(),
+            fsync: opts.fsync,
+            last_sync: Instant::now(),
+            prev: None,
+            encrypt_to: opts.encrypt_to,
+        }
+    }
+
+    // the header, and a keyframe of the screen so far unless it is the first segment; a segment
+    // is begun once the one before it is finished, to link up with it
+    pub fn begin(&mut self, segment: &mut Segment) -> io::Result<()> {
+        let head = encode_header(&Metadata {
+            segment: segment.number,
+            prev: self.prev.map(|link| seal::hex(&link)),
+            ..self.meta.clone()
+        });
+        segment.write(&head)?;
+        if segment.number > 0 {
+            self.keyframe(segment, self.start.elapsed())?;
+        }
+        segment.head_len = segment.len;
+        Ok(())
+    }
+
+    // one is written with the first output an interval after the last
+    pub fn write(&mut self, segment: &mut Segment, event: &SinkEvent) -> io::Result<()> {
+        segment.write(&event.bytes)?;
+        match event.kind {
+            EventKind::Resize => {
+                let p = &event.payload;
+                // as a client's resize is; the keyframes are drawn at the size the emulator took
+                (self.rows, self.cols) = clamp_size(u16::from_le_bytes([p[0], p[1]]), u16::from_le_bytes([p[2], p[3]]));
+                self.screen.resize(self.rows, self.cols);
+            }
+            EventKind::Output => {
+                self.screen.advance(&event.payload);
+                let elapsed = Duration::from_micros(event.elapsed);
+                if let Some(interval) = self.keyframe_interval
+                    && elapsed.saturating_sub(self.last_keyframe) >= interval
+                {
+                    self.keyframe(segment, elapsed)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn keyframe(&mut self, segment: &mut Segment, elapsed: Duration) -> io::Result<()> {
+        let entry = Keyframe {
+            micros: micros(elapsed),
+            offset: segment.len,
+        };
+        let mut payload = size_payload(self.rows, self.cols);
+        payload.extend(self.screen.snapshot());
+        segment.write(&encode_evt(&RawEvt {
+            elapsed: entry.micros,
+            kind: EventKind::Keyframe,
+            payload,
+        }))?;
+        self.last_keyframe = elapsed;
+        // a plaintext index of a sealed segment would tell when it was busy; the decrypted file
+        // is indexed as it is read
+        match self.encrypt_to {
+            Some(_) => Ok(()),
+            None => segment.store().index(&entry),
+        }
+    }
+
+    // writes out what the events since the last tick left buffered, linking the chain once bytes
+    // are waiting and signing the link once a signature is due, then syncs as the policy says
+    pub fn tick(&mut self, segment: &mut Segment) -> io::Result<()> {
+        let link = segment.chain.pending() && self.last_link.elapsed() >= CHAIN_INTERVAL;
+        if link {
+            let sign = self.last_signed.elapsed() >= self.checkpoint_interval;
+            let key = self.signing_key.as_deref().filter(|_| sign);
+            segment.checkpoint(self.start.elapsed(), key, false)?;
+            self.last_link = Instant::now();
+            if key.is_some() {
+                self.last_signed = self.last_link;
+            }
+        }
+        segment.file.flush()?;
+        let sync = match self.fsync {
+            Fsync::None => false,
+            Fsync::Interval(interval) => self.last_sync.elapsed() >= interval,
+            Fsync::Checkpoint => link,
+        };
+        if sync {
+            segment.file.sync()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    // closes the segment behind a signed last checkpoint
+    pub fn finish(&mut self, segment: &mut Segment) -> io::Result<()> {
+        let checkpoint = segment.checkpoint(self.start.elapsed(), self.signing_key.as_deref(), true);
+        if let Ok(link) = checkpoint {
+            self.prev = Some(link);
+        }
+        let closed = segment.file.close();
+        let synced = match self.fsync {
+            Fsync::None => Ok(()),
+            _ => segment.file.store().sync(),
+        };
+        checkpoint.map(drop).and(closed).and(synced)
+    }
+}
+
+// a recording in a single cast file
+pub struct FileSink {
+    recorder: Recorder,
+    segment: Segment,
+    path: PathBuf,
+    active: Arc<ActiveFiles>,
+}
+
+impl FileSink {
+    pub fn open(opts: &CastOptions, name: &str, start: Instant, meta: Metadata) -> io::Result<Self> {
+        let path = rotate::segment_path(&opts.log_dir, name, 0);
+        let mut recorder = Recorder::new(opts, start, meta);
+        let mut segment = Segment::disk(&path, 0, recorder.encrypt_to.as_ref())?;
+        opts.active.insert(&path);
+        recorder.begin(&mut segment)?;
+        Ok(Self {
+            recorder,
+            segment,
+            path,
+            active: Arc::clone(&opts.active),
+        })
+    }
+}
+
+impl CastSink for FileSink {
+    fn name(&self) -> String {
+        format!("file {}", self.path.display())
+    }
+
+    fn write(&mut self, event: &SinkEvent) -> io::Result<()> {
+        self.recorder.write(&mut self.segment, event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.recorder.tick(&mut self.segment)
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        let closed = self.recorder.finish(&mut self.segment);
+        self.active.remove(&self.path);
+        closed
+    }
+}
+
+// a recording in segments of the log dir, moving on as the rotation options say
+pub struct RotatingFileSink {
+    file: FileSink,
+    log_dir: PathBuf,
+    name: String,
+    rotation: Rotation,
+}
+
+impl RotatingFileSink {
+    pub fn open(opts: &CastOptions, name: &str, start: Instant, meta: Metadata) -> io::Result<Self> {
+        Ok(Self {
+            file: FileSink::open(opts, name, start, meta)?,
+            log_dir: opts.log_dir.clone(),
+            name: name.to_owned(),
+            rotation: opts.rotation,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let file = &mut self.file;
+        let number = file.segment.number + 1;
+        let path = rotate::segment_path(&self.log_dir, &self.name, number);
+        let next = match Segment::disk(&path, number, file.recorder.encrypt_to.as_ref()) {
+            Ok(next) => next,
+            Err(e) => {
+                file.segment.postpone();
+                return Err(io::Error::new(e.kind(), format!("rotate to {}: {}", path.display(), e)));
+            }
+        };
+        file.active.insert(&path);
+        let mut done = std::mem::replace(&mut file.segment, next);
+        let done_path = std::mem::replace(&mut file.path, path);
+        let closed = file.recorder.finish(&mut done);
+        let begun = file.recorder.begin(&mut file.segment).map_err(|e| {
+            let path = file.path.display();
+            io::Error::new(e.kind(), format!("rotate to {}: {}", path, e))
+        });
+        // sealed chunks are compressed already
+        let compress = self.rotation.compress && file.recorder.encrypt_to.is_none();
+        rotate::finish_segment(done_path, compress, Arc::clone(&file.active));
+        closed.and(begun)
+    }
+}
+
+impl CastSink for RotatingFileSink {
+    fn name(&self) -> String {
+        format!("rotating-file {}", self.file.path.display())
+    }
+
+    fn write(&mut self, event: &SinkEvent) -> io::Result<()> {
+        self.file.write(event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.file.segment.due(&self.rotation) {
+            self.rotate()?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        self.file.close()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caster::heartbeat::HeartbeatLog;
+    use crate::caster::queue::{QueueOptions, QueuePolicy};
+    use crate::caster::{CastEvent, CastReader};
+
+    fn options(log_dir: &Path, key: &SigningKey) -> CastOptions {
+        CastOptions {
+            log_dir: log_dir.to_path_buf(),
+            keyframe_interval: Some(Duration::from_secs(1)),
+            rotation: Rotation {
+                max_bytes: Some(300),
+                ..Rotation::default()
+            },
+            active: Arc::default(),
+            signing_key: Some(Arc::new(key.clone())),
+            checkpoint_interval: Duration::ZERO,
+            encrypt_to: None,
+            shipper: None,
+            sinks: Vec::new(),
+            queue: QueueOptions {
+                policy: QueuePolicy::Block,
+                size: 16,
+                max_buffer: 1 << 20,
+            },
+            fsync: Fsync::None,
+            heartbeat: Arc::new(HeartbeatLog::new(log_dir, None, None)),
+        }
+    }
+
+    fn output(secs: u64, text: &str) -> SinkEvent {
+        SinkEvent::from(RawEvt {
+            elapsed: secs * 1_000_000,
+            kind: EventKind::Output,
+            payload: text.as_bytes().to_vec(),
+        })
+    }
+
+    #[test]
+    fn rotated_segments_chain_and_index() {
+        let dir = std::env::temp_dir().join(format!("xterm-rs-segment-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let key = SigningKey::from_bytes(&[7; 32]);
+        let opts = options(&dir, &key);
+        let meta = Metadata {
+            rows: Some(4),
+            cols: Some(20),
+            ..Metadata::default()
+        };
+        let mut sink = RotatingFileSink::open(&opts, "rec", Instant::now(), meta).unwrap();
+        for secs in 1..=6 {
+            sink.write(&output(secs, &format!("line {} {}\r\n", secs, "x".repeat(100))))
+                .unwrap();
+            sink.flush().unwrap();
+        }
+        sink.close().unwrap();
+
+        let mut closing: Option<[u8; 32]> = None;
+        let mut number = 0;
+        while let Ok(data) = std::fs::read(rotate::segment_path(&dir, "rec", number)) {
+            let report = seal::verify(&data, Some(&key.verifying_key()));
+            assert_eq!(report.fault, None, "segment {number}");
+            assert_eq!(report.meta.segment, number);
+            assert_eq!(report.meta.prev, closing.map(|link| seal::hex(&link)));
+            closing = report.closing;
+
+            // every segment after the first plays on its own from a keyframe
+            let mut reader = CastReader::new(&data[..]).unwrap();
+            let first = reader.read_event().unwrap().unwrap();
+            assert_eq!(
+                number > 0,
+                matches!(first.event, CastEvent::Keyframe { rows: 4, cols: 20, .. })
+            );
+            let path = rotate::segment_path(&dir, "rec", number);
+            let rebuilt = index::build_index(CastReader::new(&data[..]).unwrap()).unwrap();
+            // none is written until a keyframe is due
+            let written = index::read_index(&index::index_path(&path)).unwrap_or_default();
+            assert_eq!(written, rebuilt, "segment {number}");
+            assert!(number == 0 || !written.is_empty());
+            number += 1;
+        }
+        assert!(number >= 3, "{number} segments");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recorded_resize_is_clamped() {
+        let dir = std::env::temp_dir().join(format!("xterm-rs-segment-resize-{}", std::process::id()));
+        let opts = options(&dir, &SigningKey::from_bytes(&[7; 32]));
+        let mut recorder = Recorder::new(&opts, Instant::now(), Metadata::default());
+        let mut segment = Segment::memory(0, None).unwrap();
+        let resize = SinkEvent::from(RawEvt {
+            elapsed: 0,
+            kind: EventKind::Resize,
+            payload: size_payload(0, 2000),
+        });
+        recorder.write(&mut segment, &resize).unwrap();
+        assert_eq!((recorder.rows, recorder.cols), (1, crate::term::MAX_SIZE));
+    }
+}
@@ -0,0 +1,598 @@
+use crate::caster::cast::{CastOptions, Metadata};
+use crate::caster::rotate::Rotation;
+use crate::caster::segment::{Recorder, Segment, Store};
+use crate::caster::sink::{CastSink, SinkEvent};
+use crate::models::logger;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Instant,
+};
+use tokio::{
+    sync::{Notify, mpsc, oneshot},
+    time::{self, Duration},
+};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const ZSTD_LEVEL: i32 = 3;
+// a chunk is cut early once this much is waiting
+const CHUNK_BYTES: usize = 1 << 20;
+
+// request headers telling the collector which file a chunk belongs to
+pub const WORKSPACE_HEADER: &str = "x-xterm-workspace";
+pub const RECORDING_HEADER: &str = "x-xterm-recording";
+pub const SEGMENT_HEADER: &str = "x-xterm-segment";
+pub const SEQ_HEADER: &str = "x-xterm-seq";
+// where the chunk starts in the segment file
+pub const OFFSET_HEADER: &str = "x-xterm-offset";
+// set on the chunk that closes a segment
+pub const LAST_HEADER: &str = "x-xterm-last";
+
+pub struct ShipOptions {
+    pub url: reqwest::Url,
+    // tells this server's recordings apart from other workspaces' at the collector
+    pub workspace: String,
+    // sent as a bearer token
+    pub token: Option<String>,
+    pub spool_dir: PathBuf,
+    // the oldest chunks are dropped past this
+    pub spool_limit: Option<u64>,
+}
+
+// a chunk in the spool: a json line of this, then the zstd body
+#[derive(Debug, Serialize, Deserialize)]
+struct ChunkMeta {
+    recording: String,
+    segment: u32,
+    seq: u64,
+    offset: u64,
+    last: bool,
+}
+
+// sends cast segments to a collector. every chunk is spooled to disk
+// first and removed once the collector acknowledged it with a 2xx, so the spool holds
+// whatever is not acknowledged yet and a restarted server carries on from there
+pub struct Shipper {
+    opts: ShipOptions,
+    wake: Notify,
+    // to the thread writing the spool, so the sinks never wait on the disk
+    spool_tx: mpsc::UnboundedSender<Spool>,
+    // bytes in the spool, kept up to date instead of summed up for every chunk
+    spooled: AtomicU64,
+    // why the collector refuses the token, until it takes a chunk again
+    refused: Mutex<Option<String>>,
+}
+
+enum Spool {
+    Chunk(PathBuf, ChunkMeta, Vec<u8>),
+    // answered once every chunk sent before it is on disk
+    Settle(oneshot::Sender<()>),
+}
+
+enum Failure {
+    // the collector answered and will not take it; retrying would not help
+    Rejected(String),
+    // the token is wrong or not allowed; fixed at the collector, so the chunks are kept until then
+    Refused(String),
+    Retry(String),
+}
+
+impl Shipper {
+    pub fn spawn(opts: ShipOptions) -> anyhow::Result<Arc<Self>> {
+        fs::create_dir_all(&opts.spool_dir)?;
+        let client = reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build()?;
+        let (spool_tx, spool_rx) = mpsc::unbounded_channel();
+        let shipper = Arc::new(Self {
+            opts,
+            wake: Notify::new(),
+            spool_tx,
+            spooled: AtomicU64::new(0),
+            refused: Mutex::new(None),
+        });
+        let pending = shipper.pending()?;
+        let spooled = pending
+            .iter()
+            .map(|path| fs::metadata(path).map_or(0, |m| m.len()))
+            .sum();
+        shipper.spooled.store(spooled, Ordering::Relaxed);
+        if !pending.is_empty() {
+            logger(
+                "info",
+                format!("Resuming upload of {} spooled cast chunks", pending.len()),
+            );
+        }
+        let spooler = Arc::clone(&shipper);
+        tokio::task::spawn_blocking(move || spooler.spool_all(spool_rx));
+        tokio::spawn(Arc::clone(&shipper).run(client));
+        Ok(shipper)
+    }
+
+    // spools the next chunk of a segment; `last` once the segment is closed
+    pub fn send(&self, recording: &str, segment: u32, seq: u64, offset: u64, last: bool, data: &[u8]) {
+        let meta = ChunkMeta {
+            recording: recording.to_owned(),
+            segment,
+            seq,
+            offset,
+            last,
+        };
+        let path = self.opts.spool_dir.join(format!("{}.{:012}.chunk", recording, seq));
+        let _ = self.spool_tx.send(Spool::Chunk(path, meta, data.to_vec()));
+    }
+
+    // waits for the chunks sent so far to be on disk, for them to outlive the process
+    pub async fn settle(&self) {
+        let (tx, rx) = oneshot::channel();
+        if self.spool_tx.send(Spool::Settle(tx)).is_ok() {
+            let _ = rx.await;
+        }
+    }
+
+    // the collector refusing the token, for the sinks to report
+    pub fn refused(&self) -> Option<String> {
+        self.refused.lock().unwrap().clone()
+    }
+
+    // the spool thread: writes the chunks in the order they were sent
+    fn spool_all(&self, mut rx: mpsc::UnboundedReceiver<Spool>) {
+        while let Some(next) = rx.blocking_recv() {
+            let (path, meta, data) = match next {
+                Spool::Chunk(path, meta, data) => (path, meta, data),
+                Spool::Settle(done) => {
+                    let _ = done.send(());
+                    continue;
+                }
+            };
+            match spool(&path, &meta, &data) {
+                Ok(size) => {
+                    self.spooled.fetch_add(size, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    logger("error", format!("Failed to spool {}: {}", path.display(), e));
+                    continue;
+                }
+            }
+            if let Some(max) = self.opts.spool_limit
+                && self.spooled.load(Ordering::Relaxed) > max
+                && let Err(e) = self.trim(max)
+            {
+                logger("error", format!("Failed to trim cast spool: {}", e));
+            }
+            self.wake.notify_one();
+        }
+    }
+
+    // takes a chunk off the spool's count once it is gone
+    fn remove(&self, path: &Path, size: u64) {
+        if fs::remove_file(path).is_ok() {
+            self.spooled.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
+
+    // spooled chunks, oldest first: names start with the recording's start time, then the seq
+    fn pending(&self) -> io::Result<Vec<PathBuf>> {
+        let mut chunks: Vec<PathBuf> = fs::read_dir(&self.opts.spool_dir)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "chunk"))
+            .collect();
+        chunks.sort();
+        Ok(chunks)
+    }
+
+    // only listed once the count says the spool is over the limit
+    fn trim(&self, max: u64) -> io::Result<()> {
+        for path in self.pending()? {
+            if self.spooled.load(Ordering::Relaxed) <= max {
+                break;
+            }
+            // uploaded in the meantime
+            let Ok(size) = fs::metadata(&path).map(|m| m.len()) else {
+                continue;
+            };
+            if fs::remove_file(&path).is_ok() {
+                self.spooled.fetch_sub(size, Ordering::Relaxed);
+                logger("error", format!("Cast spool is full, dropped {}", path.display()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn run(self: Arc<Self>, client: reqwest::Client) {
+        let mut backoff = MIN_BACKOFF;
+        let mut down = false;
+        loop {
+            let chunks = match self.pending() {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    logger("error", format!("Failed to read cast spool: {}", e));
+                    Vec::new()
+                }
+            };
+            if chunks.is_empty() {
+                self.wake.notified().await;
+                continue;
+            }
+            for path in chunks {
+                match self.upload(&client, &path).await {
+                    Ok(()) => {
+                        if down {
+                            logger("info", "Collector is reachable again, sending spooled chunks");
+                            down = false;
+                        }
+                        if self.refused.lock().unwrap().take().is_some() {
+                            logger("info", "Collector accepts the token again, sending spooled chunks");
+                        }
+                        backoff = MIN_BACKOFF;
+                    }
+                    Err(Failure::Rejected(reason)) => {
+                        logger("error", format!("Collector rejected {}: {}", path.display(), reason));
+                    }
+                    // logged at every attempt: nothing gets through until someone fixes the token
+                    Err(Failure::Refused(reason)) => {
+                        logger(
+                            "error",
+                            format!(
+                                "Collector refused the token with {}, keeping {} bytes of chunks spooled",
+                                reason,
+                                self.spooled.load(Ordering::Relaxed)
+                            ),
+                        );
+                        *self.refused.lock().unwrap() = Some(reason);
+                        time::sleep(MAX_BACKOFF).await;
+                        break;
+                    }
+                    Err(Failure::Retry(reason)) => {
+                        if !down {
+                            logger("error", format!("Collector unreachable, spooling chunks: {}", reason));
+                            down = true;
+                        }
+                        time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // removes the chunk once it is acknowledged, or refused for good
+    async fn upload(&self, client: &reqwest::Client, path: &Path) -> Result<(), Failure> {
+        let (meta, body, size) = match read_chunk(path) {
+            Ok(chunk) => chunk,
+            // trimmed while queued
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => {
+                self.remove(path, fs::metadata(path).map_or(0, |m| m.len()));
+                return Err(Failure::Rejected(format!("unreadable chunk: {}", e)));
+            }
+        };
+        let mut request = client
+            .post(self.opts.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/octet-stream")
+            .header(reqwest::header::CONTENT_ENCODING, "zstd")
+            .header(WORKSPACE_HEADER, &self.opts.workspace)
+            .header(RECORDING_HEADER, &meta.recording)
+            .header(SEGMENT_HEADER, meta.segment)
+            .header(SEQ_HEADER, meta.seq)
+            .header(OFFSET_HEADER, meta.offset);
+        if meta.last {
+            request = request.header(LAST_HEADER, "1");
+        }
+        if let Some(token) = &self.opts.token {
+            request = request.bearer_auth(token);
+        }
+        let status = match request.body(body).send().await {
+            Ok(response) => response.status(),
+            Err(e) => return Err(Failure::Retry(describe(&e.without_url()))),
+        };
+        match status.as_u16() {
+            200..=299 => {}
+            401 | 403 => return Err(Failure::Refused(status.to_string())),
+            408 | 429 => return Err(Failure::Retry(status.to_string())),
+            400..=499 => {
+                self.remove(path, size);
+                return Err(Failure::Rejected(status.to_string()));
+            }
+            _ => return Err(Failure::Retry(status.to_string())),
+        }
+        self.remove(path, size);
+        Ok(())
+    }
+}
+
+// the error with its causes; reqwest's chain tends to repeat the last one
+fn describe(e: &dyn std::error::Error) -> String {
+    let mut parts = vec![e.to_string()];
+    let mut source = e.source();
+    while let Some(e) = source {
+        let part = e.to_string();
+        if !parts.iter().any(|p| p.contains(&part)) {
+            parts.push(part);
+        }
+        source = e.source();
+    }
+    parts.join(": ")
+}
+
+// written aside and renamed into place, so a crash never leaves half a chunk to upload; the size
+// on disk
+fn spool(path: &Path, meta: &ChunkMeta, data: &[u8]) -> io::Result<u64> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    serde_json::to_writer(&mut file, meta)?;
+    file.write_all(b"\n")?;
+    file.write_all(&zstd::stream::encode_all(data, ZSTD_LEVEL)?)?;
+    file.sync_all()?;
+    let size = file.metadata()?.len();
+    fs::rename(&tmp, path)?;
+    Ok(size)
+}
+
+// the chunk, and its size on disk
+fn read_chunk(path: &Path) -> io::Result<(ChunkMeta, Vec<u8>, u64)> {
+    let mut file = BufReader::new(File::open(path)?);
+    let mut line = String::new();
+    file.read_line(&mut line)?;
+    let meta = serde_json::from_str(&line)?;
+    let mut body = Vec::new();
+    file.read_to_end(&mut body)?;
+    let size = (line.len() + body.len()) as u64;
+    Ok((meta, body, size))
+}
+
+// a recording as the segments a rotating file sink would write, kept in memory and handed to the
+// shipper a chunk at a time
+pub struct HttpSink {
+    recorder: Recorder,
+    segment: Segment,
+    rotation: Rotation,
+    chunks: Chunks,
+}
+
+// numbers chunks across segments and keeps track of where they start in theirs
+struct Chunks {
+    shipper: Arc<Shipper>,
+    name: String,
+    seq: u64,
+    offset: u64,
+}
+
+impl Chunks {
+    // what the segment wrote since the last chunk; `last` once it is closed
+    fn ship(&mut self, segment: &mut Segment, last: bool) {
+        let number = segment.number;
+        let Store::Memory(data) = segment.store() else {
+            return;
+        };
+        if data.is_empty() && !last {
+            return;
+        }
+        self.shipper.send(&self.name, number, self.seq, self.offset, last, data);
+        self.seq += 1;
+        self.offset = match last {
+            true => 0,
+            false => self.offset + data.len() as u64,
+        };
+        data.clear();
+    }
+}
+
+impl HttpSink {
+    pub fn open(
+        opts: &CastOptions,
+        shipper: Arc<Shipper>,
+        name: &str,
+        start: Instant,
+        meta: Metadata,
+    ) -> io::Result<Self> {
+        let mut recorder = Recorder::new(opts, start, meta);
+        let mut segment = Segment::memory(0, recorder.encrypt_to.as_ref())?;
+        recorder.begin(&mut segment)?;
+        Ok(Self {
+            recorder,
+            segment,
+            rotation: opts.rotation,
+            chunks: Chunks {
+                shipper,
+                name: name.to_owned(),
+                seq: 0,
+                offset: 0,
+            },
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let next = Segment::memory(self.segment.number + 1, self.recorder.encrypt_to.as_ref())?;
+        let mut done = std::mem::replace(&mut self.segment, next);
+        let closed = self.recorder.finish(&mut done);
+        self.chunks.ship(&mut done, true);
+        closed.and(self.recorder.begin(&mut self.segment))
+    }
+}
+
+impl CastSink for HttpSink {
+    fn name(&self) -> String {
+        format!("http {}", self.chunks.shipper.opts.url)
+    }
+
+    fn write(&mut self, event: &SinkEvent) -> io::Result<()> {
+        self.recorder.write(&mut self.segment, event)?;
+        if let Store::Memory(data) = self.segment.store()
+            && data.len() >= CHUNK_BYTES
+        {
+            self.chunks.ship(&mut self.segment, false);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.recorder.tick(&mut self.segment)?;
+        self.chunks.ship(&mut self.segment, false);
+        if self.segment.due(&self.rotation) {
+            self.rotate()?;
+        }
+        // shipped all the same, but kept at the collector's door
+        match self.chunks.shipper.refused() {
+            Some(reason) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("collector refused the token with {}", reason),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn close(&mut self) -> io::Result<()> {
+        let closed = self.recorder.finish(&mut self.segment);
+        self.chunks.ship(&mut self.segment, true);
+        closed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::HeaderMap, http::StatusCode, routing::post};
+    use std::collections::VecDeque;
+
+    // answers with the queued statuses, then 200, and keeps the seq and body of what it took
+    #[derive(Default)]
+    struct Collector {
+        answers: Mutex<VecDeque<u16>>,
+        taken: Mutex<Vec<(u64, Vec<u8>)>>,
+    }
+
+    async fn ingest(
+        State(collector): State<Arc<Collector>>,
+        headers: HeaderMap,
+        body: axum::body::Bytes,
+    ) -> StatusCode {
+        let status = collector.answers.lock().unwrap().pop_front().unwrap_or(200);
+        if status == 200 {
+            let seq = headers[SEQ_HEADER].to_str().unwrap().parse().unwrap();
+            let body = zstd::stream::decode_all(&body[..]).unwrap();
+            collector.taken.lock().unwrap().push((seq, body));
+        }
+        StatusCode::from_u16(status).unwrap()
+    }
+
+    async fn collector(answers: &[u16]) -> (Arc<Collector>, reqwest::Url) {
+        let collector = Arc::new(Collector {
+            answers: Mutex::new(answers.iter().copied().collect()),
+            ..Collector::default()
+        });
+        let app = axum::Router::new()
+            .route("/", post(ingest))
+            .with_state(Arc::clone(&collector));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        (collector, url)
+    }
+
+    fn options(name: &str, url: reqwest::Url) -> ShipOptions {
+        let spool_dir = std::env::temp_dir().join(format!("xterm-rs-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&spool_dir);
+        ShipOptions {
+            url,
+            workspace: "host.example".to_owned(),
+            token: Some("secret".to_owned()),
+            spool_dir,
+            spool_limit: None,
+        }
+    }
+
+    fn meta(seq: u64) -> ChunkMeta {
+        ChunkMeta {
+            recording: "1700".to_owned(),
+            segment: 0,
+            seq,
+            offset: seq * 4,
+            last: false,
+        }
+    }
+
+    // waits for the spool to empty out, a few backoffs at most
+    async fn drained(shipper: &Shipper) {
+        for _ in 0..100 {
+            if shipper.pending().unwrap().is_empty() {
+                return;
+            }
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("spool still holds {:?}", shipper.pending().unwrap());
+    }
+
+    // the spool thread lives as long as the process, so the runtime is not waited on to stop
+    fn shipping(test: impl Future<Output = ()>) {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(test);
+        runtime.shutdown_background();
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let dir = std::env::temp_dir().join(format!("xterm-rs-chunk-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("1700.000000000003.chunk");
+        let size = spool(&path, &meta(3), b"abcd").unwrap();
+        let (back, body, read) = read_chunk(&path).unwrap();
+        assert_eq!((back.seq, back.offset, back.last), (3, 12, false));
+        assert_eq!(zstd::stream::decode_all(&body[..]).unwrap(), b"abcd");
+        assert_eq!(read, size);
+        // nothing left aside
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn spooled_chunks_survive_an_outage() {
+        shipping(async {
+            let (collector, url) = collector(&[503]).await;
+            let opts = options("outage", url);
+            // left by a server that stopped before it could send it
+            fs::create_dir_all(&opts.spool_dir).unwrap();
+            spool(&opts.spool_dir.join("1700.000000000000.chunk"), &meta(0), b"left").unwrap();
+
+            let shipper = Shipper::spawn(opts).unwrap();
+            shipper.send("1700", 0, 1, 4, true, b"next");
+            shipper.settle().await;
+            drained(&shipper).await;
+            let taken = collector.taken.lock().unwrap().clone();
+            assert_eq!(taken, [(0, b"left".to_vec()), (1, b"next".to_vec())]);
+            assert_eq!(shipper.spooled.load(Ordering::Relaxed), 0);
+            assert_eq!(shipper.refused(), None);
+            fs::remove_dir_all(&shipper.opts.spool_dir).unwrap();
+        });
+    }
+
+    #[test]
+    fn rejected_and_refused_chunks() {
+        shipping(async {
+            let (collector, url) = collector(&[400, 401]).await;
+            let shipper = Shipper::spawn(options("refused", url)).unwrap();
+            shipper.send("1700", 0, 0, 0, false, b"bad");
+            shipper.send("1700", 0, 1, 3, false, b"kept");
+            shipper.settle().await;
+            for _ in 0..100 {
+                if shipper.refused().is_some() {
+                    break;
+                }
+                time::sleep(Duration::from_millis(20)).await;
+            }
+            // the rejected one is gone for good, the refused one waits for the token to be fixed
+            assert_eq!(shipper.refused().as_deref(), Some("401 Unauthorized"));
+            let pending = shipper.pending().unwrap();
+            assert_eq!(pending, [shipper.opts.spool_dir.join("1700.000000000001.chunk")]);
+            assert!(collector.taken.lock().unwrap().is_empty());
+            fs::remove_dir_all(&shipper.opts.spool_dir).unwrap();
+        });
+    }
+}
@@ -0,0 +1,514 @@
+use crate::caster::cast::{CastOptions, EventKind, Metadata, RawEvt, encode_evt, encode_header};
+use crate::caster::crypt;
+use crate::caster::queue::{GapInfo, QueueStats, SinkQueue};
+use crate::caster::segment::{FileSink, RotatingFileSink};
+use crate::caster::ship::HttpSink;
+use crate::models::logger;
+use base64::Engine as _;
+use std::{
+    io,
+    os::unix::net::UnixDatagram,
+    path::PathBuf,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{runtime::Handle, sync::mpsc, task, time};
+use x25519_dalek::PublicKey;
+
+const FILE_FLUSH: Duration = Duration::from_millis(10);
+const STDOUT_FLUSH: Duration = Duration::from_secs(120);
+const HTTP_FLUSH: Duration = Duration::from_secs(5);
+const SYSLOG_FLUSH: Duration = Duration::from_secs(1);
+const SYSLOG_PATH: &str = "/dev/log";
+// plaintext per syslog message, so one stays well under what syslog daemons take
+const SYSLOG_CHUNK: usize = 4 << 10;
+// facility user, severity info
+const SYSLOG_PRI: u8 = 14;
+const ZSTD_LEVEL: i32 = 3;
+
+// an event as the caster hands it to every sink: the raw payload, and the bytes on disk
+#[derive(Debug)]
+pub struct SinkEvent {
+    // microseconds since the session started
+    pub elapsed: u64,
+    pub kind: EventKind,
+    pub payload: Vec<u8>,
+    pub bytes: Vec<u8>,
+    // what a gap event stands for, so a queue that drops it need not decode the payload
+    pub gap: Option<GapInfo>,
+}
+
+impl SinkEvent {
+    pub fn gap(elapsed: u64, gap: GapInfo) -> Self {
+        let mut evt = Self::from(gap.event(elapsed));
+        evt.gap = Some(gap);
+        evt
+    }
+}
+
+impl From<RawEvt> for SinkEvent {
+    fn from(evt: RawEvt) -> Self {
+        let bytes = encode_evt(&evt);
+        // the caster's own gaps come through its queue encoded
+        let gap = match evt.kind {
+            EventKind::Gap => match serde_json::from_slice(&evt.payload) {
+                Ok(gap) => Some(gap),
+                Err(e) => {
+                    logger("error", format!("Cast gap event does not decode: {}", e));
+                    None
+                }
+            },
+            _ => None,
+        };
+        Self {
+            elapsed: evt.elapsed,
+            kind: evt.kind,
+            payload: evt.payload,
+            bytes,
+            gap,
+        }
+    }
+}
+
+// somewhere a recording goes. each sink runs on a thread of its own, so one that fails or blocks
+// holds up no other; errors are logged by the thread and the sink is tried again with what comes next
+pub trait CastSink: Send {
+    // for log lines about the sink
+    fn name(&self) -> String;
+    fn write(&mut self, event: &SinkEvent) -> io::Result<()>;
+    // called every flush interval of the sink
+    fn flush(&mut self) -> io::Result<()>;
+    // the recording ended
+    fn close(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SinkKind {
+    // one cast file per recording
+    File,
+    // cast files split into segments by the rotation options
+    RotatingFile,
+    // `["cast", [timestamp, b64]]` json lines, what `log_level = 2` prints
+    Stdout,
+    // segments posted to the collector, see `ship`
+    Http,
+    // the stdout lines as syslog messages on a unix datagram socket
+    Syslog,
+}
+
+// `kind[,flush=DURATION][,path=PATH]`, e.g. `stdout,flush=30s` or `syslog,path=/dev/log`
+#[derive(Debug, Clone)]
+pub struct SinkSpec {
+    pub kind: SinkKind,
+    pub flush: Option<Duration>,
+    pub path: Option<PathBuf>,
+}
+
+impl SinkSpec {
+    pub fn new(kind: SinkKind) -> Self {
+        Self {
+            kind,
+            flush: None,
+            path: None,
+        }
+    }
+
+    pub fn flush(&self) -> Duration {
+        self.flush.unwrap_or(match self.kind {
+            SinkKind::File | SinkKind::RotatingFile => FILE_FLUSH,
+            SinkKind::Stdout => STDOUT_FLUSH,
+            SinkKind::Http => HTTP_FLUSH,
+            SinkKind::Syslog => SYSLOG_FLUSH,
+        })
+    }
+
+    pub fn writes_files(&self) -> bool {
+        matches!(self.kind, SinkKind::File | SinkKind::RotatingFile)
+    }
+
+    pub fn open(
+        &self,
+        opts: &CastOptions,
+        name: &str,
+        start: Instant,
+        meta: &Metadata,
+    ) -> io::Result<Box<dyn CastSink>> {
+        Ok(match self.kind {
+            SinkKind::File => Box::new(FileSink::open(opts, name, start, meta.clone())?),
+            SinkKind::RotatingFile => Box::new(RotatingFileSink::open(opts, name, start, meta.clone())?),
+            SinkKind::Stdout => Box::new(StdoutSink {
+                stream: LogStream::new(opts, meta),
+            }),
+            SinkKind::Http => {
+                let shipper = opts
+                    .shipper
+                    .clone()
+                    .ok_or_else(|| io::Error::other("the http sink needs --collector-url"))?;
+                Box::new(HttpSink::open(opts, shipper, name, start, meta.clone())?)
+            }
+            SinkKind::Syslog => Box::new(SyslogSink {
+                path: self.path.clone().unwrap_or_else(|| PathBuf::from(SYSLOG_PATH)),
+                socket: None,
+                stream: LogStream::new(opts, meta),
+            }),
+        })
+    }
+}
+
+impl FromStr for SinkSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let kind = match parts.next().unwrap_or_default().trim() {
+            "file" => SinkKind::File,
+            "rotating-file" => SinkKind::RotatingFile,
+            "stdout" => SinkKind::Stdout,
+            "http" => SinkKind::Http,
+            "syslog" => SinkKind::Syslog,
+            other => {
+                return Err(format!(
+                    "unknown sink '{}', expected file, rotating-file, stdout, http or syslog",
+                    other
+                ));
+            }
+        };
+        let mut spec = SinkSpec::new(kind);
+        for part in parts {
+            match part.trim().split_once('=') {
+                Some(("flush", value)) => spec.flush = Some(parse_duration(value)?),
+                Some(("path", value)) if kind == SinkKind::Syslog => spec.path = Some(PathBuf::from(value)),
+                _ => return Err(format!("unknown sink option '{}'", part)),
+            }
+        }
+        Ok(spec)
+    }
+}
+
+// `250ms`, `5s`, `2m`, or bare seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("invalid duration '{}'", s))?;
+    let d = match unit {
+        "ms" => Duration::from_millis(n),
+        "" | "s" => Duration::from_secs(n),
+        "m" => Duration::from_secs(n * 60),
+        _ => return Err(format!("invalid duration '{}', expected ms, s or m", s)),
+    };
+    match d.is_zero() {
+        true => Err(format!("duration '{}' must not be zero", s)),
+        false => Ok(d),
+    }
+}
+
+// runs a sink until the caster drops its queue, then closes it
+pub fn spawn(mut sink: Box<dyn CastSink>, flush: Duration, size: usize) -> SinkQueue {
+    let (tx, mut rx) = mpsc::channel::<Arc<SinkEvent>>(size);
+    let stats = Arc::new(QueueStats::default());
+    let task_stats = Arc::clone(&stats);
+    let name = sink.name();
+    // the sink's calls block on the disk or the network, so they run on a thread of their own
+    let runtime = Handle::current();
+    let task = task::spawn_blocking(move || {
+        let mut health = Health::new(format!("Cast sink {}", sink.name()));
+        let mut next_flush = time::Instant::now() + flush;
+        loop {
+            match runtime.block_on(time::timeout_at(next_flush, rx.recv())) {
+                Ok(Some(event)) => {
+                    task_stats.dequeue();
+                    health.check("write", sink.write(&event));
+                }
+                Ok(None) => break,
+                Err(_) => {}
+            }
+            // checked after every event too, so a steady stream does not hold the flush off
+            if time::Instant::now() >= next_flush {
+                health.check("flush", sink.flush());
+                next_flush = time::Instant::now() + flush;
+            }
+        }
+        health.check("close", sink.close());
+    });
+    SinkQueue::new(name, tx, stats, task)
+}
+
+// logs the first failure of a sink and its recovery, not every failure in between; only the
+// kind of call that failed shows it recovered, a write that only buffers says nothing of a flush
+pub(crate) struct Health {
+    // what failed, as the log lines start
+    name: String,
+    failing: Option<&'static str>,
+    failures: u64,
+}
+
+impl Health {
+    pub(crate) fn new(name: String) -> Self {
+        Self {
+            name,
+            failing: None,
+            failures: 0,
+        }
+    }
+
+    pub(crate) fn check(&mut self, what: &'static str, result: io::Result<()>) {
+        match result {
+            Ok(()) if self.failing == Some(what) => {
+                logger(
+                    "info",
+                    format!("{} recovered after {} failures", self.name, self.failures),
+                );
+                self.failing = None;
+                self.failures = 0;
+            }
+            Ok(()) => {}
+            Err(e) => {
+                if self.failing.is_none() {
+                    logger("error", format!("{} failed to {}: {}", self.name, what, e));
+                    self.failing = Some(what);
+                }
+                self.failures += 1;
+            }
+        }
+    }
+}
+
+// the header and every event but input, cut into chunks that each decode on their own
+// once the earlier ones are in; events are never split between chunks
+struct LogStream {
+    timestamp: u128,
+    buf: Vec<u8>,
+    // where each event in `buf` ends
+    ends: Vec<usize>,
+    encrypt_to: Option<PublicKey>,
+}
+
+impl LogStream {
+    fn new(opts: &CastOptions, meta: &Metadata) -> Self {
+        let buf = encode_header(meta);
+        Self {
+            timestamp: meta.timestamp,
+            ends: vec![buf.len()],
+            buf,
+            encrypt_to: opts.encrypt_to,
+        }
+    }
+
+    fn write(&mut self, event: &SinkEvent) {
+        if event.kind.is_input() {
+            return;
+        }
+        self.buf.extend_from_slice(&event.bytes);
+        self.ends.push(self.buf.len());
+    }
+
+    // the waiting events as base64 chunks of about `max` bytes of plaintext each
+    fn take(&mut self, max: usize) -> io::Result<Vec<String>> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        let mut ends = std::mem::take(&mut self.ends).into_iter().peekable();
+        while let Some(mut end) = ends.next() {
+            while let Some(&next) = ends.peek()
+                && next - start <= max
+            {
+                end = next;
+                ends.next();
+            }
+            let plain = &self.buf[start..end];
+            let chunk = match &self.encrypt_to {
+                Some(recipient) => crypt::seal_message(plain, recipient)?,
+                None => zstd::stream::encode_all(plain, ZSTD_LEVEL)?,
+            };
+            chunks.push(base64::engine::general_purpose::STANDARD.encode(chunk));
+            start = end;
+        }
+        self.buf.clear();
+        Ok(chunks)
+    }
+}
+
+pub struct StdoutSink {
+    stream: LogStream,
+}
+
+impl CastSink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".to_owned()
+    }
+
+    fn write(&mut self, event: &SinkEvent) -> io::Result<()> {
+        self.stream.write(event);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for chunk in self.stream.take(usize::MAX)? {
+            logger("cast", serde_json::json!([self.stream.timestamp, chunk]));
+        }
+        Ok(())
+    }
+}
+
+// the stdout lines behind a syslog tag; `xterm-rs export` reads them back out of a syslog dump.
+// the socket never blocks, a message it cannot take is dropped
+pub struct SyslogSink {
+    path: PathBuf,
+    socket: Option<UnixDatagram>,
+    stream: LogStream,
+}
+
+impl SyslogSink {
+    fn connect(&mut self) -> io::Result<&UnixDatagram> {
+        match &mut self.socket {
+            Some(socket) => Ok(socket),
+            slot => {
+                let socket = UnixDatagram::unbound()?;
+                socket.connect(&self.path)?;
+                socket.set_nonblocking(true)?;
+                Ok(slot.insert(socket))
+            }
+        }
+    }
+}
+
+impl CastSink for SyslogSink {
+    fn name(&self) -> String {
+        format!("syslog {}", self.path.display())
+    }
+
+    fn write(&mut self, event: &SinkEvent) -> io::Result<()> {
+        self.stream.write(event);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let chunks = self.stream.take(SYSLOG_CHUNK)?;
+        if chunks.is_empty() {
+            return Ok(());
+        }
+        let timestamp = self.stream.timestamp;
+        let socket = self.connect()?;
+        let mut dropped = 0;
+        let mut failure = None;
+        for chunk in &chunks {
+            let line = serde_json::json!(["cast", [timestamp, chunk]]);
+            let message = format!("<{}>xterm-rs[{}]: {}", SYSLOG_PRI, std::process::id(), line);
+            if let Err(e) = socket.send(message.as_bytes()) {
+                dropped += 1;
+                failure.get_or_insert(e);
+            }
+        }
+        match failure {
+            None => Ok(()),
+            Some(e) => {
+                // the daemon may have restarted; connect again next time
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    self.socket = None;
+                }
+                Err(io::Error::new(
+                    e.kind(),
+                    format!("dropped {} of {} messages: {}", dropped, chunks.len(), e),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caster::queue::QueuePolicy;
+    use std::sync::Mutex;
+
+    #[test]
+    fn specs() {
+        let spec: SinkSpec = "rotating-file".parse().unwrap();
+        assert_eq!((spec.kind, spec.flush()), (SinkKind::RotatingFile, FILE_FLUSH));
+        assert!(spec.writes_files());
+
+        let spec: SinkSpec = "stdout, flush=30s".parse().unwrap();
+        assert_eq!((spec.kind, spec.flush()), (SinkKind::Stdout, Duration::from_secs(30)));
+        assert!(!spec.writes_files());
+
+        let spec: SinkSpec = "syslog,path=/run/log,flush=250ms".parse().unwrap();
+        assert_eq!(spec.path.as_deref(), Some(std::path::Path::new("/run/log")));
+        assert_eq!(spec.flush, Some(Duration::from_millis(250)));
+
+        for bad in [
+            "",
+            "tcp",
+            "file,path=/x",
+            "http,flush",
+            "stdout,flush=0",
+            "stdout,level=2",
+        ] {
+            assert!(bad.parse::<SinkSpec>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration(" 5s "), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        for bad in ["", "0s", "s", "-1s", "1h", "1.5s"] {
+            assert!(parse_duration(bad).is_err(), "{bad:?}");
+        }
+    }
+
+    // what a sink was asked to do, in order
+    struct Calls(Arc<Mutex<Vec<String>>>);
+
+    impl CastSink for Calls {
+        fn name(&self) -> String {
+            "calls".to_owned()
+        }
+
+        fn write(&mut self, event: &SinkEvent) -> io::Result<()> {
+            let payload = String::from_utf8_lossy(&event.payload).into_owned();
+            self.0.lock().unwrap().push(payload);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push("flush".to_owned());
+            Ok(())
+        }
+
+        fn close(&mut self) -> io::Result<()> {
+            self.0.lock().unwrap().push("close".to_owned());
+            Ok(())
+        }
+    }
+
+    fn output(s: &str) -> Arc<SinkEvent> {
+        Arc::new(SinkEvent::from(RawEvt {
+            elapsed: 0,
+            kind: EventKind::Output,
+            payload: s.as_bytes().to_vec(),
+        }))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn runs_until_closed() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let mut queue = spawn(Box::new(Calls(Arc::clone(&calls))), Duration::from_millis(20), 4);
+        queue.send(&output("a"), QueuePolicy::Block).await;
+        queue.send(&output("b"), QueuePolicy::Block).await;
+        time::sleep(Duration::from_millis(70)).await;
+        queue.send(&output("c"), QueuePolicy::Block).await;
+        queue.close().await;
+
+        let calls = calls.lock().unwrap().clone();
+        assert_eq!(calls[..2], ["a", "b"]);
+        assert_eq!(calls.last().map(String::as_str), Some("close"));
+        // flushed while idle, and every event written before the close
+        let c = calls.iter().position(|call| call == "c").unwrap();
+        assert!(calls[2..c].iter().all(|call| call == "flush") && c > 2, "{calls:?}");
+    }
+}
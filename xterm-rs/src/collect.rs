@@ -0,0 +1,636 @@
+use crate::caster::{rotate, ship};
+use crate::models::{logger, read_token, unix_millis};
+use anyhow::Context;
+use axum::{
+    Extension, Json, Router,
+    body::Bytes,
+    extract::{DefaultBodyLimit, Path, Request},
+    http::{HeaderMap, StatusCode, header},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use clap::ValueHint;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{self, Read, Write},
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::FileExt,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+};
+use tokio::sync::Mutex;
+use tower_http::services::ServeDir;
+
+// largest chunk taken once decompressed; the shipper cuts them at about a megabyte
+const MAX_CHUNK: usize = 16 << 20;
+const MAX_NAME_LEN: usize = 128;
+// next to the segments of each recording: which chunks and bytes have arrived
+const STATE_SUFFIX: &str = ".chunks.json";
+// how far past what a segment holds a chunk may start; the shipper sends them in order
+const MAX_AHEAD: u64 = 4 * MAX_CHUNK as u64;
+
+#[derive(clap::Args, Debug)]
+pub struct CollectArgs {
+    #[arg(
+        long,
+        value_hint = ValueHint::DirPath,
+        long_help = "Where recordings are stored, in a directory per workspace named like the server's log_dir"
+    )]
+    dir: PathBuf,
+
+    #[arg(short, long, default_value_t = 8090u16, long_help = "Port to listen on")]
+    port: u16,
+
+    #[arg(
+        long,
+        value_name = "ADDR",
+        default_value = "0.0.0.0",
+        long_help = "Address to listen on"
+    )]
+    bind: IpAddr,
+
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        long_help = "Bearer token that uploads and the listing must carry, as text or @file to read it from\nGive the servers the same with --collector-token\nRequired unless --bind is a loopback address"
+    )]
+    token: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+enum CollectError {
+    #[error("missing or invalid {0} header")]
+    Header(&'static str),
+    #[error("invalid {0} '{1}'")]
+    Name(&'static str, String),
+    #[error("chunk does not decode: {0}")]
+    Body(String),
+    #[error("offset {0} is too far past the end of the segment")]
+    Offset(u64),
+    #[error("missing or wrong bearer token")]
+    Unauthorized,
+    #[error("workspace '{0}' not found")]
+    NotFound(String),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+}
+
+impl IntoResponse for CollectError {
+    fn into_response(self) -> Response {
+        let code = match &self {
+            CollectError::Header(_) | CollectError::Name(..) | CollectError::Body(_) | CollectError::Offset(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            CollectError::Unauthorized => StatusCode::UNAUTHORIZED,
+            CollectError::NotFound(_) => StatusCode::NOT_FOUND,
+            CollectError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (code, self.to_string()).into_response()
+    }
+}
+
+// sorted, disjoint and non-adjacent half-open ranges
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+struct Ranges(Vec<(u64, u64)>);
+
+impl Ranges {
+    fn contains(&self, start: u64, end: u64) -> bool {
+        self.0.iter().any(|&(s, e)| s <= start && end <= e)
+    }
+
+    fn insert(&mut self, mut start: u64, mut end: u64) {
+        if start >= end {
+            return;
+        }
+        self.0.retain(|&(s, e)| {
+            let touching = s <= end && start <= e;
+            if touching {
+                (start, end) = (start.min(s), end.max(e));
+            }
+            !touching
+        });
+        let at = self.0.partition_point(|&(s, _)| s < start);
+        self.0.insert(at, (start, end));
+    }
+
+    fn end(&self) -> u64 {
+        self.0.last().map_or(0, |&(_, e)| e)
+    }
+
+    fn count(&self) -> u64 {
+        self.0.iter().map(|&(s, e)| e - s).sum()
+    }
+
+    // what is not covered before `end`
+    fn gaps(&self, end: u64) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut at = 0;
+        for &(s, e) in &self.0 {
+            if s >= end {
+                break;
+            }
+            if s > at {
+                gaps.push((at, s));
+            }
+            at = e;
+        }
+        if at < end {
+            gaps.push((at, end));
+        }
+        gaps
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct SegmentState {
+    written: Ranges,
+    // the file's length, once the chunk closing it arrived
+    closed: Option<u64>,
+}
+
+impl SegmentState {
+    fn complete(&self) -> bool {
+        self.closed.is_some_and(|len| self.written.gaps(len).is_empty())
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Recording {
+    // sequence numbers taken, so a chunk sent again after a lost ack is not written twice
+    seqs: Ranges,
+    segments: BTreeMap<u32, SegmentState>,
+    // unix millis of the last chunk
+    updated: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct SegmentInfo {
+    segment: u32,
+    file: String,
+    bytes: u64,
+    // closed by the server and nothing missing
+    complete: bool,
+    // byte ranges not received yet; the end of an open segment is not known
+    missing: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Serialize)]
+struct RecordingInfo {
+    workspace: String,
+    recording: String,
+    updated: u128,
+    chunks: u64,
+    segments: Vec<SegmentInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct Ack {
+    seq: u64,
+    duplicate: bool,
+}
+
+// keyed by workspace, then recording; each locked on its own, so a chunk waiting on the disk holds
+// up only the recording it belongs to
+type Recordings = BTreeMap<(String, String), Arc<Mutex<Recording>>>;
+
+struct Collector {
+    dir: PathBuf,
+    token: Option<String>,
+    recordings: std::sync::Mutex<Recordings>,
+}
+
+impl Collector {
+    fn recording(&self, workspace: &str, recording: &str) -> Arc<Mutex<Recording>> {
+        let mut recordings = self.recordings.lock().unwrap();
+        let key = (workspace.to_owned(), recording.to_owned());
+        Arc::clone(recordings.entry(key).or_default())
+    }
+
+    // the recordings matching `keep`, each as it is once no chunk is being written to it
+    async fn list(&self, keep: impl Fn(&str) -> bool) -> Vec<RecordingInfo> {
+        let recordings: Vec<_> = {
+            let recordings = self.recordings.lock().unwrap();
+            recordings
+                .iter()
+                .filter(|((workspace, _), _)| keep(workspace))
+                .map(|(key, state)| (key.clone(), Arc::clone(state)))
+                .collect()
+        };
+        let mut list = Vec::with_capacity(recordings.len());
+        for ((workspace, recording), state) in recordings {
+            let state = state.lock().await;
+            // created by a chunk that was then refused
+            if state.seqs.count() > 0 {
+                list.push(info(&workspace, &recording, &state));
+            }
+        }
+        list
+    }
+
+    fn workspace_dir(&self, workspace: &str) -> PathBuf {
+        self.dir.join(workspace)
+    }
+
+    fn state_path(&self, workspace: &str, recording: &str) -> PathBuf {
+        self.workspace_dir(workspace)
+            .join(format!("{}{}", recording, STATE_SUFFIX))
+    }
+}
+
+pub async fn run(args: CollectArgs) -> anyhow::Result<()> {
+    let token = args.token.as_deref().map(read_token).transpose()?;
+    if token.is_none() && !args.bind.is_loopback() {
+        anyhow::bail!("--token is required unless --bind is a loopback address");
+    }
+    fs::create_dir_all(&args.dir).with_context(|| format!("create {}", args.dir.display()))?;
+    let recordings = load(&args.dir)?;
+    logger(
+        "info",
+        format!("Loaded {} recordings from {}", recordings.len(), args.dir.display()),
+    );
+    let collector = Arc::new(Collector {
+        dir: args.dir.clone(),
+        token,
+        recordings: std::sync::Mutex::new(recordings),
+    });
+
+    let files = Router::new()
+        .fallback_service(ServeDir::new(&args.dir))
+        .layer(middleware::from_fn(hide_state));
+    let app = Router::new()
+        .route("/ingest", post(ingest))
+        .route("/recordings", get(list_recordings))
+        .route("/recordings/{workspace}", get(list_workspace))
+        .nest("/files", files)
+        .layer(DefaultBodyLimit::max(MAX_CHUNK))
+        .layer(middleware::from_fn(authorize))
+        .layer(Extension(collector));
+
+    let listener = tokio::net::TcpListener::bind(SocketAddr::new(args.bind, args.port)).await?;
+    logger("info", format!("Collecting on http://{}", listener.local_addr()?));
+    axum::serve(listener, app).await.context("server error")
+}
+
+fn load(dir: &FsPath) -> anyhow::Result<Recordings> {
+    let mut recordings = Recordings::new();
+    for workspace in fs::read_dir(dir)? {
+        let workspace = workspace?;
+        if !workspace.file_type()?.is_dir() {
+            continue;
+        }
+        let ws = workspace.file_name().to_string_lossy().into_owned();
+        for entry in fs::read_dir(workspace.path())? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+            let Some(recording) = name.strip_suffix(STATE_SUFFIX) else {
+                continue;
+            };
+            let state = fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|data| Ok(serde_json::from_slice(&data)?));
+            match state {
+                Ok(state) => {
+                    recordings.insert((ws.clone(), recording.to_owned()), Arc::new(Mutex::new(state)));
+                }
+                Err(e) => logger("error", format!("Skipping {}: {}", path.display(), e)),
+            }
+        }
+    }
+    Ok(recordings)
+}
+
+// constant time, so the token cannot be guessed a byte at a time
+fn same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+async fn authorize(Extension(collector): Extension<Arc<Collector>>, request: Request, next: Next) -> Response {
+    if let Some(token) = &collector.token {
+        let given = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "));
+        if !given.is_some_and(|given| same(given.as_bytes(), token.as_bytes())) {
+            return CollectError::Unauthorized.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+// the bookkeeping next to the segments is not one of the recordings
+async fn hide_state(request: Request, next: Next) -> Response {
+    let path = request.uri().path();
+    if path.ends_with(STATE_SUFFIX) || path.ends_with(".tmp") {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    next.run(request).await
+}
+
+// workspace ids become directory names and may be hostnames; recordings are named like the
+// server's cast files, where a dot would run into the segment numbers
+fn valid_name(name: &str, dots: bool) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && !name.starts_with('.')
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_' || (dots && b == b'.'))
+}
+
+fn name_header(headers: &HeaderMap, key: &'static str, what: &'static str, dots: bool) -> Result<String, CollectError> {
+    let value = headers
+        .get(key)
+        .and_then(|v| v.to_str().ok())
+        .ok_or(CollectError::Header(key))?;
+    match valid_name(value, dots) {
+        true => Ok(value.to_owned()),
+        false => Err(CollectError::Name(what, value.to_owned())),
+    }
+}
+
+fn number_header<T: std::str::FromStr>(headers: &HeaderMap, key: &'static str) -> Result<T, CollectError> {
+    headers
+        .get(key)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or(CollectError::Header(key))
+}
+
+fn decode(headers: &HeaderMap, body: &[u8]) -> Result<Vec<u8>, CollectError> {
+    match headers.get(header::CONTENT_ENCODING).map(|v| v.as_bytes()) {
+        None | Some(b"identity") => Ok(body.to_vec()),
+        Some(b"zstd") => {
+            let mut data = Vec::new();
+            zstd::stream::Decoder::new(body)
+                .and_then(|decoder| decoder.take(MAX_CHUNK as u64 + 1).read_to_end(&mut data))
+                .map_err(|e| CollectError::Body(e.to_string()))?;
+            match data.len() > MAX_CHUNK {
+                true => Err(CollectError::Body(format!("larger than {} bytes", MAX_CHUNK))),
+                false => Ok(data),
+            }
+        }
+        Some(_) => Err(CollectError::Header("content-encoding")),
+    }
+}
+
+// one chunk of a segment, as the shipper sends them: the headers say where it goes
+async fn ingest(
+    Extension(collector): Extension<Arc<Collector>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<Json<Ack>, CollectError> {
+    let workspace = name_header(&headers, ship::WORKSPACE_HEADER, "workspace", true)?;
+    let recording = name_header(&headers, ship::RECORDING_HEADER, "recording", false)?;
+    let segment: u32 = number_header(&headers, ship::SEGMENT_HEADER)?;
+    let seq: u64 = number_header(&headers, ship::SEQ_HEADER)?;
+    let offset: u64 = number_header(&headers, ship::OFFSET_HEADER)?;
+    let last = headers.contains_key(ship::LAST_HEADER);
+    let data = decode(&headers, &body)?;
+    let end = offset
+        .checked_add(data.len() as u64)
+        .ok_or(CollectError::Header(ship::OFFSET_HEADER))?;
+
+    let seq_end = seq.checked_add(1).ok_or(CollectError::Header(ship::SEQ_HEADER))?;
+
+    let entry = collector.recording(&workspace, &recording);
+    let mut state = entry.lock().await;
+    if state.seqs.contains(seq, seq_end) {
+        return Ok(Json(Ack { seq, duplicate: true }));
+    }
+    // a chunk may arrive before the one ahead of it, but not leave a hole the size of a disk
+    let written = state.segments.get(&segment).map_or(0, |seg| seg.written.end());
+    if offset > written.saturating_add(MAX_AHEAD) {
+        return Err(CollectError::Offset(offset));
+    }
+
+    let mut next = state.clone();
+    let seg = next.segments.entry(segment).or_default();
+    let was_complete = seg.complete();
+    seg.written.insert(offset, end);
+    if last {
+        seg.closed = Some(end);
+    }
+    let complete = seg.complete() && !was_complete;
+    next.seqs.insert(seq, seq_end);
+    next.updated = unix_millis();
+
+    let dir = collector.workspace_dir(&workspace);
+    let path = rotate::segment_path(&dir, &recording, segment);
+    let state_path = collector.state_path(&workspace, &recording);
+    let saved = serde_json::to_vec(&next).map_err(io::Error::from)?;
+    let written = path.clone();
+    tokio::task::spawn_blocking(move || -> io::Result<()> {
+        fs::create_dir_all(&dir)?;
+        // chunks land at their offset, so one arriving late still fills its place
+        OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&written)?
+            .write_all_at(&data, offset)?;
+        save(&state_path, &saved)
+    })
+    .await
+    .map_err(io::Error::other)??;
+
+    *state = next;
+    if complete {
+        logger("info", format!("Received all of {}", path.display()));
+    }
+    Ok(Json(Ack { seq, duplicate: false }))
+}
+
+// written aside and renamed into place, so a crash leaves the old state whole
+fn save(path: &FsPath, state: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(state)?;
+    file.write_all(b"\n")?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn info(workspace: &str, recording: &str, state: &Recording) -> RecordingInfo {
+    let segments = state
+        .segments
+        .iter()
+        .map(|(&number, seg)| {
+            let path = rotate::segment_path(FsPath::new(""), recording, number);
+            SegmentInfo {
+                segment: number,
+                file: format!("{}/{}", workspace, path.display()),
+                bytes: seg.written.count(),
+                complete: seg.complete(),
+                missing: seg.written.gaps(seg.closed.unwrap_or_else(|| seg.written.end())),
+            }
+        })
+        .collect();
+    RecordingInfo {
+        workspace: workspace.to_owned(),
+        recording: recording.to_owned(),
+        updated: state.updated,
+        chunks: state.seqs.count(),
+        segments,
+    }
+}
+
+async fn list_recordings(Extension(collector): Extension<Arc<Collector>>) -> Json<Vec<RecordingInfo>> {
+    Json(collector.list(|_| true).await)
+}
+
+async fn list_workspace(
+    Path(workspace): Path<String>,
+    Extension(collector): Extension<Arc<Collector>>,
+) -> Result<Json<Vec<RecordingInfo>>, CollectError> {
+    let list = collector.list(|ws| ws == workspace).await;
+    match list.is_empty() {
+        true => Err(CollectError::NotFound(workspace)),
+        false => Ok(Json(list)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(list: &[(u64, u64)]) -> Ranges {
+        let mut ranges = Ranges::default();
+        for &(s, e) in list {
+            ranges.insert(s, e);
+        }
+        ranges
+    }
+
+    #[test]
+    fn ranges_merge() {
+        // out of order, overlapping, adjacent and empty
+        let r = ranges(&[(10, 20), (0, 5), (30, 40), (5, 7), (15, 32), (50, 50), (60, 55)]);
+        assert_eq!(r.0, [(0, 7), (10, 40)]);
+        assert_eq!((r.count(), r.end()), (37, 40));
+        assert!(r.contains(10, 40) && r.contains(0, 1) && r.contains(3, 3));
+        assert!(!r.contains(6, 11) && !r.contains(39, 41));
+    }
+
+    #[test]
+    fn ranges_gaps() {
+        let r = ranges(&[(4, 8), (10, 12)]);
+        assert_eq!(r.gaps(20), [(0, 4), (8, 10), (12, 20)]);
+        assert_eq!(r.gaps(9), [(0, 4), (8, 9)]);
+        assert_eq!(r.gaps(4), [(0, 4)]);
+        assert!(ranges(&[(0, 12)]).gaps(12).is_empty());
+        assert!(Ranges::default().gaps(0).is_empty());
+    }
+
+    async fn send(
+        collector: &Arc<Collector>,
+        seq: u64,
+        offset: u64,
+        data: &[u8],
+        last: bool,
+    ) -> Result<bool, CollectError> {
+        let mut headers = HeaderMap::new();
+        let pairs = [
+            (ship::WORKSPACE_HEADER, "host.example"),
+            (ship::RECORDING_HEADER, "rec"),
+            (ship::SEGMENT_HEADER, "1"),
+            (ship::SEQ_HEADER, &seq.to_string()),
+            (ship::OFFSET_HEADER, &offset.to_string()),
+        ];
+        for (key, value) in pairs {
+            headers.insert(key, value.parse().unwrap());
+        }
+        if last {
+            headers.insert(ship::LAST_HEADER, "1".parse().unwrap());
+        }
+        let body = Bytes::copy_from_slice(data);
+        let Json(ack) = ingest(Extension(Arc::clone(collector)), headers, body).await?;
+        assert_eq!(ack.seq, seq);
+        Ok(ack.duplicate)
+    }
+
+    #[tokio::test]
+    async fn ingest_takes_each_chunk_once() {
+        let dir = std::env::temp_dir().join(format!("xterm-rs-collect-{}", std::process::id()));
+        let collector = Arc::new(Collector {
+            dir: dir.clone(),
+            token: None,
+            recordings: Default::default(),
+        });
+
+        // out of order, and one sent again after its ack was lost
+        assert!(!send(&collector, 1, 4, b"efgh", true).await.unwrap());
+        assert!(!send(&collector, 0, 0, b"abcd", false).await.unwrap());
+        assert!(send(&collector, 1, 4, b"efgh", true).await.unwrap());
+        assert!(matches!(
+            send(&collector, 2, 8 + MAX_AHEAD + 1, b"x", false).await,
+            Err(CollectError::Offset(_))
+        ));
+        assert!(matches!(
+            send(&collector, u64::MAX, 8, b"x", false).await,
+            Err(CollectError::Header(ship::SEQ_HEADER))
+        ));
+
+        let list = collector.list(|_| true).await;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].chunks, 2);
+        assert!(list[0].segments[0].complete);
+        let ws = dir.join("host.example");
+        assert_eq!(fs::read(ws.join("rec.1.cast")).unwrap(), b"abcdefgh");
+
+        // the refused chunks left nothing behind, and the state survives a restart
+        let loaded = load(&dir).unwrap();
+        let state = loaded[&("host.example".to_owned(), "rec".to_owned())].lock().await;
+        assert_eq!(state.seqs.0, [(0, 2)]);
+        assert_eq!(state.segments[&1].written.0, [(0, 8)]);
+        assert!(state.segments[&1].complete());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segment_complete_once_closed_without_gaps() {
+        let mut seg = SegmentState::default();
+        seg.written.insert(0, 100);
+        seg.written.insert(200, 300);
+        assert!(!seg.complete());
+        seg.closed = Some(300);
+        assert!(!seg.complete());
+        seg.written.insert(100, 200);
+        assert!(seg.complete());
+        // closed longer than what has arrived
+        seg.closed = Some(301);
+        assert!(!seg.complete());
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut state = Recording::default();
+        state.seqs.insert(0, 3);
+        state.segments.entry(1).or_default().written.insert(0, 42);
+        state.segments.get_mut(&1).unwrap().closed = Some(42);
+        let json = serde_json::to_string(&state).unwrap();
+        assert!(json.contains(r#""seqs":[[0,3]]"#), "{json}");
+        let back: Recording = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.seqs.0, [(0, 3)]);
+        assert!(back.segments[&1].complete());
+        // state written before a field existed still loads
+        let old: Recording = serde_json::from_str(r#"{"seqs":[[0,1]]}"#).unwrap();
+        assert!(old.segments.is_empty());
+    }
+
+    #[test]
+    fn names() {
+        assert!(valid_name("host-1.example.com", true));
+        assert!(!valid_name("host-1.example.com", false));
+        for bad in ["", ".", "..", ".hidden", "a/b", "a b", &"x".repeat(MAX_NAME_LEN + 1)] {
+            assert!(!valid_name(bad, true), "{bad:?}");
+        }
+        assert!(same(b"token", b"token") && !same(b"token", b"tokeN") && !same(b"token", b"tok"));
+    }
+}
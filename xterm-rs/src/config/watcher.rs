@@ -36,10 +36,7 @@ impl ConfigWatcher {
 }
 
 pub async fn spawn_cfg_watcher(path: PathBuf) -> Result<(ConfigWatcher, JoinHandle<()>)> {
-    let init_cfg = read_cfg(&path).await.unwrap_or_else(|_| AppConfig {
-        layout: "qwerty".into(),
-        theme: "Default".into(),
-    });
+    let init_cfg = read_cfg(&path).await.unwrap_or_default();
 
     let (tx, _rx_cfg) = watch::channel(init_cfg.clone());
 
@@ -0,0 +1,212 @@
+use crate::caster::asciicast::CAST_LINE;
+use crate::caster::{CastReader, asciicast, cast, crypt, index, rotate, seal};
+use crate::replay::parse_time;
+use anyhow::{Context, bail};
+use base64::Engine as _;
+use clap::ValueHint;
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct ExportArgs {
+    #[arg(
+        value_hint = ValueHint::FilePath,
+        long_help = "Recording (.cast or .cast.zst) or stdout log of a server run with log_level 2; - reads stdin"
+    )]
+    input: PathBuf,
+
+    #[arg(short, long, value_hint = ValueHint::FilePath, long_help = "Where to write [default: stdout]")]
+    output: Option<PathBuf>,
+
+    #[arg(long, long_help = "Which recording of a log to export, by its start timestamp")]
+    timestamp: Option<u128>,
+
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_time,
+        long_help = "Start at this point of the recording (SECS, MM:SS or HH:MM:SS), from the keyframe before it"
+    )]
+    from: Option<f64>,
+
+    #[arg(
+        long,
+        default_value_t = 24u16,
+        long_help = "Terminal rows if the recording does not say"
+    )]
+    rows: u16,
+
+    #[arg(
+        long,
+        default_value_t = 80u16,
+        long_help = "Terminal columns if the recording does not say"
+    )]
+    cols: u16,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ImportArgs {
+    #[arg(value_hint = ValueHint::FilePath, long_help = "asciicast v2 file; - reads stdin")]
+    input: PathBuf,
+
+    #[arg(short, long, value_hint = ValueHint::FilePath, long_help = "Recording (.cast) to write")]
+    output: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DecryptArgs {
+    #[arg(
+        value_hint = ValueHint::FilePath,
+        long_help = "Encrypted recording, or stdout log of a server run with log_level 2; - reads stdin"
+    )]
+    input: PathBuf,
+
+    #[arg(short, long, value_hint = ValueHint::FilePath, long_help = "Where to write [default: stdout]")]
+    output: Option<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "KEY",
+        long_help = "Private key from `xterm-rs keygen --encryption`, as a file or hex"
+    )]
+    key: String,
+}
+
+pub fn export(args: ExportArgs) -> anyhow::Result<()> {
+    let data = read_input(&args.input)?;
+    let from_log = is_log(&data);
+    let cast = if from_log {
+        let mut casts = asciicast::read_log(&data[..])?;
+        let timestamp = match (args.timestamp, casts.len()) {
+            (Some(ts), _) => ts,
+            (None, 1) => *casts.keys().next().unwrap(),
+            (None, 0) => bail!("no cast chunks in {}", args.input.display()),
+            (None, _) => {
+                let found: Vec<String> = casts.keys().map(|ts| ts.to_string()).collect();
+                bail!(
+                    "several recordings in the log, pick one with --timestamp: {}",
+                    found.join(", ")
+                );
+            }
+        };
+        casts
+            .remove(&timestamp)
+            .with_context(|| format!("no recording started at {}", timestamp))?
+    } else {
+        data
+    };
+
+    let mut reader = CastReader::new(io::Cursor::new(&cast[..]))?;
+    let from = args.from.unwrap_or(0.0);
+    if from > 0.0 {
+        let index = match index::read_index(&index::index_path(&args.input)) {
+            Ok(index) if !from_log => index,
+            _ => index::build_index(CastReader::new(&cast[..])?)?,
+        };
+        if let Some(keyframe) = index::keyframe_before(&index, from) {
+            reader.seek(keyframe.offset)?;
+        }
+    }
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path).with_context(|| format!("create {}", path.display()))?),
+        None => Box::new(io::stdout()),
+    });
+    if let Some(e) = asciicast::export(reader, (args.rows, args.cols), from, &mut out)? {
+        eprintln!("warning: {}: {}, exported what precedes it", args.input.display(), e);
+    }
+    out.flush()?;
+    Ok(())
+}
+
+pub fn import(args: ImportArgs) -> anyhow::Result<()> {
+    let data = read_input(&args.input)?;
+    let file = File::create(&args.output).with_context(|| format!("create {}", args.output.display()))?;
+    let mut out = BufWriter::new(file);
+    asciicast::import(&data[..], &mut out).with_context(|| format!("import {}", args.input.display()))?;
+    out.flush()?;
+    Ok(())
+}
+
+// a recording comes out as the plain .cast it was, a log with its cast chunks readable
+pub fn decrypt(args: DecryptArgs) -> anyhow::Result<()> {
+    let secret = x25519_dalek::StaticSecret::from(seal::read_key(&args.key)?);
+    let data = read_raw(&args.input)?;
+    let plain = if is_log(&data) {
+        decrypt_log(&data, &secret)?
+    } else {
+        let decrypted = crypt::decrypt(&data, &secret).with_context(|| format!("decrypt {}", args.input.display()))?;
+        if !decrypted.complete {
+            eprintln!(
+                "warning: {}: ends before its last chunk, decrypted what precedes it",
+                args.input.display()
+            );
+        }
+        decrypted.data
+    };
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(File::create(path).with_context(|| format!("create {}", path.display()))?),
+        None => Box::new(io::stdout()),
+    });
+    out.write_all(&plain)?;
+    out.flush()?;
+    Ok(())
+}
+
+// other lines are copied as they are
+fn decrypt_log(data: &[u8], secret: &x25519_dalek::StaticSecret) -> anyhow::Result<Vec<u8>> {
+    let b64 = base64::engine::general_purpose::STANDARD;
+    let mut out = Vec::with_capacity(data.len());
+    for (n, line) in data.lines().enumerate() {
+        let line = line?;
+        // the syslog sink's lines keep their tag
+        let (tag, json) = line.find(CAST_LINE).map_or(("", &line[..]), |i| line.split_at(i));
+        let chunk = match serde_json::from_str::<(String, (u128, String))>(json) {
+            Ok((kind, (timestamp, chunk))) if kind == "cast" => Some((timestamp, b64.decode(chunk)?)),
+            _ => None,
+        };
+        match chunk {
+            Some((timestamp, sealed)) if crypt::is_encrypted(&sealed) => {
+                let plain = crypt::decrypt(&sealed, secret).with_context(|| format!("decrypt line {}", n + 1))?;
+                let zst = zstd::stream::encode_all(&plain.data[..], 3)?;
+                out.extend_from_slice(tag.as_bytes());
+                serde_json::to_writer(&mut out, &("cast", (timestamp, b64.encode(zst))))?;
+            }
+            _ => out.extend_from_slice(line.as_bytes()),
+        }
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+fn read_input(path: &Path) -> anyhow::Result<Vec<u8>> {
+    Ok(rotate::decompress(read_raw(path)?)?)
+}
+
+fn read_raw(path: &Path) -> anyhow::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    if path == Path::new("-") {
+        io::stdin().read_to_end(&mut data)?;
+    } else {
+        File::open(path)
+            .and_then(|f| BufReader::new(f).read_to_end(&mut data))
+            .with_context(|| format!("read {}", path.display()))?;
+    }
+    Ok(data)
+}
+
+// a server log is json lines, a syslog dump has cast lines among others; a binary recording
+// starts with its timestamp or the magic
+fn is_log(data: &[u8]) -> bool {
+    let json = data
+        .lines()
+        .next()
+        .and_then(Result::ok)
+        .is_some_and(|line| serde_json::from_str::<(String, serde_json::Value)>(&line).is_ok());
+    json || (!data.starts_with(cast::MAGIC)
+        && !crypt::is_encrypted(data)
+        && memchr::memmem::find(data, CAST_LINE.as_bytes()).is_some())
+}
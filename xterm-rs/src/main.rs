@@ -2,38 +2,128 @@
 // dir  := .
 // kid  :=
 use anyhow::Context;
-use axum::{Extension, Router, routing::get};
-use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use axum::{
+    Extension, Router,
+    routing::{get, post},
+};
+use std::{sync::Arc, time::Duration};
+use tokio::signal::unix::{SignalKind, signal};
 use tower_http::services::ServeDir;
 
 mod caster;
+mod collect;
 mod config;
+mod convert;
 mod index;
 mod models;
 mod pty;
+mod replay;
+mod session;
 mod sockets;
+mod term;
+mod verify;
 
 use index::index;
 
-use caster::Caster;
+use caster::{
+    CastOptions,
+    heartbeat::HeartbeatLog,
+    queue::{QueueOptions, QueuePolicy},
+    rotate::{Retention, Rotation, spawn_retention},
+    seal,
+    segment::{Fsync, FsyncMode},
+    ship::{ShipOptions, Shipper},
+    sink::{SinkKind, SinkSpec},
+};
 use config::spawn_cfg_watcher;
-use models::{AppState, logger};
-use pty::PtyManager;
-use sockets::{ws_handler, ws_handler_debug};
+use models::{AppState, HistoryLimit, logger, parse_bytes, read_token};
+use pty::{HistoryOptions, RespawnMode, RespawnPolicy, SessionSpec, parse_env_pair};
+use session::{DEFAULT_SESSION, SessionRegistry, create_session, destroy_session, list_sessions};
+use sockets::{DebugShells, ws_handler, ws_handler_debug, ws_handler_session};
 
-use clap::{Parser, ValueHint};
+use clap::{Parser, Subcommand, ValueHint};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = "TODO")]
+#[command(
+    author,
+    version,
+    about,
+    long_about = "TODO",
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    serve: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Play back a .cast recording in this terminal
+    Replay(replay::ReplayArgs),
+    /// Convert a .cast recording (or a log_level 2 stdout log) to asciicast v2
+    Export(convert::ExportArgs),
+    /// Convert an asciicast v2 file to a .cast recording
+    Import(convert::ImportArgs),
+    /// Decrypt a recording or log_level 2 stdout log made with --encrypt-to
+    Decrypt(convert::DecryptArgs),
+    /// Check the hash chain and checkpoint signatures of .cast recordings
+    Verify(verify::VerifyArgs),
+    /// Create a key for signing cast checkpoints, or for encrypting recordings
+    Keygen(verify::KeygenArgs),
+    /// Receive the recordings servers ship with --collector-url and store them per workspace
+    Collect(collect::CollectArgs),
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
+    #[arg(short, long, long_help = "Command to run in the terminal [default: /bin/bash]")]
+    command: Option<String>,
+
+    #[arg(last = true, long_help = "Arguments passed to the command")]
+    args: Vec<String>,
+
+    #[arg(long, value_hint = ValueHint::DirPath, long_help = "Working directory of the command")]
+    cwd: Option<std::path::PathBuf>,
+
+    #[arg(
+        long = "env",
+        value_name = "KEY=VALUE",
+        value_parser = parse_env_pair,
+        long_help = "Set an environment variable for the command (repeatable)"
+    )]
+    env: Vec<(String, String)>,
+
+    #[arg(
+        long = "env-remove",
+        value_name = "KEY",
+        long_help = "Remove an environment variable for the command (repeatable)"
+    )]
+    env_remove: Vec<String>,
+
+    #[arg(
+        long,
+        long_help = "Start the command as a login shell\nOnly for shells that take -l: bash, zsh, sh, dash, ksh, mksh or fish"
+    )]
+    login: bool,
+
     #[arg(
-        short,
         long,
-        default_value = "/bin/bash",
-        long_help = "Command to run in the terminal"
+        long_help = "Report the commands run at bash prompts to clients and cast files\nbash is started with a generated --rcfile that sources the usual startup files"
     )]
-    command: String,
+    shell_integration: bool,
+
+    #[arg(long, value_enum, long_help = "What to do when the command exits [default: always]")]
+    respawn: Option<RespawnMode>,
+
+    #[arg(
+        long,
+        long_help = "Consecutive respawns before waiting for a client [default: 5]\nOnly used when respawn is backoff"
+    )]
+    respawn_max_attempts: Option<u32>,
 
     #[arg(long, default_value_t = 24u16, long_help = "Terminal initial rows")]
     rows: u16,
@@ -63,64 +153,383 @@ struct Args {
 
     #[arg(
         long,
-        default_value_t = 4194304usize, // 4MB
-        long_help = "Terminal history buffer limit (bytes)"
+        default_value = "4194304", // 4MB
+        long_help = "Terminal history buffer limit\nBytes (4194304, 512k, 4m) or lines (10000l)"
     )]
-    history_limit: usize,
+    history_limit: HistoryLimit,
+
+    #[arg(
+        long,
+        default_value_t = 1000usize,
+        long_help = "Scrollback lines included in the screen snapshot sent to new clients"
+    )]
+    scrollback: usize,
+
+    #[arg(
+        long,
+        default_value_t = 8u8,
+        value_parser = clap::value_parser!(u8).range(1..=64),
+        long_help = "Maximum number of named terminal sessions"
+    )]
+    max_sessions: u8,
+
+    #[arg(
+        long,
+        default_value_t = 4u8,
+        value_parser = clap::value_parser!(u8).range(0..=64),
+        long_help = "Maximum number of concurrent /debug/ws shells (0 disables them)"
+    )]
+    max_debug_shells: u8,
 
     #[arg(
         long,
         default_value_t = 0u8,
         value_parser = clap::value_parser!(u8).range(0..=2),
-        long_help = "Log verbosity level:\n  0 = none\n  1 = cast files\n  2 = cast files & stdout"
+        long_help = "Log verbosity level:\n  0 = none\n  1 = cast files\n  2 = cast files & stdout\nIgnored when --sink is given"
     )]
     log_level: u8,
 
+    #[arg(
+        long,
+        value_name = "SPEC",
+        long_help = "Where recordings go, in place of what log_level picks (repeatable)\nfile, rotating-file, stdout, http or syslog, then options after commas:\n  flush=DURATION  how often the sink is flushed, e.g. 10ms, 5s, 2m\n  path=PATH       the syslog socket [default: /dev/log]\ne.g. --sink rotating-file --sink syslog,flush=5s"
+    )]
+    sink: Vec<SinkSpec>,
+
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 4096u32,
+        value_parser = clap::value_parser!(u32).range(1..),
+        long_help = "Events a session's recording queues hold, before the caster and before each sink"
+    )]
+    cast_queue: u32,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = QueuePolicy::Coalesce,
+        long_help = "What a full cast queue does with the next event:\n  block     wait for room, slowing the recording down to the sinks\n  drop      drop it, and record a gap in the cast\n  coalesce  hold it aside with output merged, up to --cast-buffer, then drop the oldest\nSinks that fall behind miss events under drop and coalesce; the cast records the gap"
+    )]
+    cast_queue_policy: QueuePolicy,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "4m",
+        value_parser = parse_bytes,
+        long_help = "Output held back before it reaches the queue or the sinks, e.g. 4m"
+    )]
+    cast_buffer: u64,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = FsyncMode::None,
+        long_help = "When cast files are synced to disk; they are written out every flush either way:\n  none        left to the kernel\n  interval    every --cast-fsync-interval seconds\n  checkpoint  with every link of the hash chain, about once a second while output comes\nFiles are synced as they are closed unless this is none"
+    )]
+    cast_fsync: FsyncMode,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 5u32,
+        value_parser = clap::value_parser!(u32).range(1..=3600),
+        long_help = "Seconds between syncs of cast files\nOnly used when cast-fsync is interval"
+    )]
+    cast_fsync_interval: u32,
+
     #[arg(
         long,
         default_value_t = 120u32,
         value_parser = clap::value_parser!(u32).range(10..=3600),
-        long_help = "Verbose log interval (s)\nOnly used when log_level is 2"
+        long_help = "Verbose log interval (s)\nThe flush interval of the stdout sink unless it sets one"
     )]
     verbose_interval: u32,
+
+    #[arg(
+        long,
+        default_value_t = 60u32,
+        long_help = "Seconds between screen keyframes in cast files, for seeking (0 disables)\nOnly used when log_level is at least 1"
+    )]
+    keyframe_interval: u32,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "64m",
+        value_parser = parse_bytes,
+        long_help = "Start a new cast segment once one reaches this size, e.g. 64m (0 disables)\nAlso rotates heartbeat.log"
+    )]
+    rotate_size: u64,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 0u32,
+        long_help = "Start a new cast segment once one is this old (0 disables)"
+    )]
+    rotate_interval: u32,
+
+    #[arg(long, long_help = "Compress finished cast segments with zstd")]
+    compress_rotated: bool,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "0",
+        value_parser = parse_bytes,
+        long_help = "Remove the oldest logs once log_dir holds more than this, e.g. 1g (0 disables)\nOff by default: nothing is removed unless one of the --retain options is set"
+    )]
+    retain_size: u64,
+
+    #[arg(
+        long,
+        value_name = "DAYS",
+        default_value_t = 0u32,
+        long_help = "Remove logs older than this many days (0 disables)"
+    )]
+    retain_days: u32,
+
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 0usize,
+        long_help = "Keep at most this many recordings, segments counted apart (0 disables)"
+    )]
+    retain_files: usize,
+
+    #[arg(
+        long,
+        value_hint = ValueHint::FilePath,
+        long_help = "Private key from `xterm-rs keygen` to sign cast checkpoints with\nKeep it outside anything the recorded user can write"
+    )]
+    signing_key: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 60u32,
+        value_parser = clap::value_parser!(u32).range(1..),
+        long_help = "Seconds between signed checkpoints in cast files\nFiles are also signed as they are closed"
+    )]
+    checkpoint_interval: u32,
+
+    #[arg(
+        long,
+        value_name = "KEY",
+        long_help = "X25519 public key, as hex or a file holding it, to encrypt cast files and stdout chunks to\nCreate one with `xterm-rs keygen --encryption`; read them back with `xterm-rs decrypt`"
+    )]
+    encrypt_to: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "URL",
+        long_help = "Also POST cast files, in sequence-numbered chunks, to this collector\nChunks are spooled to disk until it acknowledges them with a 2xx\nAdds an http sink when log_level is at least 1, or takes the chunks of --sink http"
+    )]
+    collector_url: Option<reqwest::Url>,
+
+    #[arg(
+        long,
+        value_name = "ID",
+        long_help = "Identifies this workspace to the collector [default: hostname]"
+    )]
+    workspace_id: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "TOKEN",
+        long_help = "Bearer token for the collector, as text or @file to read it from"
+    )]
+    collector_token: Option<String>,
+
+    #[arg(
+        long,
+        value_hint = ValueHint::DirPath,
+        long_help = "Where chunks wait for the collector [default: <log_dir>/spool]"
+    )]
+    spool_dir: Option<std::path::PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SIZE",
+        default_value = "256m",
+        value_parser = parse_bytes,
+        long_help = "Drop the oldest spooled chunks past this, e.g. 256m (0 disables)"
+    )]
+    spool_size: u64,
+
+    #[arg(
+        long,
+        value_name = "SECS",
+        default_value_t = 5u32,
+        value_parser = clap::value_parser!(u32).range(1..=3600),
+        long_help = "Seconds between chunks sent to the collector\nThe flush interval of the http sink unless it sets one"
+    )]
+    ship_interval: u32,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
-    let pty = Arc::new(PtyManager::new(args.rows, args.cols, args.history_limit).await?);
-
-    let ts_millis = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("time went backwards")
-        .as_millis();
-    let start = std::time::Instant::now();
-
-    let caster = match args.log_level {
-        0 => None,
-        x => Some(Caster::new(
-            args.log_dir,
-            start,
-            ts_millis,
-            x == 2,
-            args.verbose_interval,
-            (args.rows, args.cols),
-        )?),
+    let args = match Cli::parse() {
+        Cli {
+            command: Some(command), ..
+        } => {
+            return match command {
+                Command::Replay(args) => replay::run(args),
+                Command::Export(args) => convert::export(args),
+                Command::Import(args) => convert::import(args),
+                Command::Decrypt(args) => convert::decrypt(args),
+                Command::Verify(args) => verify::verify(args),
+                Command::Keygen(args) => verify::keygen(args),
+                Command::Collect(args) => collect::run(args).await,
+            };
+        }
+        Cli { serve: Some(serve), .. } => serve,
+        Cli { serve: None, .. } => unreachable!("clap requires --resource without a subcommand"),
     };
 
     let (cfg_watcher, _join) = spawn_cfg_watcher(args.config_path).await?;
 
+    let cfg_respawn = cfg_watcher.current().session.respawn();
+    let respawn = match (args.respawn, args.respawn_max_attempts) {
+        (None, None) => None,
+        (mode, max_attempts) => Some(RespawnPolicy {
+            mode: mode.unwrap_or(cfg_respawn.mode),
+            max_attempts: max_attempts.unwrap_or(cfg_respawn.max_attempts),
+            ..cfg_respawn
+        }),
+    };
+    let cli_session = SessionSpec {
+        command: args.command,
+        args: args.args,
+        cwd: args.cwd,
+        env: args.env.into_iter().collect(),
+        env_remove: args.env_remove,
+        login: args.login,
+        shell_integration: args.shell_integration,
+        respawn,
+    };
+    let session = Arc::new(cfg_watcher.current().session.merge(cli_session));
+    session.check().map_err(anyhow::Error::msg)?;
+
+    let signing_key = match &args.signing_key {
+        Some(path) => Some(Arc::new(seal::load_signing_key(path)?)),
+        None => None,
+    };
+    let encrypt_to = match &args.encrypt_to {
+        Some(key) => Some(x25519_dalek::PublicKey::from(seal::read_key(key)?)),
+        None => None,
+    };
+    let mut sinks = args.sink;
+    if sinks.is_empty() {
+        if args.log_level > 0 {
+            sinks.push(SinkSpec::new(SinkKind::RotatingFile));
+        }
+        if args.log_level == 2 {
+            sinks.push(SinkSpec::new(SinkKind::Stdout));
+        }
+        if args.log_level > 0 && args.collector_url.is_some() {
+            sinks.push(SinkSpec::new(SinkKind::Http));
+        }
+    }
+    for spec in &mut sinks {
+        let interval = match spec.kind {
+            SinkKind::Stdout => args.verbose_interval,
+            SinkKind::Http => args.ship_interval,
+            _ => continue,
+        };
+        spec.flush.get_or_insert(Duration::from_secs(interval.into()));
+    }
+    let shipping = sinks.iter().any(|spec| spec.kind == SinkKind::Http);
+    if shipping && args.collector_url.is_none() {
+        anyhow::bail!("--sink http needs --collector-url");
+    }
+
+    let shipper = match &args.collector_url {
+        Some(url) if shipping => {
+            if !matches!(url.scheme(), "http" | "https") {
+                anyhow::bail!("--collector-url must be http or https, got {}", url);
+            }
+            let workspace = match args.workspace_id {
+                Some(id) => id,
+                None => nix::unistd::gethostname()?.to_string_lossy().into_owned(),
+            };
+            Some(Shipper::spawn(ShipOptions {
+                url: url.clone(),
+                workspace,
+                token: args.collector_token.as_deref().map(read_token).transpose()?,
+                spool_dir: args.spool_dir.unwrap_or_else(|| args.log_dir.join("spool")),
+                spool_limit: (args.spool_size > 0).then_some(args.spool_size),
+            })?)
+        }
+        _ => None,
+    };
+    let secs = |n: u32| (n > 0).then(|| Duration::from_secs(n.into()));
+    let writes_files = sinks.iter().any(SinkSpec::writes_files);
+    let max_bytes = (args.rotate_size > 0).then_some(args.rotate_size);
+    let heartbeat = Arc::new(HeartbeatLog::new(&args.log_dir, max_bytes, signing_key.clone()));
+    let cast = match sinks.is_empty() {
+        true => None,
+        false => Some(CastOptions {
+            log_dir: args.log_dir,
+            keyframe_interval: secs(args.keyframe_interval),
+            rotation: Rotation {
+                max_bytes,
+                max_age: secs(args.rotate_interval),
+                compress: args.compress_rotated,
+            },
+            active: Arc::default(),
+            signing_key,
+            checkpoint_interval: Duration::from_secs(args.checkpoint_interval.into()),
+            encrypt_to,
+            shipper: shipper.clone(),
+            sinks,
+            queue: QueueOptions {
+                policy: args.cast_queue_policy,
+                size: args.cast_queue as usize,
+                max_buffer: args.cast_buffer.max(1) as usize,
+            },
+            fsync: Fsync::new(args.cast_fsync, Duration::from_secs(args.cast_fsync_interval.into())),
+            heartbeat,
+        }),
+    };
+    let retention = Retention {
+        max_bytes: (args.retain_size > 0).then_some(args.retain_size),
+        max_age: (args.retain_days > 0).then(|| Duration::from_secs(u64::from(args.retain_days) * 86400)),
+        max_files: (args.retain_files > 0).then_some(args.retain_files),
+    };
+    if let Some(opts) = &cast
+        && writes_files
+        && retention.enabled()
+    {
+        spawn_retention(opts.log_dir.clone(), retention, Arc::clone(&opts.active));
+    }
+
+    let sessions = Arc::new(SessionRegistry::new(
+        session,
+        HistoryOptions {
+            limit: args.history_limit,
+            scrollback: args.scrollback,
+        },
+        args.max_sessions.into(),
+        cast,
+        (args.rows, args.cols),
+    ));
+    sessions.create(DEFAULT_SESSION).await?;
+
     let state = Arc::new(AppState {
-        start,
-        pty: Arc::clone(&pty),
-        caster,
+        sessions: Arc::clone(&sessions),
         watcher: cfg_watcher,
-        stty_size: Arc::new(tokio::sync::RwLock::new((args.rows, args.cols))),
+        debug: DebugShells::new(args.max_debug_shells.into()),
     });
 
     let app = Router::new()
         .nest_service("/static", ServeDir::new(args.resource))
         .route("/ws", get(ws_handler))
+        .route("/ws/{session}", get(ws_handler_session))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{session}", post(create_session).delete(destroy_session))
         .route("/", get(index))
         .route("/debug", get(index))
         .route("/debug/ws", get(ws_handler_debug))
@@ -130,5 +539,18 @@ async fn main() -> anyhow::Result<()> {
 
     logger("info", format!("Listening on http://{}", listener.local_addr()?));
 
-    axum::serve(listener, app).await.context("server error")
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let name = tokio::select! {
+        served = axum::serve(listener, app) => return served.context("server error"),
+        _ = terminate.recv() => "SIGTERM",
+        _ = interrupt.recv() => "SIGINT",
+    };
+    // the sinks write what they hold and close their files behind a last checkpoint
+    logger("info", format!("Received {}, closing recordings", name));
+    sessions.close().await;
+    if let Some(shipper) = shipper {
+        shipper.settle().await;
+    }
+    Ok(())
 }
@@ -1,17 +1,18 @@
-use crate::caster::Caster;
 use crate::config::ConfigWatcher;
-use crate::pty::PtyManager;
+use crate::pty::{PtySignal, SessionSpec};
+use crate::session::{SessionError, SessionRegistry};
+use crate::sockets::DebugShells;
 use axum::{
     http::StatusCode,
     response::{IntoResponse, Response},
 };
-use memchr::memrchr;
 use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
 use std::io::Write;
-use std::{sync::Arc, time::Instant};
-use tokio::sync::RwLock;
-use unicode_width::UnicodeWidthChar;
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 // app config
 fn default_layout() -> String {
@@ -26,6 +27,18 @@ pub struct AppConfig {
     pub layout: String,
     #[serde(default = "default_theme")]
     pub theme: String,
+    #[serde(default, skip_serializing)]
+    pub session: SessionSpec,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            layout: default_layout(),
+            theme: default_theme(),
+            session: SessionSpec::default(),
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -34,6 +47,8 @@ pub enum ClientMsg {
     Data { value: String },
     Resize { value: SttySize },
     Heartbeat,
+    Respawn,
+    Signal { value: PtySignal },
 }
 
 #[derive(Deserialize, Debug)]
@@ -43,71 +58,46 @@ pub struct SttySize {
 }
 
 pub struct AppState {
-    pub start: Instant,
-    pub pty: Arc<PtyManager>,
-    pub caster: Option<Arc<Caster>>,
+    pub sessions: Arc<SessionRegistry>,
     pub watcher: ConfigWatcher,
-    pub stty_size: Arc<RwLock<(u16, u16)>>,
+    pub debug: DebugShells,
 }
 
 #[derive(Debug, thiserror::Error)]
 pub enum AppError {
     #[error("bad request: {0}")]
     BadRequest(#[from] anyhow::Error),
+    #[error("{0}")]
+    Session(#[from] SessionError),
+    #[error("too many debug shells (max {0})")]
+    DebugLimit(usize),
 }
 
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
         match &self {
             AppError::BadRequest(_) => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+            AppError::Session(e) => {
+                let code = match e {
+                    SessionError::NotFound(_) => StatusCode::NOT_FOUND,
+                    SessionError::Exists(_) => StatusCode::CONFLICT,
+                    SessionError::Protected(_) => StatusCode::FORBIDDEN,
+                    SessionError::Limit(_) => StatusCode::TOO_MANY_REQUESTS,
+                    SessionError::InvalidName(_) => StatusCode::BAD_REQUEST,
+                    SessionError::Spawn(_) => StatusCode::INTERNAL_SERVER_ERROR,
+                };
+                (code, self.to_string()).into_response()
+            }
+            AppError::DebugLimit(_) => (StatusCode::TOO_MANY_REQUESTS, self.to_string()).into_response(),
         }
     }
 }
 
-pub fn buf_trim(buf: &[u8], cols: u16, max_lines: u32) -> usize {
-    let mut lines = 0;
-    let mut col = 0;
-    let mut i = buf.len();
-
-    while i > 0 && lines < max_lines {
-        i -= 1;
-        match buf[i] {
-            b'\n' => {
-                lines += 1;
-                col = 0;
-            }
-            0x20..=0x7e => {
-                col += 1;
-                if col == cols {
-                    lines += 1;
-                    col = 0;
-                }
-            }
-            0x80..=0xff => {
-                let mut start = i;
-                while start > 0 && (buf[start] & 0b1100_0000) == 0b1000_0000 {
-                    start -= 1;
-                }
-                let ch = std::str::from_utf8(&buf[start..=i])
-                    .ok()
-                    .and_then(|s| s.chars().next())
-                    .unwrap_or(' ');
-                col += UnicodeWidthChar::width(ch).unwrap_or(1) as u16;
-                if col >= cols {
-                    lines += 1;
-                    col = if col == cols { 0 } else { col - cols };
-                }
-                i = start;
-            }
-            0x1b => {
-                if let Some(pos) = memrchr(b'm', &buf[..=i]) {
-                    i = pos.saturating_sub(1);
-                }
-            }
-            _ => {}
-        }
-    }
-    i
+pub fn unix_millis() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis()
 }
 
 pub fn logger<P>(kind: &str, payload: P)
@@ -121,41 +111,269 @@ where
     stdout.flush().ok();
 }
 
-// loop queue
+// how much raw output a session keeps, e.g. `4194304`, `512k`, `4m` or `10000l`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryLimit {
+    Bytes(usize),
+    Lines(usize),
+}
+
+impl std::str::FromStr for HistoryLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let (num, unit) = s.split_at(split);
+        match unit.trim() {
+            "l" | "lines" => match num.parse() {
+                Ok(n) if n > 0 => Ok(Self::Lines(n)),
+                _ => Err(format!("invalid history limit '{s}'")),
+            },
+            _ => parse_bytes(&s)
+                .map(|n| Self::Bytes(n as usize))
+                .map_err(|_| format!("invalid history limit '{s}', expected bytes (k/m) or lines (l)")),
+        }
+    }
+}
+
+// a byte count with an optional unit, e.g. `4194304`, `512k`, `64m` or `1g`
+pub fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim().to_ascii_lowercase();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(split);
+    let n: u64 = num.parse().map_err(|_| format!("invalid size '{s}'"))?;
+    let shift = match unit.trim() {
+        "" | "b" => 0,
+        "k" | "kb" => 10,
+        "m" | "mb" => 20,
+        "g" | "gb" => 30,
+        _ => return Err(format!("invalid size '{s}', expected bytes with an optional k/m/g")),
+    };
+    n.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
+
+// a secret given as text, or as `@path` to a file holding it so it stays out of `ps`
+pub fn read_token(s: &str) -> anyhow::Result<String> {
+    let Some(path) = s.strip_prefix('@') else {
+        return Ok(s.to_owned());
+    };
+    let token = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("read {}: {}", path, e))?
+        .trim()
+        .to_owned();
+    anyhow::ensure!(!token.is_empty(), "{} is empty", path);
+    Ok(token)
+}
+
+// hard cap for lines mode, and for a bytes limit never reaching a safe cut
+const HISTORY_HARD_CAP: usize = 64 << 20;
+
+// tracks escape sequences so the ring only trims where a new reader can start
+#[derive(Debug, Clone, Copy, Default)]
+enum Scan {
+    #[default]
+    Ground,
+    Esc,
+    Csi,
+    // osc, dcs, apc, pm, sos: until bel or st
+    Str,
+    StrEsc,
+}
+
+impl Scan {
+    // true when `b` is a newline outside any sequence
+    fn step(&mut self, b: u8) -> bool {
+        *self = match (*self, b) {
+            (Scan::Ground, b'\n') => return true,
+            (Scan::Ground, 0x1b) => Scan::Esc,
+            (Scan::Ground, _) => Scan::Ground,
+            (Scan::Esc, b'[') => Scan::Csi,
+            (Scan::Esc, b']' | b'P' | b'_' | b'^' | b'X') => Scan::Str,
+            (Scan::Esc, 0x20..=0x2f) => Scan::Esc,
+            (Scan::Csi, 0x1b) => Scan::Esc,
+            (Scan::Csi, 0x40..=0x7e | 0x18 | 0x1a) => Scan::Ground,
+            (Scan::Csi, _) => Scan::Csi,
+            (Scan::Str, 0x07) => Scan::Ground,
+            (Scan::Str, 0x1b) => Scan::StrEsc,
+            (Scan::Str, _) => Scan::Str,
+            (Scan::StrEsc, b'\\') => Scan::Ground,
+            (Scan::StrEsc, _) => {
+                *self = Scan::Esc;
+                return self.step(b);
+            }
+            (Scan::Esc, _) => Scan::Ground,
+        };
+        false
+    }
+}
+
+// loop queue; `total` counts every byte ever appended, so positions in the
+// stream (offsets) stay valid after the front has been dropped.
+// the front is only dropped right after a newline outside an escape
+// sequence, so whatever is left starts cleanly for a new reader
 #[derive(Clone)]
 pub struct RingBytes {
     buf: VecDeque<u8>,
-    limit: usize,
+    limit: HistoryLimit,
+    total: u64,
+    scan: Scan,
+    // offsets just past each safe newline still in `buf`
+    lines: VecDeque<u64>,
 }
 
 impl RingBytes {
-    pub fn new(limit: usize) -> Self {
+    pub fn new(limit: HistoryLimit) -> Self {
         Self {
-            buf: VecDeque::with_capacity(limit),
+            buf: VecDeque::new(),
             limit,
+            total: 0,
+            scan: Scan::default(),
+            lines: VecDeque::new(),
         }
     }
 
     pub fn extend(&mut self, chunk: &[u8]) {
-        match chunk.len().checked_sub(self.limit) {
-            Some(x) => {
-                self.buf.clear();
-                self.buf.extend(&chunk[x..]);
+        for (i, b) in chunk.iter().enumerate() {
+            if self.scan.step(*b) {
+                self.lines.push_back(self.total + i as u64 + 1);
             }
-            None => {
-                if let Some(x) = (self.buf.len() + chunk.len()).checked_sub(self.limit) {
-                    self.buf.drain(..x);
-                }
-                self.buf.extend(chunk);
+        }
+        self.buf.extend(chunk);
+        self.total += chunk.len() as u64;
+        self.trim();
+    }
+
+    fn trim(&mut self) {
+        let start = self.start_offset();
+        let (cut, cap) = match self.limit {
+            HistoryLimit::Bytes(n) => {
+                let excess = self.buf.len().saturating_sub(n) as u64;
+                let cut = self.lines.iter().find(|&&l| l >= start + excess).copied();
+                (cut.filter(|_| excess > 0), n.saturating_mul(2).min(HISTORY_HARD_CAP))
             }
+            HistoryLimit::Lines(n) => (self.lines.len().checked_sub(n).map(|i| self.lines[i]), HISTORY_HARD_CAP),
+        };
+        if let Some(cut) = cut {
+            self.drop_until(cut);
+        }
+        // no safe cut in reach: keep memory bounded and at least split on a char
+        if let Some(excess) = self.buf.len().checked_sub(cap).filter(|x| *x > 0) {
+            let skip = self
+                .buf
+                .range(excess..)
+                .take_while(|b| (*b & 0xc0) == 0x80)
+                .take(3)
+                .count();
+            self.drop_until(self.start_offset() + (excess + skip) as u64);
+        }
+    }
+
+    fn drop_until(&mut self, offset: u64) {
+        let n = (offset - self.start_offset()) as usize;
+        self.buf.drain(..n.min(self.buf.len()));
+        while self.lines.front().is_some_and(|&l| l <= offset) {
+            self.lines.pop_front();
+        }
+    }
+
+    pub fn start_offset(&self) -> u64 {
+        self.total - self.buf.len() as u64
+    }
+
+    pub fn end_offset(&self) -> u64 {
+        self.total
+    }
+
+    // bytes from `offset` to the end, if the ring still holds them
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start_offset() || offset > self.total {
+            return None;
+        }
+        let skip = (offset - self.start_offset()) as usize;
+        Some(self.buf.range(skip..).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(limit: HistoryLimit, chunks: &[&[u8]]) -> RingBytes {
+        let mut ring = RingBytes::new(limit);
+        for chunk in chunks {
+            ring.extend(chunk);
         }
+        ring
+    }
+
+    fn held(ring: &RingBytes) -> Vec<u8> {
+        ring.since(ring.start_offset()).unwrap()
+    }
+
+    #[test]
+    fn history_limit_parses_bytes_and_lines() {
+        assert_eq!("4194304".parse(), Ok(HistoryLimit::Bytes(4194304)));
+        assert_eq!("512k".parse(), Ok(HistoryLimit::Bytes(512 << 10)));
+        assert_eq!(" 4M ".parse(), Ok(HistoryLimit::Bytes(4 << 20)));
+        assert_eq!("10000l".parse(), Ok(HistoryLimit::Lines(10000)));
+        assert_eq!("10 lines".parse(), Ok(HistoryLimit::Lines(10)));
+        for bad in ["0l", "l", "", "4x", "k"] {
+            assert!(bad.parse::<HistoryLimit>().is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn parse_bytes_rejects_overflow() {
+        assert_eq!(parse_bytes("1g"), Ok(1 << 30));
+        assert!(parse_bytes(&format!("{}g", u64::MAX >> 20)).is_err());
+    }
+
+    #[test]
+    fn bytes_limit_trims_after_a_newline() {
+        let ring = filled(HistoryLimit::Bytes(10), &[b"aaaa\nbb", b"bb\ncccc\n"]);
+        assert_eq!(held(&ring), b"bbbb\ncccc\n");
+        assert_eq!((ring.start_offset(), ring.end_offset()), (5, 15));
+    }
+
+    #[test]
+    fn bytes_limit_keeps_more_until_a_safe_cut() {
+        // the only newline is inside an osc title, so nothing can go yet
+        let ring = filled(HistoryLimit::Bytes(8), &[b"\x1b]0;a\nb\x07xy"]);
+        assert_eq!(ring.start_offset(), 0);
+        assert_eq!(held(&ring), b"\x1b]0;a\nb\x07xy");
+
+        // a csi split across chunks still ends before the newline
+        let ring = filled(HistoryLimit::Bytes(2), &[b"\x1b[1;", b"2mx\n", b"z\n"]);
+        assert_eq!(held(&ring), b"z\n");
+    }
+
+    #[test]
+    fn lines_limit_counts_the_line_being_written() {
+        let ring = filled(HistoryLimit::Lines(2), &[b"a\nb\nc\nd"]);
+        assert_eq!(held(&ring), b"c\nd");
+        assert_eq!(ring.start_offset(), 4);
+
+        let ring = filled(HistoryLimit::Lines(5), &[b"a\nb\n"]);
+        assert_eq!(held(&ring), b"a\nb\n");
+    }
+
+    #[test]
+    fn hard_cap_does_not_split_a_char() {
+        // no newline at all: cut at twice the limit, past any continuation bytes
+        let ring = filled(HistoryLimit::Bytes(2), &["a", "é", "éa"].map(str::as_bytes));
+        assert_eq!(held(&ring), "éa".as_bytes());
+        assert_eq!(ring.start_offset(), 3);
     }
 
-    pub fn to_vec(&self) -> Vec<u8> {
-        let (a, b) = self.buf.as_slices();
-        let mut v = Vec::with_capacity(self.buf.len());
-        v.extend_from_slice(a);
-        v.extend_from_slice(b);
-        v
+    #[test]
+    fn since_only_answers_for_held_offsets() {
+        let ring = filled(HistoryLimit::Bytes(4), &[b"ab\ncd\n"]);
+        assert_eq!(ring.start_offset(), 3);
+        assert_eq!(ring.since(4).as_deref(), Some(&b"d\n"[..]));
+        assert_eq!(ring.since(6).as_deref(), Some(&b""[..]));
+        assert_eq!(ring.since(2), None);
+        assert_eq!(ring.since(7), None);
     }
 }
@@ -1,2 +1,5 @@
 pub mod common;
-pub use common::{AppError, AppState, AppConfig, ClientMsg, buf_trim, logger, RingBytes};
+pub use common::{
+    AppConfig, AppError, AppState, ClientMsg, HistoryLimit, RingBytes, logger, parse_bytes, read_token,
+    unix_millis,
+};
@@ -0,0 +1,70 @@
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RespawnAction {
+    Immediate,
+    Delayed,
+    OnDemand,
+    None,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExitInfo {
+    pub code: u32,
+    pub signal: Option<String>,
+    pub respawn: RespawnAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delay_ms: Option<u64>,
+}
+
+impl ExitInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "event": "exit", "value": self }).to_string()
+    }
+}
+
+// a command run at a shell prompt, from the OSC 133 marks of shell integration
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CommandInfo {
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cwd: Option<String>,
+    // unix millis
+    pub started_at: u128,
+    pub ended_at: u128,
+    pub exit_code: Option<i32>,
+}
+
+impl CommandInfo {
+    pub fn to_json(&self) -> String {
+        serde_json::json!({ "event": "command", "value": self }).to_string()
+    }
+}
+
+// everything a pty broadcasts to its subscribers
+#[derive(Debug, Clone)]
+pub enum PtyEvent {
+    // `offset` is the stream position of the first byte of `data`
+    Output { offset: u64, data: Bytes },
+    Exit(ExitInfo),
+    Command(CommandInfo),
+}
+
+// a position in one pty's output stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub epoch: u64,
+    pub offset: u64,
+}
+
+// what a subscriber is sent before live output: either the raw bytes since
+// its position or, for a fresh or reset client, a snapshot of the screen.
+// either way it leaves the client at stream position `offset`
+#[derive(Debug)]
+pub struct Replay {
+    pub offset: u64,
+    pub bytes: Vec<u8>,
+    pub reset: bool,
+}
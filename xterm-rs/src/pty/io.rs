@@ -0,0 +1,58 @@
+use anyhow::{Context, Result, anyhow};
+use nix::{
+    errno::Errno,
+    fcntl::{FcntlArg, OFlag, fcntl},
+    unistd,
+};
+use portable_pty::MasterPty;
+use std::{
+    io,
+    os::fd::{AsRawFd, BorrowedFd, OwnedFd},
+};
+use tokio::io::unix::AsyncFd;
+
+// non-blocking handle on a pty master, driven by the tokio reactor
+pub struct PtyFd {
+    fd: AsyncFd<OwnedFd>,
+}
+
+impl PtyFd {
+    pub fn new(master: &dyn MasterPty) -> Result<Self> {
+        let raw = master.as_raw_fd().ok_or_else(|| anyhow!("pty master has no fd"))?;
+        // safety: `master` owns `raw` and outlives this call
+        let fd = unsafe { BorrowedFd::borrow_raw(raw) }
+            .try_clone_to_owned()
+            .context("dup pty master")?;
+        let flags = OFlag::from_bits_truncate(fcntl(fd.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(fd.as_raw_fd(), FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        Ok(Self {
+            fd: AsyncFd::new(fd).context("register pty master")?,
+        })
+    }
+
+    // 0 once the child side is gone (linux reports that as EIO)
+    pub async fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let mut guard = self.fd.readable().await?;
+            match guard.try_io(|fd| unistd::read(fd.as_raw_fd(), buf).map_err(io::Error::from)) {
+                Ok(Err(e)) if e.raw_os_error() == Some(Errno::EIO as i32) => return Ok(0),
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(res) => return res,
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub async fn write_all(&self, mut buf: &[u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            let mut guard = self.fd.writable().await?;
+            match guard.try_io(|fd| unistd::write(fd, buf).map_err(io::Error::from)) {
+                Ok(Ok(n)) => buf = &buf[n..],
+                Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(Err(e)) => return Err(e),
+                Err(_would_block) => continue,
+            }
+        }
+        Ok(())
+    }
+}
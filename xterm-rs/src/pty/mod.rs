@@ -1,2 +1,10 @@
+mod event;
+mod io;
 mod pty_manager;
-pub use pty_manager::PtyManager;
+mod shell;
+mod signal;
+mod spec;
+pub use event::{CommandInfo, ExitInfo, Position, PtyEvent, Replay, RespawnAction};
+pub use pty_manager::{HistoryOptions, PtyManager};
+pub use signal::PtySignal;
+pub use spec::{RespawnMode, RespawnPolicy, SessionSpec, parse_env_pair};
@@ -1,140 +1,439 @@
-use crate::models::RingBytes;
-use anyhow::{Context, Result};
+use crate::models::{HistoryLimit, RingBytes, unix_millis};
+use crate::pty::io::PtyFd;
+use crate::pty::shell::{self, CommandMarks};
+use crate::pty::{ExitInfo, Position, PtyEvent, PtySignal, Replay, RespawnAction, RespawnMode, SessionSpec};
+use crate::term::Emulator;
+use anyhow::{Context, Result, anyhow};
+use bytes::Bytes;
+use nix::{
+    sys::{
+        signal::{Signal, killpg},
+        termios::LocalFlags,
+    },
+    unistd::Pid,
+};
 use portable_pty::*;
 use std::{
-    io::{Read, Write},
-    sync::Arc,
+    io,
+    sync::{
+        Arc, LazyLock,
+        atomic::{AtomicBool, AtomicI32, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
 };
 use tokio::{
-    sync::{Mutex, broadcast},
-    task,
+    select,
+    sync::{Mutex, Notify, broadcast, mpsc, watch},
+    task, time,
 };
 
 const BUF_SIZE: usize = 4096;
+// input is queued in chunks of at most this size...
+const WRITE_CHUNK: usize = 4096;
+// ...and at most this many; a larger paste waits for the child to read
+const WRITE_QUEUE: usize = 256;
+// a child that lived this long resets the backoff attempt counter
+const BACKOFF_RESET: Duration = Duration::from_secs(60);
+// counts up from a random start, so no two ptys of this process or an earlier one share an epoch;
+// kept to 52 bits, which the browser reads back as a number without rounding
+static NEXT_EPOCH: LazyLock<AtomicU64> =
+    LazyLock::new(|| AtomicU64::new(getrandom::u64().unwrap_or_else(|_| unix_millis() as u64) >> 12));
+
+#[derive(Debug, Clone, Copy)]
+pub struct HistoryOptions {
+    // raw output kept for resuming clients
+    pub limit: HistoryLimit,
+    // lines kept above the screen in snapshots
+    pub scrollback: usize,
+}
+
+// raw output for deltas and the screen it produces for snapshots
+struct History {
+    ring: RingBytes,
+    screen: Emulator,
+}
+
+// the current child's pty; swapped on respawn
+type FdSlot = std::sync::Mutex<Arc<PtyFd>>;
+
+// state shared between the manager and its reader task
+struct Shared {
+    spec: Arc<SessionSpec>,
+    tx: broadcast::Sender<PtyEvent>,
+    history: Mutex<History>,
+    // the current child's OSC 133 state, fed from `emit`
+    marks: std::sync::Mutex<CommandMarks>,
+    fd: Arc<FdSlot>,
+    input: mpsc::Sender<Bytes>,
+    master: Mutex<Box<dyn MasterPty + Send>>,
+    size: Mutex<PtySize>,
+    killer: Mutex<Box<dyn ChildKiller + Send + Sync>>,
+    // the child leads its own session, so this is also its process group
+    pid: AtomicI32,
+    closing: AtomicBool,
+    // stops the reader even if a descendant still holds the pty open
+    stop: Notify,
+    // the child is gone and the reader waits for a client to ask for a new one
+    waiting: AtomicBool,
+    // wakes the reader while it waits to respawn
+    respawn_tx: mpsc::UnboundedSender<()>,
+}
+
+impl Shared {
+    // send under the history lock so a snapshot never disagrees with the stream
+    async fn emit(&self, bytes: &[u8]) {
+        let mut history = self.history.lock().await;
+        let offset = history.ring.end_offset();
+        history.ring.extend(bytes);
+        history.screen.advance(bytes);
+        let _ = self.tx.send(PtyEvent::Output {
+            offset,
+            data: Bytes::copy_from_slice(bytes),
+        });
+        for command in self.marks.lock().unwrap().feed(bytes) {
+            let _ = self.tx.send(PtyEvent::Command(command));
+        }
+    }
+}
+
+struct Spawned {
+    fd: PtyFd,
+    master: Box<dyn MasterPty + Send>,
+    child: Box<dyn Child + Send + Sync>,
+}
 
 pub struct PtyManager {
-    tx: broadcast::Sender<Vec<u8>>,
-    history: Arc<Mutex<RingBytes>>,
-    writer: Arc<Mutex<Box<dyn Write + Send>>>,
-    master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
-    size: Arc<Mutex<PtySize>>,
+    shared: Arc<Shared>,
+    closed: watch::Receiver<bool>,
+    epoch: u64,
 }
 
 impl PtyManager {
-    pub async fn new(rows: u16, cols: u16, history_limit: usize) -> Result<Self> {
-        let (tx, _) = broadcast::channel::<Vec<u8>>(4096);
-        let history = Arc::new(Mutex::new(RingBytes::new(history_limit)));
-        let size = Arc::new(Mutex::new(PtySize {
+    pub async fn new(spec: Arc<SessionSpec>, rows: u16, cols: u16, history: HistoryOptions) -> Result<Self> {
+        let (tx, _) = broadcast::channel::<PtyEvent>(4096);
+        let size = PtySize {
             rows,
             cols,
             pixel_width: 0,
             pixel_height: 0,
-        }));
+        };
 
-        let (writer, master, _child) = Self::spawn_shell(&size).await?;
-        let writer = Arc::new(Mutex::new(writer));
-        let master = Arc::new(Mutex::new(master));
+        let Spawned { fd, master, child } = Self::spawn_shell(&spec, size)?;
+        let (respawn_tx, respawn_rx) = mpsc::unbounded_channel();
+        let (input, input_rx) = mpsc::channel(WRITE_QUEUE);
+        let fd = Arc::new(std::sync::Mutex::new(Arc::new(fd)));
+        let shared = Arc::new(Shared {
+            spec,
+            tx,
+            history: Mutex::new(History {
+                ring: RingBytes::new(history.limit),
+                screen: Emulator::new(rows, cols, history.scrollback),
+            }),
+            marks: std::sync::Mutex::default(),
+            fd: Arc::clone(&fd),
+            input,
+            master: Mutex::new(master),
+            size: Mutex::new(size),
+            killer: Mutex::new(child.clone_killer()),
+            pid: AtomicI32::new(child_pid(&*child)),
+            closing: AtomicBool::new(false),
+            stop: Notify::new(),
+            waiting: AtomicBool::new(false),
+            respawn_tx,
+        });
+        let (closed_tx, closed) = watch::channel(false);
 
-        Self::launch_reader(
-            tx.clone(),
-            Arc::clone(&history),
-            Arc::clone(&writer),
-            Arc::clone(&master),
-            Arc::clone(&size),
-        );
+        Self::launch_writer(fd, input_rx);
+        Self::launch_reader(Arc::clone(&shared), child, respawn_rx, closed_tx);
 
         Ok(Self {
-            tx,
-            history,
-            writer,
-            master,
-            size,
+            shared,
+            closed,
+            epoch: NEXT_EPOCH.fetch_add(1, Ordering::Relaxed),
         })
     }
 
-    pub async fn subscribe(&self) -> (broadcast::Receiver<Vec<u8>>, RingBytes) {
-        (self.tx.subscribe(), self.history.lock().await.clone())
+    // subscribing under the history lock means every chunk is either in the
+    // replay or on the receiver, never both and never neither
+    pub async fn subscribe(&self, from: Option<Position>) -> (broadcast::Receiver<PtyEvent>, Replay) {
+        let history = self.shared.history.lock().await;
+        (self.shared.tx.subscribe(), self.replay_from(&history, from))
+    }
+
+    pub async fn replay(&self, from: Option<Position>) -> Replay {
+        self.replay_from(&*self.shared.history.lock().await, from)
+    }
+
+    // identifies this pty's stream; offsets from another epoch are meaningless
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    fn replay_from(&self, history: &History, from: Option<Position>) -> Replay {
+        let delta = from
+            .filter(|p| p.epoch == self.epoch)
+            .and_then(|p| history.ring.since(p.offset));
+        Replay {
+            offset: history.ring.end_offset(),
+            reset: from.is_some() && delta.is_none(),
+            bytes: delta.unwrap_or_else(|| history.screen.snapshot()),
+        }
     }
 
     pub async fn write(&self, bytes: &[u8]) -> Result<()> {
-        let mut writer = self.writer.lock().await;
-        writer.write_all(bytes)?;
-        writer.flush()?;
+        if self.shared.waiting.load(Ordering::SeqCst) {
+            self.respawn();
+            return Ok(());
+        }
+        for chunk in bytes.chunks(WRITE_CHUNK) {
+            self.shared
+                .input
+                .send(Bytes::copy_from_slice(chunk))
+                .await
+                .map_err(|_| anyhow!("pty writer closed"))?;
+        }
         Ok(())
     }
 
-    pub async fn resize(&self, rows: u16, cols: u16) -> Result<()> {
-        let mut sz = self.size.lock().await;
+    // true if the size actually changed
+    pub async fn resize(&self, rows: u16, cols: u16) -> Result<bool> {
+        let mut sz = self.shared.size.lock().await;
         if sz.rows == rows && sz.cols == cols {
-            return Ok(());
+            return Ok(false);
         }
         sz.rows = rows;
         sz.cols = cols;
-        self.master.lock().await.resize(*sz)?;
-        Ok(())
+        self.shared.master.lock().await.resize(*sz)?;
+        self.shared.history.lock().await.screen.resize(rows, cols);
+        Ok(true)
+    }
+
+    pub async fn size(&self) -> (u16, u16) {
+        let sz = self.shared.size.lock().await;
+        (sz.rows, sz.cols)
+    }
+
+    // the line discipline reads a line without echoing it, as getpass and password prompts do;
+    // full-screen programs also turn echo off but read raw, so they are not counted
+    pub async fn hides_input(&self) -> bool {
+        self.shared
+            .master
+            .lock()
+            .await
+            .get_termios()
+            .is_some_and(|t| t.local_flags.contains(LocalFlags::ICANON) && !t.local_flags.contains(LocalFlags::ECHO))
+    }
+
+    // deliver to whatever owns the terminal right now, regardless of the line discipline
+    pub async fn signal(&self, sig: PtySignal) -> Result<i32> {
+        let pgrp = self
+            .shared
+            .master
+            .lock()
+            .await
+            .process_group_leader()
+            .ok_or_else(|| anyhow!("no foreground process group"))?;
+        killpg(Pid::from_raw(pgrp), Signal::from(sig)).with_context(|| format!("killpg {pgrp}"))?;
+        Ok(pgrp)
+    }
+
+    // ask a reader that is waiting (on-demand, backoff delay) to respawn now
+    pub fn respawn(&self) {
+        let _ = self.shared.respawn_tx.send(());
+    }
+
+    // hang up the child's process group and stop respawning; `closed()` fires once the reader is gone
+    pub async fn shutdown(&self) {
+        if self.shared.closing.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        self.kill_groups(Signal::SIGHUP).await;
+        let _ = self.shared.killer.lock().await.kill();
+        self.shared.stop.notify_one();
+        let _ = self.shared.respawn_tx.send(());
+    }
+
+    // shutdown, then SIGKILL whatever is left after `grace`; false if the reader still did not stop
+    pub async fn close(&self, grace: Duration) -> bool {
+        self.shutdown().await;
+        let mut closed = self.closed();
+        if time::timeout(grace, closed.wait_for(|c| *c)).await.is_ok() {
+            return true;
+        }
+        self.kill_groups(Signal::SIGKILL).await;
+        time::timeout(grace, closed.wait_for(|c| *c)).await.is_ok()
     }
 
-    async fn spawn_shell(
-        size: &Arc<Mutex<PtySize>>,
-    ) -> Result<(Box<dyn Write + Send>, Box<dyn MasterPty + Send>, Box<dyn Child + Send>)> {
-        let sz = *size.lock().await;
+    // every process group in the child's session: its own, the foreground job and background jobs
+    async fn kill_groups(&self, sig: Signal) {
+        let fg = self.shared.master.lock().await.process_group_leader();
+        let pid = self.shared.pid.load(Ordering::SeqCst);
+        let mut groups = session_groups(pid);
+        groups.extend([pid].into_iter().chain(fg));
+        groups.sort_unstable();
+        groups.dedup();
+        for pgrp in groups.into_iter().filter(|p| *p > 0) {
+            let _ = killpg(Pid::from_raw(pgrp), sig);
+        }
+    }
+
+    pub fn closed(&self) -> watch::Receiver<bool> {
+        self.closed.clone()
+    }
+
+    pub fn is_closed(&self) -> bool {
+        *self.closed.borrow()
+    }
+
+    fn spawn_shell(spec: &SessionSpec, size: PtySize) -> Result<Spawned> {
         let pty_system = native_pty_system();
-        let pair = pty_system.openpty(sz).context("open pty")?;
+        let pair = pty_system.openpty(size).context("open pty")?;
 
-        let mut cmd = CommandBuilder::new("/bin/bash");
-        cmd.env("LC_CTYPE", "C.UTF-8");
-        cmd.env("TERM", "xterm-color");
-        cmd.env("COLORTERM", "truecolor");
+        let rcfile = match spec.integrates_shell() {
+            true => Some(shell::bash_rcfile().context("write shell integration rcfile")?),
+            false => None,
+        };
+        let cmd = spec.command_builder(rcfile.as_deref());
+        let child = pair
+            .slave
+            .spawn_command(cmd)
+            .with_context(|| format!("spawn {}", spec.program()))?;
+        let fd = PtyFd::new(&*pair.master)?;
+        Ok(Spawned {
+            fd,
+            master: pair.master,
+            child,
+        })
+    }
 
-        let child = pair.slave.spawn_command(cmd).context("spawn shell")?;
-        let writer = pair.master.take_writer().context("take writer")?;
-        Ok((writer, pair.master, child))
+    // the only place input reaches the pty, so a slow child never blocks a client's task
+    fn launch_writer(fd: Arc<FdSlot>, mut rx: mpsc::Receiver<Bytes>) {
+        tokio::spawn(async move {
+            while let Some(chunk) = rx.recv().await {
+                let pty = Arc::clone(&fd.lock().unwrap());
+                // input for a child that is gone goes with it
+                let _ = pty.write_all(&chunk).await;
+            }
+        });
     }
 
     fn launch_reader(
-        tx: broadcast::Sender<Vec<u8>>,
-        history: Arc<Mutex<RingBytes>>,
-        writer: Arc<Mutex<Box<dyn Write + Send>>>,
-        master: Arc<Mutex<Box<dyn MasterPty + Send>>>,
-        size: Arc<Mutex<PtySize>>,
+        shared: Arc<Shared>,
+        mut child: Box<dyn Child + Send + Sync>,
+        mut respawn_rx: mpsc::UnboundedReceiver<()>,
+        closed: watch::Sender<bool>,
     ) {
-        task::spawn_blocking(move || {
+        tokio::spawn(async move {
+            let policy = shared.spec.respawn();
+            let mut attempts = 0u32;
+            let mut buf = vec![0u8; BUF_SIZE];
             loop {
-                let mut reader = master.blocking_lock().try_clone_reader().expect("clone reader");
-
-                let mut buf = [0u8; BUF_SIZE];
+                let started = Instant::now();
+                let fd = Arc::clone(&shared.fd.lock().unwrap());
                 loop {
-                    match reader.read(&mut buf) {
+                    let read = select! {
+                        read = fd.read(&mut buf) => read,
+                        _ = shared.stop.notified() => break,
+                    };
+                    match read {
                         Ok(0) => break,
-                        Ok(n) => {
-                            history.blocking_lock().extend(&buf[..n]);
-                            let _ = tx.send(buf[..n].to_vec());
-                        }
-                        Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
-                        Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => continue,
+                        Ok(n) => shared.emit(&buf[..n]).await,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
                         Err(_) => break,
                     }
                 }
 
-                const COMPLETED: &[u8] = b"[Process completed]\r\n\r\n";
-                {
-                    history.blocking_lock().extend(COMPLETED);
-                    let _ = tx.send(COMPLETED.to_vec());
+                let status = task::spawn_blocking(move || child.wait())
+                    .await
+                    .ok()
+                    .and_then(Result::ok)
+                    .unwrap_or_else(|| ExitStatus::with_exit_code(1));
+                shared.emit(b"[Process completed]\r\n\r\n").await;
+
+                if started.elapsed() >= BACKOFF_RESET {
+                    attempts = 0;
                 }
+                let (respawn, delay) = match policy.mode {
+                    _ if shared.closing.load(Ordering::SeqCst) => (RespawnAction::None, None),
+                    RespawnMode::Never => (RespawnAction::None, None),
+                    RespawnMode::Always => (RespawnAction::Immediate, None),
+                    RespawnMode::OnDemand => (RespawnAction::OnDemand, None),
+                    RespawnMode::Backoff if attempts >= policy.max_attempts => (RespawnAction::OnDemand, None),
+                    RespawnMode::Backoff => (RespawnAction::Delayed, Some(policy.delay(attempts))),
+                };
+                let _ = shared.tx.send(PtyEvent::Exit(ExitInfo {
+                    code: status.exit_code(),
+                    signal: status.signal().map(str::to_string),
+                    respawn,
+                    delay_ms: delay.map(|d| d.as_millis() as u64),
+                }));
 
-                match tokio::runtime::Handle::current().block_on(Self::spawn_shell(&size)) {
-                    Ok((new_writer, new_master, _new_child)) => {
-                        *writer.blocking_lock() = new_writer;
-                        *master.blocking_lock() = new_master;
+                // drop respawn requests that arrived while the child was running
+                while respawn_rx.try_recv().is_ok() {}
+                if shared.closing.load(Ordering::SeqCst) {
+                    break;
+                }
+                match respawn {
+                    RespawnAction::None => break,
+                    RespawnAction::Immediate => {}
+                    RespawnAction::Delayed => {
+                        attempts += 1;
+                        let _ = time::timeout(delay.unwrap_or_default(), respawn_rx.recv()).await;
+                    }
+                    RespawnAction::OnDemand => {
+                        attempts = 0;
+                        shared.emit(b"[Press any key to restart]\r\n").await;
+                        shared.waiting.store(true, Ordering::SeqCst);
+                        let woke = respawn_rx.recv().await;
+                        shared.waiting.store(false, Ordering::SeqCst);
+                        if woke.is_none() {
+                            break;
+                        }
+                    }
+                }
+                if shared.closing.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let size = *shared.size.lock().await;
+                match Self::spawn_shell(&shared.spec, size) {
+                    Ok(spawned) => {
+                        shared.marks.lock().unwrap().reset();
+                        *shared.fd.lock().unwrap() = Arc::new(spawned.fd);
+                        *shared.master.lock().await = spawned.master;
+                        *shared.killer.lock().await = spawned.child.clone_killer();
+                        shared.pid.store(child_pid(&*spawned.child), Ordering::SeqCst);
+                        child = spawned.child;
                     }
                     Err(e) => {
-                        let msg = format!("[Respawn failed: {e}]\r\n").into_bytes();
-                        let _ = tx.send(msg.clone());
-                        history.blocking_lock().extend(&msg);
+                        shared.emit(format!("[Respawn failed: {e}]\r\n").as_bytes()).await;
                         break;
                     }
                 }
             }
+            let _ = closed.send(true);
         });
     }
 }
+
+fn child_pid(child: &(dyn Child + Send + Sync)) -> i32 {
+    child.process_id().map_or(0, |p| p as i32)
+}
+
+// process groups of every process whose session id is `sid`, from /proc
+fn session_groups(sid: i32) -> Vec<i32> {
+    let Ok(dir) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    dir.flatten()
+        .filter(|e| e.file_name().to_string_lossy().bytes().all(|b| b.is_ascii_digit()))
+        .filter_map(|e| std::fs::read_to_string(e.path().join("stat")).ok())
+        .filter_map(|stat| {
+            // fields after the command name: state ppid pgrp session
+            let mut fields = stat[stat.rfind(')')? + 1..].split_whitespace().skip(2);
+            let pgrp = fields.next()?.parse().ok()?;
+            let session: i32 = fields.next()?.parse().ok()?;
+            (session == sid).then_some(pgrp)
+        })
+        .collect()
+}
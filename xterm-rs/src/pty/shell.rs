@@ -0,0 +1,291 @@
+use crate::models::unix_millis;
+use crate::pty::CommandInfo;
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+// longest OSC sequence looked at; anything longer is not one of ours
+const MAX_OSC: usize = 8192;
+
+// sourced by bash through `--rcfile` in place of the usual startup files, which it sources itself.
+// the prompt reports the previous command's status (D) and a new prompt (A); PS0, expanded once
+// a line is read, reports the command about to run (C) with the cwd and the line from history
+const BASH_RC: &str = r#"# xterm-rs shell integration
+if [ -n "$XTERM_RS_LOGIN" ]; then
+    [ -r /etc/profile ] && . /etc/profile
+    for __xterm_rs_f in ~/.bash_profile ~/.bash_login ~/.profile; do
+        [ -r "$__xterm_rs_f" ] && { . "$__xterm_rs_f"; break; }
+    done
+    unset __xterm_rs_f
+else
+    [ -r /etc/bash.bashrc ] && . /etc/bash.bashrc
+    [ -r ~/.bashrc ] && . ~/.bashrc
+fi
+unset XTERM_RS_LOGIN
+
+__xterm_rs_prompt() {
+    local status=$?
+    printf '\e]133;D;%s\a\e]133;A\a' "$status"
+    return $status
+}
+
+__xterm_rs_preexec() {
+    local line
+    line=$(HISTTIMEFORMAT= builtin history 1)
+    line=${line#*[0-9]  }
+    printf '\e]133;C;cwd=%s;cmdline=%s\a' "${PWD//[$'\a\e;']/}" "${line//[$'\a\e']/}"
+}
+
+if [[ ";${PROMPT_COMMAND[*]};" != *";__xterm_rs_prompt;"* ]]; then
+    PROMPT_COMMAND="__xterm_rs_prompt${PROMPT_COMMAND:+;$PROMPT_COMMAND}"
+fi
+PS0='$(__xterm_rs_preexec)'"$PS0"
+"#;
+
+// writes the rcfile for `bash --rcfile`; rewritten on every spawn in case tmp was cleaned.
+// it is the same for every server, so one per user is enough
+pub fn bash_rcfile() -> io::Result<PathBuf> {
+    let dir = runtime_dir();
+    private_dir(&dir)?;
+    // a fresh file renamed into place, so a shell starting meanwhile reads it whole
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let tmp = dir.join(format!(
+        "bashrc.{}.{}.tmp",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)
+        .and_then(|mut file| file.write_all(BASH_RC.as_bytes()));
+    let path = dir.join("bashrc");
+    if let Err(e) = written.and_then(|()| fs::rename(&tmp, &path)) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    Ok(path)
+}
+
+// $XDG_RUNTIME_DIR when there is one, else a directory of this user's own under tmp
+fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() && dir.is_dir() => dir.join("xterm-rs"),
+        _ => std::env::temp_dir().join(format!("xterm-rs-{}", nix::unistd::getuid())),
+    }
+}
+
+// creates `dir` closed to everyone else; one already there must be a directory of this user that
+// no one else can enter, or another user could swap the rcfile under the shell
+fn private_dir(dir: &Path) -> io::Result<()> {
+    match fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    // not followed, so a link planted in its place is refused
+    let meta = fs::symlink_metadata(dir)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::getuid().as_raw() || meta.mode() & 0o077 != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is not a private directory of this user", dir.display()),
+        ));
+    }
+    Ok(())
+}
+
+// a command announced by OSC 133;C that has not reported its status yet
+struct Running {
+    command: String,
+    cwd: Option<String>,
+    started_at: u128,
+}
+
+#[derive(Default)]
+enum Scan {
+    #[default]
+    Text,
+    Esc,
+    Osc(Vec<u8>),
+    OscEsc(Vec<u8>),
+}
+
+// finds OSC 133 command boundaries in pty output, across read boundaries
+#[derive(Default)]
+pub struct CommandMarks {
+    scan: Scan,
+    running: Option<Running>,
+}
+
+impl CommandMarks {
+    // commands that finished within `bytes`
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Vec<CommandInfo> {
+        let mut done = Vec::new();
+        while !bytes.is_empty() {
+            if let Scan::Text = self.scan {
+                match memchr::memchr(0x1b, bytes) {
+                    Some(i) => {
+                        self.scan = Scan::Esc;
+                        bytes = &bytes[i + 1..];
+                        continue;
+                    }
+                    None => break,
+                }
+            }
+            let b = bytes[0];
+            bytes = &bytes[1..];
+            self.scan = match std::mem::take(&mut self.scan) {
+                Scan::Esc if b == b']' => Scan::Osc(Vec::new()),
+                Scan::Esc if b == 0x1b => Scan::Esc,
+                Scan::Osc(buf) | Scan::OscEsc(buf) if b == 0x07 => {
+                    done.extend(self.mark(&buf));
+                    Scan::Text
+                }
+                Scan::OscEsc(buf) if b == b'\\' => {
+                    done.extend(self.mark(&buf));
+                    Scan::Text
+                }
+                Scan::Osc(buf) if b == 0x1b => Scan::OscEsc(buf),
+                Scan::Osc(mut buf) if buf.len() < MAX_OSC => {
+                    buf.push(b);
+                    Scan::Osc(buf)
+                }
+                Scan::OscEsc(_) if b == 0x1b => Scan::Esc,
+                _ => Scan::Text,
+            };
+        }
+        done
+    }
+
+    // the shell went away; a command it was running never finishes
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn mark(&mut self, osc: &[u8]) -> Option<CommandInfo> {
+        let osc = String::from_utf8_lossy(osc);
+        let mark = osc.strip_prefix("133;")?;
+        let (kind, params) = mark.split_once(';').unwrap_or((mark, ""));
+        match kind {
+            "C" => {
+                // cmdline comes last and runs to the end, it may hold anything but the terminator
+                let (head, command) = match params.find("cmdline=") {
+                    Some(i) => (&params[..i], params[i + "cmdline=".len()..].to_owned()),
+                    None => (params, String::new()),
+                };
+                let cwd = head.split(';').find_map(|p| p.strip_prefix("cwd=")).map(str::to_owned);
+                self.running = Some(Running {
+                    command,
+                    cwd,
+                    started_at: unix_millis(),
+                });
+                None
+            }
+            "D" => {
+                // an empty line only reprints the prompt
+                let running = self.running.take()?;
+                Some(CommandInfo {
+                    command: running.command,
+                    cwd: running.cwd,
+                    started_at: running.started_at,
+                    ended_at: unix_millis(),
+                    exit_code: params.split(';').next().and_then(|c| c.parse().ok()),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    const LS: &[u8] = b"\x1b]133;C;cwd=/tmp;cmdline=ls -l; echo a\x07file\r\n\x1b]133;D;2\x1b\\\x1b]133;A\x07$ ";
+
+    #[test]
+    fn command_between_marks() {
+        let mut marks = CommandMarks::default();
+        let done = marks.feed(LS);
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].command, "ls -l; echo a");
+        assert_eq!(done[0].cwd.as_deref(), Some("/tmp"));
+        assert_eq!(done[0].exit_code, Some(2));
+        assert!(done[0].started_at <= done[0].ended_at);
+    }
+
+    #[test]
+    fn marks_split_across_reads() {
+        let mut marks = CommandMarks::default();
+        let done: Vec<_> = LS.chunks(1).flat_map(|b| marks.feed(b)).collect();
+        assert_eq!(done.len(), 1);
+        assert_eq!(done[0].command, "ls -l; echo a");
+        assert_eq!(done[0].exit_code, Some(2));
+    }
+
+    #[test]
+    fn status_without_a_command() {
+        let mut marks = CommandMarks::default();
+        // an empty line, then a title and a command that reports no status
+        assert!(marks.feed(b"\x1b]133;D;0\x07\x1b]133;A\x07").is_empty());
+        let done = marks.feed(b"\x1b]0;title\x07\x1b]133;C;cmdline=true\x07\x1b]133;D\x07");
+        assert_eq!(done.len(), 1);
+        assert_eq!((done[0].command.as_str(), done[0].cwd.as_deref()), ("true", None));
+        assert_eq!(done[0].exit_code, None);
+    }
+
+    #[test]
+    fn reset_forgets_the_running_command() {
+        let mut marks = CommandMarks::default();
+        assert!(marks.feed(b"\x1b]133;C;cmdline=sleep 9\x07\x1b]133").is_empty());
+        marks.reset();
+        assert!(marks.feed(b";D;0\x07\x1b]133;D;0\x07").is_empty());
+    }
+
+    #[test]
+    fn overlong_osc_is_not_a_mark() {
+        let mut marks = CommandMarks::default();
+        let mut long = b"\x1b]133;C;cmdline=".to_vec();
+        long.resize(MAX_OSC + 100, b'x');
+        long.extend_from_slice(b"\x07\x1b]133;D;0\x07");
+        assert!(marks.feed(&long).is_empty());
+        // scanning picks up again after it
+        assert_eq!(marks.feed(b"\x1b]133;C;cmdline=ok\x07\x1b]133;D;0\x07").len(), 1);
+    }
+
+    #[test]
+    fn rcfile_dir_must_be_private() {
+        let base = std::env::temp_dir().join(format!("xterm-rs-shell-test-{}", std::process::id()));
+        fs::create_dir_all(&base).unwrap();
+        let dir = base.join("private");
+        private_dir(&dir).unwrap();
+        assert_eq!(fs::metadata(&dir).unwrap().mode() & 0o777, 0o700);
+        // again, as on every spawn
+        private_dir(&dir).unwrap();
+
+        let open = base.join("open");
+        fs::create_dir(&open).unwrap();
+        fs::set_permissions(&open, PermissionsExt::from_mode(0o755)).unwrap();
+        assert_eq!(private_dir(&open).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        let link = base.join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert_eq!(private_dir(&link).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn rcfile_is_private() {
+        let path = bash_rcfile().unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        assert_eq!(meta.mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), BASH_RC);
+        assert_eq!(path.parent(), Some(runtime_dir().as_path()));
+    }
+}
@@ -0,0 +1,29 @@
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+
+// signals a client may deliver to the foreground process group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PtySignal {
+    #[serde(rename = "SIGINT", alias = "INT")]
+    Int,
+    #[serde(rename = "SIGTERM", alias = "TERM")]
+    Term,
+    #[serde(rename = "SIGKILL", alias = "KILL")]
+    Kill,
+    #[serde(rename = "SIGTSTP", alias = "TSTP")]
+    Tstp,
+    #[serde(rename = "SIGQUIT", alias = "QUIT")]
+    Quit,
+}
+
+impl From<PtySignal> for Signal {
+    fn from(s: PtySignal) -> Self {
+        match s {
+            PtySignal::Int => Signal::SIGINT,
+            PtySignal::Term => Signal::SIGTERM,
+            PtySignal::Kill => Signal::SIGKILL,
+            PtySignal::Tstp => Signal::SIGTSTP,
+            PtySignal::Quit => Signal::SIGQUIT,
+        }
+    }
+}
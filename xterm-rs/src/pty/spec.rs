@@ -0,0 +1,185 @@
+use portable_pty::CommandBuilder;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const DEFAULT_COMMAND: &str = "/bin/bash";
+
+// shells that take `-l` to start as a login shell
+const LOGIN_SHELLS: &[&str] = &["bash", "zsh", "sh", "dash", "ksh", "mksh", "fish"];
+
+const DEFAULT_ENV: &[(&str, &str)] = &[
+    ("LC_CTYPE", "C.UTF-8"),
+    ("TERM", "xterm-color"),
+    ("COLORTERM", "truecolor"),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum RespawnMode {
+    #[default]
+    Always,
+    Never,
+    OnDemand,
+    Backoff,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RespawnPolicy {
+    pub mode: RespawnMode,
+    // consecutive respawns before giving up and waiting for a client (backoff only)
+    pub max_attempts: u32,
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RespawnPolicy {
+    fn default() -> Self {
+        Self {
+            mode: RespawnMode::Always,
+            max_attempts: 5,
+            initial_delay_ms: 500,
+            max_delay_ms: 30_000,
+        }
+    }
+}
+
+impl RespawnPolicy {
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let ms = self.initial_delay_ms.saturating_mul(1u64 << attempt.min(20));
+        Duration::from_millis(ms.min(self.max_delay_ms))
+    }
+}
+
+// what to run inside the pty, from `[session]` in config.toml and the cli
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct SessionSpec {
+    pub command: Option<String>,
+    pub args: Vec<String>,
+    pub cwd: Option<PathBuf>,
+    pub env: BTreeMap<String, String>,
+    pub env_remove: Vec<String>,
+    pub login: bool,
+    // report command boundaries from bash prompts (OSC 133), see `pty::shell`
+    pub shell_integration: bool,
+    pub respawn: Option<RespawnPolicy>,
+}
+
+impl SessionSpec {
+    // fields set in `other` win; args follow the command they belong to
+    pub fn merge(mut self, other: SessionSpec) -> Self {
+        if other.command.is_some() {
+            self.command = other.command;
+            self.args = other.args;
+        } else if !other.args.is_empty() {
+            self.args = other.args;
+        }
+        if other.cwd.is_some() {
+            self.cwd = other.cwd;
+        }
+        for (k, v) in other.env {
+            self.env_remove.retain(|r| r != &k);
+            self.env.insert(k, v);
+        }
+        for k in other.env_remove {
+            self.env.remove(&k);
+            self.env_remove.push(k);
+        }
+        self.login |= other.login;
+        self.shell_integration |= other.shell_integration;
+        if other.respawn.is_some() {
+            self.respawn = other.respawn;
+        }
+        self
+    }
+
+    pub fn respawn(&self) -> RespawnPolicy {
+        self.respawn.clone().unwrap_or_default()
+    }
+
+    pub fn program(&self) -> &str {
+        self.command.as_deref().unwrap_or(DEFAULT_COMMAND)
+    }
+
+    // `login` passes `-l`, which anything but a shell takes to mean something else or nothing
+    pub fn check(&self) -> Result<(), String> {
+        let name = Path::new(self.program()).file_name().unwrap_or_default();
+        if self.login && !LOGIN_SHELLS.iter().any(|shell| name == *shell) {
+            return Err(format!(
+                "login needs a shell that takes -l ({}), not '{}'",
+                LOGIN_SHELLS.join(", "),
+                self.program()
+            ));
+        }
+        Ok(())
+    }
+
+    // what `command_builder` runs, as argv
+    pub fn argv(&self) -> Vec<String> {
+        let mut argv = vec![self.program().to_owned()];
+        if self.login {
+            argv.push("-l".to_owned());
+        }
+        argv.extend(self.args.iter().cloned());
+        argv
+    }
+
+    // a variable as the command will see it, for those set here
+    pub fn env_var(&self, key: &str) -> Option<&str> {
+        if let Some(v) = self.env.get(key) {
+            return Some(v);
+        }
+        if self.env_remove.iter().any(|k| k == key) {
+            return None;
+        }
+        DEFAULT_ENV.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    }
+
+    // only an interactive bash started without arguments reads the rcfile
+    pub fn integrates_shell(&self) -> bool {
+        self.shell_integration && self.args.is_empty() && Path::new(self.program()).file_name() == Some("bash".as_ref())
+    }
+
+    // with `rcfile`, bash starts through it instead of its startup files; a login shell is
+    // emulated there since bash ignores `--rcfile` with `-l`
+    pub fn command_builder(&self, rcfile: Option<&Path>) -> CommandBuilder {
+        let mut cmd = CommandBuilder::new(self.program());
+        match rcfile {
+            Some(rcfile) => {
+                cmd.arg("--rcfile");
+                cmd.arg(rcfile);
+                if self.login {
+                    cmd.env("XTERM_RS_LOGIN", "1");
+                }
+            }
+            None if self.login => cmd.arg("-l"),
+            None => {}
+        }
+        cmd.args(&self.args);
+        if let Some(cwd) = &self.cwd {
+            cmd.cwd(cwd);
+        }
+        for (k, v) in DEFAULT_ENV {
+            cmd.env(k, v);
+        }
+        for (k, v) in &self.env {
+            cmd.env(k, v);
+        }
+        for k in &self.env_remove {
+            cmd.env_remove(k);
+        }
+        cmd
+    }
+}
+
+pub fn parse_env_pair(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got '{s}'")),
+    }
+}
@@ -0,0 +1,140 @@
+mod player;
+
+use crate::caster::{CastReader, CastRecord, rotate};
+use anyhow::Context;
+use clap::ValueHint;
+use std::path::PathBuf;
+
+pub use player::Player;
+
+#[derive(clap::Args, Debug)]
+pub struct ReplayArgs {
+    #[arg(value_hint = ValueHint::FilePath, long_help = "Recording to play (.cast or .cast.zst)")]
+    file: PathBuf,
+
+    #[arg(
+        short,
+        long,
+        default_value_t = 1.0,
+        value_parser = parse_speed,
+        long_help = "Playback speed multiplier"
+    )]
+    speed: f64,
+
+    #[arg(
+        short,
+        long,
+        value_name = "SECS",
+        value_parser = parse_secs,
+        long_help = "Shorten pauses between events to at most this long"
+    )]
+    idle_limit: Option<f64>,
+
+    #[arg(
+        long,
+        value_name = "TIME",
+        value_parser = parse_time,
+        long_help = "Start at this point of the recording (SECS, MM:SS or HH:MM:SS)"
+    )]
+    from: Option<f64>,
+
+    #[arg(long, long_help = "Start paused")]
+    paused: bool,
+
+    #[arg(long, long_help = "Show the recorded keystrokes on the bottom line")]
+    show_input: bool,
+}
+
+pub fn run(args: ReplayArgs) -> anyhow::Result<()> {
+    let file = rotate::open_cast(&args.file).with_context(|| format!("open {}", args.file.display()))?;
+    let (records, err) = read_records(file)?;
+    if let Some(e) = &err {
+        let end = records.last().map_or(0, |r| r.offset);
+        eprintln!(
+            "warning: {}: {}, playing {} events up to offset {}",
+            args.file.display(),
+            e,
+            records.len(),
+            end
+        );
+    }
+
+    let mut player = Player::new(records, args.speed, args.idle_limit, args.show_input, args.paused);
+    player.play(args.from)
+}
+
+// every record up to the end or the first decode error, which is returned alongside
+pub fn read_records(r: impl std::io::Read) -> anyhow::Result<(Vec<CastRecord>, Option<crate::caster::DecodeError>)> {
+    let reader = CastReader::new(r)?;
+    let mut records = Vec::new();
+    for rec in reader {
+        match rec {
+            Ok(rec) => records.push(rec),
+            Err(e) => return Ok((records, Some(e))),
+        }
+    }
+    Ok((records, None))
+}
+
+pub fn parse_time(s: &str) -> Result<f64, String> {
+    let mut secs = 0.0;
+    for part in s.split(':') {
+        let n: f64 = part.parse().map_err(|_| format!("invalid time '{s}'"))?;
+        secs = secs * 60.0 + n;
+    }
+    if s.split(':').count() > 3 || !secs.is_finite() || secs < 0.0 {
+        return Err(format!("invalid time '{s}'"));
+    }
+    Ok(secs)
+}
+
+fn parse_speed(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(n) if n.is_finite() && n > 0.0 => Ok(n),
+        _ => Err(format!("invalid speed '{s}', expected a number above 0")),
+    }
+}
+
+fn parse_secs(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(n) if n.is_finite() && n >= 0.0 => Ok(n),
+        _ => Err(format!("invalid seconds '{s}', expected a number of at least 0")),
+    }
+}
+
+pub fn format_time(secs: f64) -> String {
+    let secs = secs.max(0.0) as u64;
+    match secs / 3600 {
+        0 => format!("{:02}:{:02}", secs / 60, secs % 60),
+        h => format!("{}:{:02}:{:02}", h, secs / 60 % 60, secs % 60),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time("90"), Ok(90.0));
+        assert_eq!(parse_time("1:30"), Ok(90.0));
+        assert_eq!(parse_time("1:00:01.5"), Ok(3601.5));
+        for bad in ["", "-1", "1:2:3:4", "NaN", "inf", "x"] {
+            assert!(parse_time(bad).is_err(), "{bad:?}");
+        }
+        assert_eq!(format_time(3601.5), "1:00:01");
+        assert_eq!(format_time(-1.0), "00:00");
+    }
+
+    #[test]
+    fn speed_and_idle_limit() {
+        assert_eq!(parse_speed("0.5"), Ok(0.5));
+        assert_eq!(parse_secs("0"), Ok(0.0));
+        for bad in ["0", "-1", "NaN", "inf"] {
+            assert!(parse_speed(bad).is_err(), "{bad:?}");
+        }
+        for bad in ["-1", "NaN", "inf", "1:00"] {
+            assert!(parse_secs(bad).is_err(), "{bad:?}");
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::caster::{CastEvent, CastRecord, asciicast};
+use crate::replay::format_time;
+use crossterm::{
+    event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    terminal,
+};
+use std::{
+    io::{self, BufWriter, Stdout, Write},
+    time::{Duration, Instant},
+};
+
+const SEEK_STEP: f64 = 10.0;
+const MAX_SPEED: f64 = 64.0;
+// keystrokes kept on the input overlay
+const OVERLAY_LEN: usize = 40;
+// modes a recording may leave on; reset so the terminal is usable after playback
+const RESTORE: &[u8] =
+    b"\x1b[0m\x1b[r\x1b[?25h\x1b[?1l\x1b>\x1b[?1000l\x1b[?1002l\x1b[?1003l\x1b[?1006l\x1b[?2004l\x1b[?1049l";
+
+enum Action {
+    Next,
+    Seek(f64),
+    Quit,
+}
+
+// raw mode on an alternate screen for as long as it lives
+struct TermGuard;
+
+impl TermGuard {
+    fn enter(out: &mut impl Write) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        out.write_all(b"\x1b[?1049h\x1b[H\x1b[2J")?;
+        out.flush()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TermGuard {
+    fn drop(&mut self) {
+        let mut out = io::stdout();
+        let _ = out.write_all(RESTORE);
+        let _ = out.flush();
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+pub struct Player {
+    records: Vec<CastRecord>,
+    pos: usize,
+    // recording time of the last event played
+    clock: f64,
+    speed: f64,
+    idle_limit: Option<f64>,
+    show_input: bool,
+    paused: bool,
+    keys: String,
+    // client whose keystrokes the overlay last showed
+    typist: Option<u64>,
+    exit: Option<String>,
+    // gaps played past, where the recording is missing something
+    gaps: usize,
+    // size of the recorded terminal, once the recording says
+    size: Option<(u16, u16)>,
+    out: BufWriter<Stdout>,
+}
+
+impl Player {
+    pub fn new(records: Vec<CastRecord>, speed: f64, idle_limit: Option<f64>, show_input: bool, paused: bool) -> Self {
+        Self {
+            records,
+            pos: 0,
+            clock: 0.0,
+            speed: speed.clamp(1.0 / MAX_SPEED, MAX_SPEED),
+            idle_limit,
+            show_input,
+            paused,
+            keys: String::new(),
+            typist: None,
+            exit: None,
+            gaps: 0,
+            size: None,
+            out: BufWriter::new(io::stdout()),
+        }
+    }
+
+    // space pauses, `.` steps while paused, +/- change speed, arrows seek, q quits
+    pub fn play(&mut self, from: Option<f64>) -> anyhow::Result<()> {
+        let _guard = TermGuard::enter(&mut self.out)?;
+        if let Some(from) = from {
+            self.seek(from)?;
+        }
+        self.draw_status()?;
+
+        loop {
+            let delay = match self.records.get(self.pos) {
+                Some(rec) => {
+                    let gap = (rec.time - self.clock).max(0.0);
+                    let gap = self.idle_limit.map_or(gap, |limit| gap.min(limit));
+                    // a time a corrupt v1 file made infinite plays at once
+                    Duration::try_from_secs_f64(gap / self.speed).unwrap_or_default()
+                }
+                None => Duration::ZERO,
+            };
+            match self.wait(delay)? {
+                Action::Next => self.step()?,
+                Action::Seek(t) => self.seek(t)?,
+                Action::Quit => return Ok(()),
+            }
+        }
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.records.len()
+    }
+
+    fn duration(&self) -> f64 {
+        self.records.last().map_or(0.0, |r| r.time)
+    }
+
+    // sleep until the next event is due, handling keys meanwhile
+    fn wait(&mut self, delay: Duration) -> io::Result<Action> {
+        let mut remaining = delay;
+        loop {
+            let idle = self.paused || self.at_end();
+            let started = Instant::now();
+            if !idle && !event::poll(remaining)? {
+                return Ok(Action::Next);
+            }
+            remaining = remaining.saturating_sub(started.elapsed());
+
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(Action::Quit),
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(Action::Quit),
+                KeyCode::Char(' ') => {
+                    self.paused = !self.paused;
+                    self.draw_status()?;
+                }
+                KeyCode::Char('.' | 'n') if self.paused && !self.at_end() => return Ok(Action::Next),
+                KeyCode::Char('+' | '=') if self.speed < MAX_SPEED => {
+                    self.speed *= 2.0;
+                    remaining /= 2;
+                    self.draw_status()?;
+                }
+                KeyCode::Char('-') if self.speed > 1.0 / MAX_SPEED => {
+                    self.speed /= 2.0;
+                    remaining *= 2;
+                    self.draw_status()?;
+                }
+                KeyCode::Right => return Ok(Action::Seek(self.clock + SEEK_STEP)),
+                KeyCode::Left => return Ok(Action::Seek(self.clock - SEEK_STEP)),
+                _ => {}
+            }
+        }
+    }
+
+    fn step(&mut self) -> io::Result<()> {
+        if self.at_end() {
+            return Ok(());
+        }
+        self.apply(self.pos);
+        self.pos += 1;
+        self.draw_status()
+    }
+
+    // jumps to the last keyframe before the target when going back or past one; without a
+    // keyframe going back replays from the start, the terminal has no way to un-print
+    fn seek(&mut self, target: f64) -> io::Result<()> {
+        let target = target.clamp(0.0, self.duration());
+        let back = target < self.clock;
+        let keyframe = self
+            .records
+            .iter()
+            .rposition(|r| r.time <= target && matches!(r.event, CastEvent::Keyframe { .. }));
+        match keyframe {
+            Some(k) if back || k >= self.pos => {
+                self.reset()?;
+                if let CastEvent::Keyframe { rows, cols, screen } = &self.records[k].event {
+                    self.out.write_all(screen)?;
+                    self.size = Some((*rows, *cols));
+                }
+                self.clock = self.records[k].time;
+                self.pos = k + 1;
+            }
+            None if back => self.reset()?,
+            _ => {}
+        }
+        while self.records.get(self.pos).is_some_and(|r| r.time <= target) {
+            self.apply(self.pos);
+            self.pos += 1;
+        }
+        self.clock = target;
+        self.draw_status()
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        // soft reset: a full one would also leave the alternate screen
+        self.out.write_all(b"\x1b[!p\x1b[H\x1b[2J")?;
+        self.pos = 0;
+        self.clock = 0.0;
+        self.keys.clear();
+        self.typist = None;
+        self.exit = None;
+        self.gaps = 0;
+        Ok(())
+    }
+
+    fn apply(&mut self, idx: usize) {
+        let rec = &self.records[idx];
+        self.clock = rec.time;
+        match &rec.event {
+            CastEvent::Output(data) => {
+                let _ = self.out.write_all(data);
+            }
+            CastEvent::Input { client, data } if self.show_input => self.typed(*client, &printable(data)),
+            CastEvent::Redacted { client, len } if self.show_input => {
+                self.typed(Some(*client), &asciicast::REDACTED.repeat(*len))
+            }
+            CastEvent::Resize { rows, cols } => self.size = Some((*rows, *cols)),
+            CastEvent::Exit(info) => {
+                self.exit = Some(match &info.signal {
+                    Some(sig) => format!("exited ({})", sig),
+                    None => format!("exited {}", info.code),
+                })
+            }
+            CastEvent::Gap(_) => self.gaps += 1,
+            CastEvent::Input { .. }
+            | CastEvent::Redacted { .. }
+            | CastEvent::Keyframe { .. }
+            | CastEvent::Command(_)
+            | CastEvent::Checkpoint(_) => {}
+        }
+    }
+
+    fn typed(&mut self, client: Option<u64>, keys: &str) {
+        if client != self.typist {
+            if let Some(id) = client {
+                self.keys.push_str(&format!(" #{}:", id));
+            }
+            self.typist = client;
+        }
+        self.keys.push_str(keys);
+        let excess = self.keys.chars().count().saturating_sub(OVERLAY_LEN);
+        self.keys = self.keys.chars().skip(excess).collect();
+    }
+
+    // bottom line of the real terminal; shown while paused, at the end, or with the input overlay
+    fn draw_status(&mut self) -> io::Result<()> {
+        if !(self.paused || self.at_end() || self.show_input) {
+            return self.out.flush();
+        }
+        let (cols, rows) = terminal::size()?;
+        let state = match (self.at_end(), self.paused) {
+            (true, _) => "end",
+            (false, true) => "paused",
+            (false, false) => "playing",
+        };
+        let mut line = format!(
+            " {} {} / {} x{}",
+            state,
+            format_time(self.clock),
+            format_time(self.duration()),
+            self.speed
+        );
+        if let Some((r, c)) = self.size {
+            line.push_str(&format!(" [{}x{}]", c, r));
+        }
+        if let Some(exit) = &self.exit {
+            line.push_str(&format!(" {}", exit));
+        }
+        if self.gaps > 0 {
+            line.push_str(&format!(" ({} gaps)", self.gaps));
+        }
+        if self.show_input {
+            line.push_str(&format!("  keys: {}", self.keys));
+        }
+        let line: String = line.chars().take(cols as usize).collect();
+        write!(
+            self.out,
+            "\x1b7\x1b[{};1H\x1b[0;7m{:<w$}\x1b[0m\x1b8",
+            rows,
+            line,
+            w = cols as usize
+        )?;
+        self.out.flush()
+    }
+}
+
+// keystrokes as they would read on a keycap
+fn printable(data: &[u8]) -> String {
+    let text = String::from_utf8_lossy(data);
+    let mut out = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\r' | '\n' => out.push('⏎'),
+            '\t' => out.push('⇥'),
+            '\x7f' | '\x08' => out.push('⌫'),
+            '\x1b' if chars.peek() == Some(&'[') => {
+                chars.next();
+                out.push(match chars.next() {
+                    Some('A') => '↑',
+                    Some('B') => '↓',
+                    Some('C') => '→',
+                    Some('D') => '←',
+                    _ => '⎋',
+                });
+            }
+            '\x1b' => out.push('⎋'),
+            c if (c as u32) < 0x20 => {
+                out.push('^');
+                out.push((c as u8 + b'@') as char);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,28 @@
+use crate::models::{AppError, AppState};
+use crate::session::SessionInfo;
+use axum::{
+    Json,
+    extract::{Extension, Path},
+    http::StatusCode,
+};
+use std::sync::Arc;
+
+pub async fn list_sessions(Extension(state): Extension<Arc<AppState>>) -> Json<Vec<SessionInfo>> {
+    Json(state.sessions.list().await)
+}
+
+pub async fn create_session(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<(StatusCode, Json<SessionInfo>), AppError> {
+    let session = state.sessions.create(&name).await?;
+    Ok((StatusCode::CREATED, Json(session.info().await)))
+}
+
+pub async fn destroy_session(
+    Path(name): Path<String>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<StatusCode, AppError> {
+    state.sessions.destroy(&name).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
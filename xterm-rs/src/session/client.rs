@@ -0,0 +1,55 @@
+use crate::models::unix_millis;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+// per-connection counters, reported through the session listing
+#[derive(Debug)]
+pub struct ClientStats {
+    pub id: u64,
+    pub connected: u128,
+    lagged: AtomicU64,
+    dropped: AtomicU64,
+    lost: AtomicU64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ClientInfo {
+    pub id: u64,
+    pub connected: u128,
+    pub lagged: u64,
+    pub dropped: u64,
+    pub lost: u64,
+}
+
+impl ClientStats {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            connected: unix_millis(),
+            lagged: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            lost: AtomicU64::new(0),
+        }
+    }
+
+    // one lag event that skipped `dropped` chunks on the broadcast channel
+    pub fn record_lag(&self, dropped: u64) {
+        self.lagged.fetch_add(1, Ordering::Relaxed);
+        self.dropped.fetch_add(dropped, Ordering::Relaxed);
+    }
+
+    // bytes that had already left the history when the client caught up
+    pub fn record_lost(&self, bytes: u64) {
+        self.lost.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn info(&self) -> ClientInfo {
+        ClientInfo {
+            id: self.id,
+            connected: self.connected,
+            lagged: self.lagged.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            lost: self.lost.load(Ordering::Relaxed),
+        }
+    }
+}
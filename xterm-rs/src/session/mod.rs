@@ -0,0 +1,6 @@
+pub mod api;
+pub mod client;
+pub mod registry;
+pub use api::{create_session, destroy_session, list_sessions};
+pub use client::{ClientInfo, ClientStats};
+pub use registry::{DEFAULT_SESSION, Session, SessionError, SessionInfo, SessionRegistry};
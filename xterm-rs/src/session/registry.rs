@@ -0,0 +1,277 @@
+use crate::caster::{CastInfo, CastOptions, Caster, Metadata};
+use crate::models::{logger, unix_millis};
+use crate::pty::{HistoryOptions, PtyManager, SessionSpec};
+use crate::session::{ClientInfo, ClientStats};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Mutex, RwLock};
+
+pub const DEFAULT_SESSION: &str = "main";
+const MAX_NAME_LEN: usize = 32;
+// how long a destroyed session's recording gets to take in its last events
+const CLOSE_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, thiserror::Error)]
+pub enum SessionError {
+    #[error("invalid session name '{0}'")]
+    InvalidName(String),
+    #[error("session '{0}' already exists")]
+    Exists(String),
+    #[error("session '{0}' not found")]
+    NotFound(String),
+    #[error("session '{0}' cannot be destroyed")]
+    Protected(String),
+    #[error("too many sessions (max {0})")]
+    Limit(usize),
+    #[error(transparent)]
+    Spawn(#[from] anyhow::Error),
+}
+
+pub struct Session {
+    pub name: String,
+    pub start: Instant,
+    pub created: u128,
+    pub pty: Arc<PtyManager>,
+    pub caster: Option<Arc<Caster>>,
+    clients: std::sync::Mutex<BTreeMap<u64, Arc<ClientStats>>>,
+    next_client: AtomicU64,
+}
+
+impl Session {
+    pub async fn info(&self) -> SessionInfo {
+        let (rows, cols) = self.pty.size().await;
+        SessionInfo {
+            name: self.name.clone(),
+            created: self.created,
+            rows,
+            cols,
+            clients: self.clients.lock().unwrap().values().map(|c| c.info()).collect(),
+            cast: self.caster.as_ref().map(|c| c.info()),
+        }
+    }
+
+    // registers a client for as long as the guard lives
+    pub fn attach(self: &Arc<Self>) -> ClientGuard {
+        let id = self.next_client.fetch_add(1, Ordering::SeqCst);
+        let stats = Arc::new(ClientStats::new(id));
+        self.clients.lock().unwrap().insert(id, Arc::clone(&stats));
+        ClientGuard {
+            session: Arc::clone(self),
+            stats,
+        }
+    }
+}
+
+pub struct ClientGuard {
+    session: Arc<Session>,
+    stats: Arc<ClientStats>,
+}
+
+impl ClientGuard {
+    pub fn id(&self) -> u64 {
+        self.stats.id
+    }
+
+    pub fn stats(&self) -> Arc<ClientStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        self.session.clients.lock().unwrap().remove(&self.stats.id);
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo {
+    pub name: String,
+    pub created: u128,
+    pub rows: u16,
+    pub cols: u16,
+    pub clients: Vec<ClientInfo>,
+    pub cast: Option<CastInfo>,
+}
+
+pub struct SessionRegistry {
+    spec: Arc<SessionSpec>,
+    history: HistoryOptions,
+    max_sessions: usize,
+    cast: Option<CastOptions>,
+    // rows and cols a session starts at; after that each has its own, see `PtyManager::size`
+    size: (u16, u16),
+    sessions: RwLock<BTreeMap<String, Arc<Session>>>,
+    // serializes create so two clients racing on a new name get the same session
+    create_lock: Mutex<()>,
+}
+
+impl SessionRegistry {
+    pub fn new(
+        spec: Arc<SessionSpec>,
+        history: HistoryOptions,
+        max_sessions: usize,
+        cast: Option<CastOptions>,
+        size: (u16, u16),
+    ) -> Self {
+        Self {
+            spec,
+            history,
+            max_sessions,
+            cast,
+            size,
+            sessions: RwLock::new(BTreeMap::new()),
+            create_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn spec(&self) -> Arc<SessionSpec> {
+        Arc::clone(&self.spec)
+    }
+
+    pub fn size(&self) -> (u16, u16) {
+        self.size
+    }
+
+    // closed sessions (child gone for good) are treated as absent
+    pub async fn get(&self, name: &str) -> Option<Arc<Session>> {
+        self.sessions
+            .read()
+            .await
+            .get(name)
+            .filter(|s| !s.pty.is_closed())
+            .cloned()
+    }
+
+    pub async fn create(&self, name: &str) -> Result<Arc<Session>, SessionError> {
+        let _guard = self.create_lock.lock().await;
+        if self.get(name).await.is_some() {
+            return Err(SessionError::Exists(name.into()));
+        }
+        self.spawn(name).await
+    }
+
+    // get the named session, creating it on first use
+    pub async fn attach(&self, name: &str) -> Result<Arc<Session>, SessionError> {
+        if let Some(s) = self.get(name).await {
+            return Ok(s);
+        }
+        let _guard = self.create_lock.lock().await;
+        if let Some(s) = self.get(name).await {
+            return Ok(s);
+        }
+        self.spawn(name).await
+    }
+
+    pub async fn destroy(&self, name: &str) -> Result<(), SessionError> {
+        if name == DEFAULT_SESSION {
+            return Err(SessionError::Protected(name.into()));
+        }
+        let session = self
+            .sessions
+            .write()
+            .await
+            .remove(name)
+            .ok_or_else(|| SessionError::NotFound(name.into()))?;
+        session.pty.shutdown().await;
+        if let Some(caster) = &session.caster {
+            caster.finish(CLOSE_GRACE).await;
+        }
+        logger("info", format!("Session '{}' destroyed", name));
+        Ok(())
+    }
+
+    // closes every recording, for a server on its way out
+    pub async fn close(&self) {
+        let sessions: Vec<_> = self.sessions.read().await.values().cloned().collect();
+        for session in sessions {
+            if let Some(caster) = &session.caster {
+                caster.close().await;
+            }
+        }
+    }
+
+    pub async fn list(&self) -> Vec<SessionInfo> {
+        let sessions: Vec<_> = self.sessions.read().await.values().cloned().collect();
+        let mut out = Vec::with_capacity(sessions.len());
+        for s in sessions {
+            out.push(s.info().await);
+        }
+        out
+    }
+
+    async fn spawn(&self, name: &str) -> Result<Arc<Session>, SessionError> {
+        if !valid_name(name) {
+            return Err(SessionError::InvalidName(name.into()));
+        }
+        let (exited, open): (Vec<_>, usize) = {
+            let mut sessions = self.sessions.write().await;
+            let exited = sessions.values().filter(|s| s.pty.is_closed()).cloned().collect();
+            sessions.retain(|_, s| !s.pty.is_closed());
+            (exited, sessions.len())
+        };
+        // their recordings close behind a last checkpoint before they are dropped
+        for session in exited {
+            if let Some(caster) = &session.caster {
+                caster.finish(CLOSE_GRACE).await;
+            }
+        }
+        if open >= self.max_sessions {
+            return Err(SessionError::Limit(self.max_sessions));
+        }
+
+        let (rows, cols) = self.size;
+        let start = Instant::now();
+        let created = unix_millis();
+        let pty = Arc::new(PtyManager::new(self.spec(), rows, cols, self.history).await?);
+
+        let caster = match &self.cast {
+            None => None,
+            Some(opts) => {
+                let file_name = match name {
+                    DEFAULT_SESSION => created.to_string(),
+                    _ => format!("{}-{}", created, name),
+                };
+                match Caster::new(opts, &file_name, start, Metadata::new(&self.spec, created, rows, cols)) {
+                    Ok(caster) => Some(caster),
+                    Err(e) => {
+                        // the shell is already running; it goes rather than run unrecorded
+                        pty.close(CLOSE_GRACE).await;
+                        return Err(e.into());
+                    }
+                }
+            }
+        };
+
+        if let Some(caster) = &caster {
+            caster.record(Arc::clone(&pty), start);
+        }
+
+        let session = Arc::new(Session {
+            name: name.into(),
+            start,
+            created,
+            pty,
+            caster,
+            clients: std::sync::Mutex::new(BTreeMap::new()),
+            next_client: AtomicU64::new(1),
+        });
+        self.sessions.write().await.insert(name.into(), Arc::clone(&session));
+        logger("info", format!("Session '{}' created", name));
+        Ok(session)
+    }
+}
+
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
@@ -0,0 +1,128 @@
+use crate::pty::{CommandInfo, ExitInfo, Position, PtyEvent, PtyManager, Replay};
+use crate::session::ClientStats;
+use axum::extract::ws::{Message, WebSocket};
+use bytes::Bytes;
+use std::sync::Arc;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+
+pub enum Frame {
+    Output(Bytes),
+    Exit(ExitInfo),
+    Command(CommandInfo),
+    // sent on attach and whenever the client fell behind
+    Sync(u64, Replay),
+    Closed,
+}
+
+impl Frame {
+    pub async fn send(self, socket: &mut WebSocket) -> Result<(), axum::Error> {
+        match self {
+            Frame::Output(bytes) => socket.send(Message::Binary(bytes)).await,
+            Frame::Exit(info) => socket.send(Message::from(info.to_json())).await,
+            Frame::Command(info) => socket.send(Message::from(info.to_json())).await,
+            Frame::Sync(epoch, replay) => {
+                let payload = serde_json::json!({
+                    "event": "sync",
+                    "value": { "epoch": epoch, "offset": replay.offset, "reset": replay.reset }
+                });
+                socket.send(Message::from(payload.to_string())).await?;
+                socket.send(Message::Binary(Bytes::from(replay.bytes))).await
+            }
+            Frame::Closed => Ok(()),
+        }
+    }
+}
+
+// one client's view of a pty's output; a gap in offsets (or a broadcast lag)
+// is answered by replaying the missing range instead of silently losing it
+pub struct OutputFeed {
+    rx: broadcast::Receiver<PtyEvent>,
+    next: u64,
+    stats: Arc<ClientStats>,
+}
+
+impl OutputFeed {
+    // attach to `pty`, resuming at `from` if the history still covers it
+    pub async fn attach(pty: &PtyManager, from: Option<Position>, stats: Arc<ClientStats>) -> (Self, Frame) {
+        let (rx, replay) = pty.subscribe(from).await;
+        if replay.reset
+            && let Some(from) = from.filter(|p| p.epoch == pty.epoch())
+        {
+            stats.record_lost(replay.offset.saturating_sub(from.offset));
+        }
+        let feed = Self {
+            rx,
+            next: replay.offset,
+            stats,
+        };
+        (feed, Frame::Sync(pty.epoch(), replay))
+    }
+
+    // cancel safe: an interrupted resync shows up again as an offset gap
+    pub async fn next(&mut self, pty: &PtyManager) -> Frame {
+        loop {
+            let event = self.rx.recv().await;
+            if let Some(frame) = self.frame(pty, event).await {
+                return frame;
+            }
+        }
+    }
+
+    // what the pty sent before it closed and the client has not seen yet
+    pub async fn rest(&mut self, pty: &PtyManager) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        loop {
+            let event = match self.rx.try_recv() {
+                Ok(event) => Ok(event),
+                Err(TryRecvError::Lagged(n)) => Err(RecvError::Lagged(n)),
+                Err(_) => return frames,
+            };
+            if let Some(frame) = self.frame(pty, event).await {
+                frames.push(frame);
+            }
+        }
+    }
+
+    // none for output the client already has
+    async fn frame(&mut self, pty: &PtyManager, event: Result<PtyEvent, RecvError>) -> Option<Frame> {
+        let frame = match event {
+            Ok(PtyEvent::Output { offset, data }) => {
+                let end = offset + data.len() as u64;
+                if end <= self.next {
+                    return None;
+                }
+                if offset > self.next {
+                    self.stats.record_lag(1);
+                    return Some(self.resync(pty).await);
+                }
+                let skip = (self.next - offset) as usize;
+                self.next = end;
+                Frame::Output(data.slice(skip..))
+            }
+            Ok(PtyEvent::Exit(info)) => Frame::Exit(info),
+            Ok(PtyEvent::Command(info)) => Frame::Command(info),
+            Err(RecvError::Lagged(n)) => {
+                self.stats.record_lag(n);
+                self.resync(pty).await
+            }
+            Err(RecvError::Closed) => Frame::Closed,
+        };
+        Some(frame)
+    }
+
+    async fn resync(&mut self, pty: &PtyManager) -> Frame {
+        let from = Position {
+            epoch: pty.epoch(),
+            offset: self.next,
+        };
+        let replay = pty.replay(Some(from)).await;
+        if replay.reset {
+            self.stats.record_lost(replay.offset.saturating_sub(self.next));
+        }
+        self.next = replay.offset;
+        Frame::Sync(pty.epoch(), replay)
+    }
+}
@@ -1,4 +1,5 @@
+pub mod feed;
 pub mod socket_handler;
 pub mod socket_handler_debug;
-pub use socket_handler::ws_handler;
-pub use socket_handler_debug::ws_handler_debug;
+pub use socket_handler::{ws_handler, ws_handler_session};
+pub use socket_handler_debug::{DebugShells, ws_handler_debug};
@@ -1,24 +1,69 @@
-use crate::models::{AppState, logger};
+use crate::models::{AppError, AppState, logger};
+use crate::pty::Position;
+use crate::session::{DEFAULT_SESSION, Session};
+use crate::sockets::feed::{Frame, OutputFeed};
+use crate::term::clamp_size;
 use axum::{
     extract::{
-        Extension,
-        ws::{Message, WebSocket, WebSocketUpgrade},
+        Extension, Path, Query,
+        ws::{CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
     },
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use bytes::Bytes;
+use serde::Deserialize;
 use std::sync::Arc;
 use tokio::select;
 
 use crate::models::ClientMsg;
 
-pub async fn ws_handler(ws: WebSocketUpgrade, Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
-    ws.on_upgrade(move |socket| client_session(socket, state))
+// a reconnecting client names the stream it saw and how far it got
+#[derive(Debug, Default, Deserialize)]
+pub struct Resume {
+    epoch: Option<u64>,
+    offset: Option<u64>,
 }
 
-async fn client_session(mut socket: WebSocket, state: Arc<AppState>) {
-    let (mut rx, history) = state.pty.subscribe().await;
-    if let Err(e) = socket.send(Message::Binary(Bytes::from(history.to_vec()))).await {
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Query(resume): Query<Resume>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    ws_attach(ws, state, DEFAULT_SESSION, resume).await
+}
+
+pub async fn ws_handler_session(
+    ws: WebSocketUpgrade,
+    Path(name): Path<String>,
+    Query(resume): Query<Resume>,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    ws_attach(ws, state, &name, resume).await
+}
+
+async fn ws_attach(
+    ws: WebSocketUpgrade,
+    state: Arc<AppState>,
+    name: &str,
+    resume: Resume,
+) -> Result<Response, AppError> {
+    let session = state.sessions.attach(name).await?;
+    Ok(ws
+        .on_upgrade(move |socket| client_session(socket, state, session, resume))
+        .into_response())
+}
+
+async fn client_session(mut socket: WebSocket, state: Arc<AppState>, session: Arc<Session>, resume: Resume) {
+    let client = session.attach();
+    let mut closed = session.pty.closed();
+    let from = match resume {
+        Resume {
+            epoch: Some(epoch),
+            offset: Some(offset),
+        } => Some(Position { epoch, offset }),
+        _ => None,
+    };
+    let (mut feed, sync) = OutputFeed::attach(&session.pty, from, client.stats()).await;
+    if let Err(e) = sync.send(&mut socket).await {
         logger("error", format!("Failed to send history: {}", e));
         return;
     }
@@ -33,11 +78,25 @@ async fn client_session(mut socket: WebSocket, state: Arc<AppState>) {
 
     loop {
         select! {
-            Ok(bytes) = rx.recv() => {
-                socket.send(Message::Binary(Bytes::copy_from_slice(&bytes))).await.ok();
-                if let Some(caster) = &state.caster {
-                    caster.output(state.start.elapsed().as_secs_f32(), bytes.to_vec());
+            frame = feed.next(&session.pty) => {
+                if matches!(frame, Frame::Closed) {
+                    break;
                 }
+                frame.send(&mut socket).await.ok();
+            }
+
+            Ok(()) = closed.changed() => {
+                // output and the exit may still be queued behind the close
+                for frame in feed.rest(&session.pty).await {
+                    frame.send(&mut socket).await.ok();
+                }
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: 1000,
+                        reason: Utf8Bytes::from(format!("session '{}' closed", session.name)),
+                    })))
+                    .await;
+                break;
             }
 
             Ok(()) = cfg_rx.changed() => {
@@ -52,17 +111,17 @@ async fn client_session(mut socket: WebSocket, state: Arc<AppState>) {
             msg = socket.recv() => {
                 match msg {
                     Some(Ok(Message::Text(txt))) => {
-                        if let Ok(cmd) = serde_json::from_str::<ClientMsg>(&txt) {
-                            if handle(cmd, &state, &mut socket).await.is_err() {
-                                break;
-                            }
+                        if let Ok(cmd) = serde_json::from_str::<ClientMsg>(&txt)
+                            && handle(cmd, &session, client.id(), &mut socket).await.is_err()
+                        {
+                            break;
                         }
                     }
                     Some(Ok(Message::Binary(bin))) => {
-                        if let Ok(cmd) = serde_json::from_slice::<ClientMsg>(&bin) {
-                            if handle(cmd, &state, &mut socket).await.is_err() {
-                                break;
-                            }
+                        if let Ok(cmd) = serde_json::from_slice::<ClientMsg>(&bin)
+                            && handle(cmd, &session, client.id(), &mut socket).await.is_err()
+                        {
+                            break;
                         }
                     }
                     Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
@@ -73,28 +132,42 @@ async fn client_session(mut socket: WebSocket, state: Arc<AppState>) {
     }
 }
 
-async fn handle(msg: ClientMsg, state: &AppState, sock: &mut WebSocket) -> anyhow::Result<()> {
+async fn handle(msg: ClientMsg, session: &Session, client: u64, sock: &mut WebSocket) -> anyhow::Result<()> {
     match msg {
         ClientMsg::Data { value } => {
-            if let Some(caster) = &state.caster {
-                caster.input(state.start.elapsed().as_secs_f32(), value.as_bytes().to_vec());
+            if let Some(caster) = &session.caster {
+                // typed at a password prompt: only the length and timing are kept
+                match session.pty.hides_input().await {
+                    true => caster.redacted(session.start.elapsed(), client, value.len()).await,
+                    false => caster.input(session.start.elapsed(), client, value.as_bytes()).await,
+                }
             }
-            state.pty.write(value.as_bytes()).await?;
+            session.pty.write(value.as_bytes()).await?;
         }
         ClientMsg::Resize { value } => {
-            if let Some(caster) = &state.caster {
-                caster.resize(state.start.elapsed().as_secs_f32(), value.rows, value.cols);
+            // every attached client reports the same size; only a change is recorded
+            let (rows, cols) = clamp_size(value.rows, value.cols);
+            if session.pty.resize(rows, cols).await?
+                && let Some(caster) = &session.caster
+            {
+                caster.resize(session.start.elapsed(), rows, cols).await;
             }
-            state.pty.resize(value.rows, value.cols).await?;
-            let mut sz = state.stty_size.write().await;
-            *sz = (value.rows, value.cols);
         }
         ClientMsg::Heartbeat => {
-            if let Some(caster) = &state.caster {
+            if let Some(caster) = &session.caster {
                 caster.heartbeat();
             }
             sock.send(Message::Text(r#"{"event":"heartbeat-pong"}"#.into())).await?;
         }
+        ClientMsg::Respawn => session.pty.respawn(),
+        ClientMsg::Signal { value } => {
+            // a failed signal is reported to the client, it does not end the session
+            let reply = match session.pty.signal(value).await {
+                Ok(pgrp) => serde_json::json!({ "event": "signal", "value": { "signal": value, "pgrp": pgrp } }),
+                Err(e) => serde_json::json!({ "event": "error", "value": format!("signal {:?}: {}", value, e) }),
+            };
+            sock.send(Message::from(reply.to_string())).await?;
+        }
     }
     Ok(())
 }
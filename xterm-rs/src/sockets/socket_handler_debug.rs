@@ -1,63 +1,130 @@
-use crate::models::AppState;
 use crate::models::ClientMsg;
-use crate::pty::PtyManager;
+use crate::models::{AppError, AppState, HistoryLimit, logger};
+use crate::pty::{HistoryOptions, PtyManager};
+use crate::session::ClientStats;
+use crate::sockets::feed::{Frame, OutputFeed};
+use crate::term::clamp_size;
 use axum::{
     extract::{
         Extension,
         ws::{CloseFrame, Message, Utf8Bytes, WebSocket, WebSocketUpgrade},
     },
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
 
-use bytes::Bytes;
-use std::sync::Arc;
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
 
-use tokio::select;
+use tokio::{
+    select,
+    sync::{OwnedSemaphorePermit, Semaphore},
+};
 
-pub async fn ws_handler_debug(ws: WebSocketUpgrade, Extension(state): Extension<Arc<AppState>>) -> impl IntoResponse {
-    let size_lock = Arc::clone(&state.stty_size);
+// how long a debug shell gets to exit after a hangup before it is killed
+const CLOSE_GRACE: Duration = Duration::from_secs(2);
 
-    ws.on_upgrade(move |mut socket| async move {
-        let (rows, cols) = *size_lock.read().await;
-        match PtyManager::new(rows, cols, 0).await {
-            Ok(new_pty) => {
-                let pty = Arc::new(new_pty);
-                debug_session(socket, pty).await;
-            }
-            Err(e) => {
-                let _ = socket
-                    .send(Message::Close(Some(CloseFrame {
-                        code: 1011,
-                        reason: Utf8Bytes::from(format!("pty init error: {e}")),
-                    })))
-                    .await;
-            }
+// every /debug/ws connection gets its own shell, which lives exactly as long as the socket
+pub struct DebugShells {
+    slots: Arc<Semaphore>,
+    max: usize,
+    next_id: AtomicU64,
+}
+
+impl DebugShells {
+    pub fn new(max: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(max)),
+            max,
+            next_id: AtomicU64::new(1),
         }
-    })
+    }
+
+    fn acquire(&self) -> Result<(u64, OwnedSemaphorePermit), AppError> {
+        let permit = Arc::clone(&self.slots)
+            .try_acquire_owned()
+            .map_err(|_| AppError::DebugLimit(self.max))?;
+        Ok((self.next_id.fetch_add(1, Ordering::SeqCst), permit))
+    }
+}
+
+pub async fn ws_handler_debug(
+    ws: WebSocketUpgrade,
+    Extension(state): Extension<Arc<AppState>>,
+) -> Result<Response, AppError> {
+    let (rows, cols) = state.sessions.size();
+    let session = state.sessions.spec();
+    let (id, permit) = state.debug.acquire()?;
+
+    Ok(ws
+        .on_upgrade(move |mut socket| async move {
+            // the slot is released only once the shell is gone
+            let _permit = permit;
+            let history = HistoryOptions {
+                limit: HistoryLimit::Bytes(0),
+                scrollback: 0,
+            };
+            match PtyManager::new(session, rows, cols, history).await {
+                Ok(pty) => {
+                    logger("info", format!("Debug shell {} started", id));
+                    let reason = debug_session(socket, &pty).await;
+                    match pty.close(CLOSE_GRACE).await {
+                        true => logger("info", format!("Debug shell {} closed ({})", id, reason)),
+                        false => logger("error", format!("Debug shell {} did not exit after SIGKILL", id)),
+                    }
+                }
+                Err(e) => {
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: 1011,
+                            reason: Utf8Bytes::from(format!("pty init error: {e}")),
+                        })))
+                        .await;
+                }
+            }
+        })
+        .into_response())
 }
 
-async fn debug_session(mut socket: WebSocket, pty: Arc<PtyManager>) {
-    let (mut rx, history) = pty.subscribe().await;
-    let _ = socket.send(Message::Binary(Bytes::from(history.to_vec()))).await;
+// runs until either side goes away; returns why
+async fn debug_session(mut socket: WebSocket, pty: &PtyManager) -> &'static str {
+    let (mut feed, sync) = OutputFeed::attach(pty, None, Arc::new(ClientStats::new(0))).await;
+    let _ = sync.send(&mut socket).await;
+    let mut closed = pty.closed();
 
     loop {
         select! {
-            Ok(bytes) = rx.recv() => {
-                socket.send(Message::Binary(Bytes::from(bytes))).await.ok();
+            frame = feed.next(pty) => {
+                if matches!(frame, Frame::Closed) {
+                    return "shell exited";
+                }
+                frame.send(&mut socket).await.ok();
+            }
+
+            _ = closed.changed() => {
+                // output and the exit may still be queued behind the close
+                for frame in feed.rest(pty).await {
+                    frame.send(&mut socket).await.ok();
+                }
+                return "shell exited";
             }
 
             msg = socket.recv() => match msg {
                 Some(Ok(Message::Text(txt))) => {
                     if let Ok(cmd) = serde_json::from_str::<ClientMsg>(&txt) {
-                        apply_cmd(cmd, &pty).await;
+                        apply_cmd(cmd, pty).await;
                     }
                 }
                 Some(Ok(Message::Binary(bin))) => {
                     if let Ok(cmd) = serde_json::from_slice::<ClientMsg>(&bin) {
-                        apply_cmd(cmd, &pty).await;
+                        apply_cmd(cmd, pty).await;
                     }
                 }
-                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => break,
+                Some(Ok(Message::Close(_))) | None | Some(Err(_)) => return "client disconnected",
                 _ => {}
             }
         }
@@ -70,7 +137,12 @@ async fn apply_cmd(cmd: ClientMsg, pty: &PtyManager) {
             let _ = pty.write(value.as_bytes()).await;
         }
         ClientMsg::Resize { value } => {
-            let _ = pty.resize(value.rows, value.cols).await;
+            let (rows, cols) = clamp_size(value.rows, value.cols);
+            let _ = pty.resize(rows, cols).await;
+        }
+        ClientMsg::Respawn => pty.respawn(),
+        ClientMsg::Signal { value } => {
+            let _ = pty.signal(value).await;
         }
         _ => {}
     }
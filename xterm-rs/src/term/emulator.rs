@@ -0,0 +1,604 @@
+use crate::term::grid::{self, Attrs, Cell, Color, Row};
+use crate::term::screen::{Cursor, Screen};
+use std::collections::VecDeque;
+use unicode_width::UnicodeWidthChar;
+use vte::{Params, Perform};
+
+const TAB_WIDTH: usize = 8;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Charset {
+    #[default]
+    Ascii,
+    // dec special graphics (line drawing)
+    LineDrawing,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Modes {
+    pub app_cursor: bool,
+    pub app_keypad: bool,
+    pub origin: bool,
+    pub autowrap: bool,
+    pub insert: bool,
+    pub cursor_visible: bool,
+    pub cursor_style: u16,
+    pub bracketed_paste: bool,
+    pub focus_events: bool,
+    // 0, 9, 1000, 1002 or 1003
+    pub mouse: u16,
+    // 0, 1005, 1006 or 1015
+    pub mouse_encoding: u16,
+}
+
+impl Default for Modes {
+    fn default() -> Self {
+        Self {
+            app_cursor: false,
+            app_keypad: false,
+            origin: false,
+            autowrap: true,
+            insert: false,
+            cursor_visible: true,
+            cursor_style: 0,
+            bracketed_paste: false,
+            focus_events: false,
+            mouse: 0,
+            mouse_encoding: 0,
+        }
+    }
+}
+
+// the screen state a fresh xterm.js would reach after the whole output stream
+pub struct Term {
+    pub primary: Screen,
+    pub alternate: Screen,
+    pub alt_active: bool,
+    pub scrollback: VecDeque<Row>,
+    pub scrollback_limit: usize,
+    pub modes: Modes,
+    pub title: String,
+    pub charsets: [Charset; 2],
+    pub active_charset: usize,
+    last_char: Option<char>,
+}
+
+impl Term {
+    pub fn new(rows: usize, cols: usize, scrollback_limit: usize) -> Self {
+        Self {
+            primary: Screen::new(rows, cols),
+            alternate: Screen::new(rows, cols),
+            alt_active: false,
+            scrollback: VecDeque::new(),
+            scrollback_limit,
+            modes: Modes::default(),
+            title: String::new(),
+            charsets: [Charset::Ascii; 2],
+            active_charset: 0,
+            last_char: None,
+        }
+    }
+
+    pub fn screen(&self) -> &Screen {
+        if self.alt_active {
+            &self.alternate
+        } else {
+            &self.primary
+        }
+    }
+
+    fn screen_mut(&mut self) -> &mut Screen {
+        if self.alt_active {
+            &mut self.alternate
+        } else {
+            &mut self.primary
+        }
+    }
+
+    fn cursor(&mut self) -> &mut Cursor {
+        &mut self.screen_mut().cursor
+    }
+
+    pub fn resize(&mut self, rows: usize, cols: usize) {
+        let out = self.primary.resize(rows, cols);
+        self.push_scrollback(out);
+        self.alternate.resize(rows, cols);
+        for row in &mut self.scrollback {
+            row.resize(cols);
+        }
+    }
+
+    fn push_scrollback(&mut self, rows: Vec<Row>) {
+        if self.scrollback_limit == 0 {
+            return;
+        }
+        for row in rows {
+            if self.scrollback.len() == self.scrollback_limit {
+                self.scrollback.pop_front();
+            }
+            self.scrollback.push_back(row);
+        }
+    }
+
+    fn scroll_up(&mut self, n: usize) {
+        let screen = self.screen_mut();
+        let to_scrollback = screen.top == 0;
+        let out = screen.scroll_up(n);
+        if to_scrollback && !self.alt_active {
+            self.push_scrollback(out);
+        }
+    }
+
+    fn linefeed(&mut self) {
+        let screen = self.screen_mut();
+        screen.cursor.pending_wrap = false;
+        if screen.cursor.row == screen.bottom {
+            self.scroll_up(1);
+        } else if screen.cursor.row + 1 < screen.height() {
+            screen.cursor.row += 1;
+        }
+    }
+
+    fn reverse_index(&mut self) {
+        let screen = self.screen_mut();
+        screen.cursor.pending_wrap = false;
+        if screen.cursor.row == screen.top {
+            screen.scroll_down(1);
+        } else {
+            screen.cursor.row = screen.cursor.row.saturating_sub(1);
+        }
+    }
+
+    fn wrap(&mut self) {
+        let screen = self.screen_mut();
+        let row = screen.cursor.row;
+        screen.rows[row].wrapped = true;
+        screen.cursor.col = 0;
+        self.linefeed();
+    }
+
+    // first and last row the cursor may move to vertically
+    fn vertical_bounds(&self) -> (usize, usize) {
+        let screen = self.screen();
+        let row = screen.cursor.row;
+        if self.modes.origin || (row >= screen.top && row <= screen.bottom) {
+            (screen.top, screen.bottom)
+        } else {
+            (0, screen.height() - 1)
+        }
+    }
+
+    fn goto(&mut self, row: usize, col: usize) {
+        let origin = self.modes.origin;
+        let screen = self.screen_mut();
+        let (top, bottom) = if origin {
+            (screen.top, screen.bottom)
+        } else {
+            (0, screen.height() - 1)
+        };
+        screen.cursor.row = (top + row).min(bottom);
+        screen.cursor.col = col.min(screen.cols - 1);
+        screen.cursor.pending_wrap = false;
+    }
+
+    fn set_row(&mut self, row: usize) {
+        let col = self.screen().cursor.col;
+        self.goto(row, col);
+    }
+
+    fn move_vertical(&mut self, delta: isize) {
+        let (top, bottom) = self.vertical_bounds();
+        let cursor = self.cursor();
+        cursor.row = cursor.row.saturating_add_signed(delta).clamp(top, bottom);
+        cursor.pending_wrap = false;
+    }
+
+    fn set_col(&mut self, col: usize) {
+        let screen = self.screen_mut();
+        screen.cursor.col = col.min(screen.cols - 1);
+        screen.cursor.pending_wrap = false;
+    }
+
+    fn save_cursor(&mut self) {
+        let screen = self.screen_mut();
+        screen.saved = Some(screen.cursor);
+    }
+
+    fn restore_cursor(&mut self) {
+        let screen = self.screen_mut();
+        screen.cursor = screen.saved.unwrap_or_default();
+    }
+
+    fn enter_alternate(&mut self, save: bool) {
+        if self.alt_active {
+            return;
+        }
+        if save {
+            self.save_cursor();
+        }
+        self.alternate.cursor = self.primary.cursor;
+        self.alt_active = true;
+        self.alternate.clear();
+    }
+
+    fn leave_alternate(&mut self, restore: bool) {
+        if !self.alt_active {
+            return;
+        }
+        self.alt_active = false;
+        if restore {
+            self.restore_cursor();
+        }
+    }
+
+    fn reset(&mut self) {
+        let (rows, cols) = (self.primary.height(), self.primary.cols);
+        *self = Self::new(rows, cols, self.scrollback_limit);
+    }
+
+    fn put_char(&mut self, c: char) {
+        let c = match self.charsets[self.active_charset] {
+            Charset::LineDrawing => line_drawing(c),
+            Charset::Ascii => c,
+        };
+        // combining marks are dropped; the snapshot only needs the base char
+        let width = match c.width() {
+            Some(w @ 1..=2) => w,
+            _ => return,
+        };
+        self.last_char = Some(c);
+
+        let (autowrap, insert) = (self.modes.autowrap, self.modes.insert);
+        if self.screen().cursor.pending_wrap && autowrap {
+            self.wrap();
+        }
+        let cols = self.screen().cols;
+        if width == 2 && self.screen().cursor.col + 1 >= cols {
+            if !autowrap || cols < 2 {
+                return;
+            }
+            let row = self.screen().cursor.row;
+            self.screen_mut().erase_cells(row, cols - 1, cols);
+            self.wrap();
+        }
+        if insert {
+            self.screen_mut().insert_chars(width);
+        }
+
+        let screen = self.screen_mut();
+        let Cursor { row, col, attrs, .. } = screen.cursor;
+        let cells = &mut screen.rows[row].cells;
+        // never leave half of a wide char behind
+        if cells[col].width == 0 && col > 0 {
+            cells[col - 1] = Cell::blank(attrs);
+        }
+        if col + width < cols && cells[col + width].width == 0 {
+            cells[col + width] = Cell::blank(attrs);
+        }
+        if width == 1 && cells[col].width == 2 && col + 1 < cols {
+            cells[col + 1] = Cell::blank(attrs);
+        }
+        cells[col] = Cell {
+            ch: c,
+            width: width as u8,
+            attrs,
+        };
+        if width == 2 {
+            cells[col + 1] = Cell {
+                ch: ' ',
+                width: 0,
+                attrs,
+            };
+        }
+
+        if col + width >= cols {
+            screen.cursor.col = cols - 1;
+            screen.cursor.pending_wrap = autowrap;
+        } else {
+            screen.cursor.col = col + width;
+        }
+    }
+
+    fn set_private_mode(&mut self, mode: u16, on: bool) {
+        match mode {
+            1 => self.modes.app_cursor = on,
+            6 => {
+                self.modes.origin = on;
+                self.goto(0, 0);
+            }
+            7 => self.modes.autowrap = on,
+            25 => self.modes.cursor_visible = on,
+            47 | 1047 if on => self.enter_alternate(false),
+            47 | 1047 => self.leave_alternate(false),
+            1048 if on => self.save_cursor(),
+            1048 => self.restore_cursor(),
+            1049 if on => self.enter_alternate(true),
+            1049 => self.leave_alternate(true),
+            66 => self.modes.app_keypad = on,
+            9 | 1000 | 1002 | 1003 if on => self.modes.mouse = mode,
+            9 | 1000 | 1002 | 1003 if self.modes.mouse == mode => self.modes.mouse = 0,
+            1005 | 1006 | 1015 if on => self.modes.mouse_encoding = mode,
+            1005 | 1006 | 1015 if self.modes.mouse_encoding == mode => self.modes.mouse_encoding = 0,
+            1004 => self.modes.focus_events = on,
+            2004 => self.modes.bracketed_paste = on,
+            _ => {}
+        }
+    }
+
+    fn set_margins(&mut self, top: usize, bottom: usize) {
+        let screen = self.screen_mut();
+        let top = top.saturating_sub(1);
+        let bottom = match bottom {
+            0 => screen.height() - 1,
+            b => (b - 1).min(screen.height() - 1),
+        };
+        if top < bottom {
+            screen.top = top;
+            screen.bottom = bottom;
+            self.goto(0, 0);
+        }
+    }
+
+    fn erase_display(&mut self, mode: u16) {
+        let screen = self.screen_mut();
+        let Cursor { row, col, .. } = screen.cursor;
+        match mode {
+            0 => {
+                screen.erase_cells(row, col, screen.cols);
+                screen.erase_rows(row + 1, screen.height());
+            }
+            1 => {
+                screen.erase_rows(0, row);
+                screen.erase_cells(row, 0, col + 1);
+            }
+            2 => screen.erase_rows(0, screen.height()),
+            3 => self.scrollback.clear(),
+            _ => {}
+        }
+    }
+
+    fn erase_line(&mut self, mode: u16) {
+        let screen = self.screen_mut();
+        let Cursor { row, col, .. } = screen.cursor;
+        match mode {
+            0 => screen.erase_cells(row, col, screen.cols),
+            1 => screen.erase_cells(row, 0, col + 1),
+            2 => screen.erase_cells(row, 0, screen.cols),
+            _ => {}
+        }
+    }
+
+    fn sgr(&mut self, params: &Params) {
+        let mut attrs = self.screen().cursor.attrs;
+        let mut iter = params.iter();
+        while let Some(param) = iter.next() {
+            match param[0] {
+                0 => attrs = Attrs::default(),
+                1 => attrs.flags |= grid::BOLD,
+                2 => attrs.flags |= grid::DIM,
+                3 => attrs.flags |= grid::ITALIC,
+                4 => match param.get(1) {
+                    Some(0) => attrs.flags &= !grid::UNDERLINE,
+                    _ => attrs.flags |= grid::UNDERLINE,
+                },
+                5 | 6 => attrs.flags |= grid::BLINK,
+                7 => attrs.flags |= grid::INVERSE,
+                8 => attrs.flags |= grid::HIDDEN,
+                9 => attrs.flags |= grid::STRIKE,
+                21 => attrs.flags |= grid::UNDERLINE,
+                22 => attrs.flags &= !(grid::BOLD | grid::DIM),
+                23 => attrs.flags &= !grid::ITALIC,
+                24 => attrs.flags &= !grid::UNDERLINE,
+                25 => attrs.flags &= !grid::BLINK,
+                27 => attrs.flags &= !grid::INVERSE,
+                28 => attrs.flags &= !grid::HIDDEN,
+                29 => attrs.flags &= !grid::STRIKE,
+                n @ 30..=37 => attrs.fg = Color::Indexed(n as u8 - 30),
+                38 => attrs.fg = extended_color(param, &mut iter).unwrap_or(attrs.fg),
+                39 => attrs.fg = Color::Default,
+                n @ 40..=47 => attrs.bg = Color::Indexed(n as u8 - 40),
+                48 => attrs.bg = extended_color(param, &mut iter).unwrap_or(attrs.bg),
+                49 => attrs.bg = Color::Default,
+                n @ 90..=97 => attrs.fg = Color::Indexed(n as u8 - 90 + 8),
+                n @ 100..=107 => attrs.bg = Color::Indexed(n as u8 - 100 + 8),
+                _ => {}
+            }
+        }
+        self.cursor().attrs = attrs;
+    }
+}
+
+// `38;5;n`, `38;2;r;g;b` and their colon forms
+fn extended_color<'a>(param: &[u16], iter: &mut impl Iterator<Item = &'a [u16]>) -> Option<Color> {
+    let mut values: Vec<u16> = param[1..].to_vec();
+    if values.is_empty() {
+        values.push(*iter.next()?.first()?);
+        let n = if values[0] == 2 { 3 } else { 1 };
+        for _ in 0..n {
+            values.push(*iter.next()?.first()?);
+        }
+    }
+    match values.as_slice() {
+        [5, n, ..] => Some(Color::Indexed(*n as u8)),
+        // colon form may carry a colorspace id before the components
+        [2, _, r, g, b] | [2, r, g, b] => Some(Color::Rgb(*r as u8, *g as u8, *b as u8)),
+        _ => None,
+    }
+}
+
+fn line_drawing(c: char) -> char {
+    match c {
+        '`' => '◆',
+        'a' => '▒',
+        'f' => '°',
+        'g' => '±',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        c => c,
+    }
+}
+
+impl Perform for Term {
+    fn print(&mut self, c: char) {
+        self.put_char(c);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            0x08 => {
+                let cursor = self.cursor();
+                cursor.col = cursor.col.saturating_sub(1);
+                cursor.pending_wrap = false;
+            }
+            0x09 => {
+                let col = self.screen().cursor.col;
+                self.set_col((col / TAB_WIDTH + 1) * TAB_WIDTH);
+            }
+            0x0a..=0x0c => self.linefeed(),
+            0x0d => self.set_col(0),
+            0x0e => self.active_charset = 1,
+            0x0f => self.active_charset = 0,
+            _ => {}
+        }
+    }
+
+    fn csi_dispatch(&mut self, params: &Params, intermediates: &[u8], ignore: bool, action: char) {
+        if ignore {
+            return;
+        }
+        let args: Vec<u16> = params.iter().map(|p| p[0]).collect();
+        let arg = |i: usize| args.get(i).copied().unwrap_or(0) as usize;
+        // most sequences treat a missing or zero count as one
+        let count = arg(0).max(1);
+
+        match (intermediates, action) {
+            ([], '@') => self.screen_mut().insert_chars(count),
+            ([], 'A') => self.move_vertical(-(count as isize)),
+            ([], 'B' | 'e') => self.move_vertical(count as isize),
+            ([], 'C' | 'a') => {
+                let col = self.screen().cursor.col;
+                self.set_col(col + count);
+            }
+            ([], 'D') => {
+                let col = self.screen().cursor.col;
+                self.set_col(col.saturating_sub(count));
+            }
+            ([], 'E') => {
+                self.move_vertical(count as isize);
+                self.set_col(0);
+            }
+            ([], 'F') => {
+                self.move_vertical(-(count as isize));
+                self.set_col(0);
+            }
+            ([], 'G' | '`') => self.set_col(count - 1),
+            ([], 'H' | 'f') => self.goto(count - 1, arg(1).max(1) - 1),
+            ([], 'd') => self.set_row(count - 1),
+            ([], 'J') => self.erase_display(arg(0) as u16),
+            ([], 'K') => self.erase_line(arg(0) as u16),
+            ([], 'L') => {
+                self.screen_mut().insert_lines(count);
+                self.set_col(0);
+            }
+            ([], 'M') => {
+                self.screen_mut().delete_lines(count);
+                self.set_col(0);
+            }
+            ([], 'P') => self.screen_mut().delete_chars(count),
+            ([], 'X') => {
+                let screen = self.screen_mut();
+                let Cursor { row, col, .. } = screen.cursor;
+                screen.erase_cells(row, col, col + count);
+            }
+            ([], 'S') => self.scroll_up(count),
+            ([], 'T') if args.len() <= 1 => self.screen_mut().scroll_down(count),
+            ([], 'b') => {
+                if let Some(c) = self.last_char {
+                    for _ in 0..count.min(u16::MAX as usize) {
+                        self.put_char(c);
+                    }
+                }
+            }
+            ([], 'm') => self.sgr(params),
+            ([], 'r') => self.set_margins(arg(0), arg(1)),
+            ([], 's') => self.save_cursor(),
+            ([], 'u') => self.restore_cursor(),
+            ([], 'h' | 'l') => {
+                for mode in &args {
+                    if *mode == 4 {
+                        self.modes.insert = action == 'h';
+                    }
+                }
+            }
+            ([b'?'], 'h' | 'l') => {
+                for mode in &args {
+                    self.set_private_mode(*mode, action == 'h');
+                }
+            }
+            ([b' '], 'q') => self.modes.cursor_style = arg(0) as u16,
+            ([b'!'], 'p') => {
+                self.modes = Modes::default();
+                self.charsets = [Charset::Ascii; 2];
+                self.active_charset = 0;
+                let screen = self.screen_mut();
+                screen.cursor.attrs = Attrs::default();
+                screen.cursor.pending_wrap = false;
+                screen.saved = None;
+                screen.top = 0;
+                screen.bottom = screen.height() - 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], ignore: bool, byte: u8) {
+        if ignore {
+            return;
+        }
+        match (intermediates, byte) {
+            ([], b'7') => self.save_cursor(),
+            ([], b'8') => self.restore_cursor(),
+            ([], b'D') => self.linefeed(),
+            ([], b'E') => {
+                self.linefeed();
+                self.set_col(0);
+            }
+            ([], b'M') => self.reverse_index(),
+            ([], b'c') => self.reset(),
+            ([], b'=') => self.modes.app_keypad = true,
+            ([], b'>') => self.modes.app_keypad = false,
+            ([g @ (b'(' | b')')], set) => {
+                self.charsets[(*g == b')') as usize] = match set {
+                    b'0' => Charset::LineDrawing,
+                    _ => Charset::Ascii,
+                };
+            }
+            _ => {}
+        }
+    }
+
+    fn osc_dispatch(&mut self, params: &[&[u8]], _bell_terminated: bool) {
+        if let [b"0" | b"2", title, ..] = params {
+            self.title = String::from_utf8_lossy(title).into_owned();
+        }
+    }
+}
@@ -0,0 +1,97 @@
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Color {
+    #[default]
+    Default,
+    Indexed(u8),
+    Rgb(u8, u8, u8),
+}
+
+pub const BOLD: u16 = 1;
+pub const DIM: u16 = 1 << 1;
+pub const ITALIC: u16 = 1 << 2;
+pub const UNDERLINE: u16 = 1 << 3;
+pub const BLINK: u16 = 1 << 4;
+pub const INVERSE: u16 = 1 << 5;
+pub const HIDDEN: u16 = 1 << 6;
+pub const STRIKE: u16 = 1 << 7;
+
+// sgr parameter for each flag, in flag order
+pub const FLAG_SGR: [(u16, u8); 8] = [
+    (BOLD, 1),
+    (DIM, 2),
+    (ITALIC, 3),
+    (UNDERLINE, 4),
+    (BLINK, 5),
+    (INVERSE, 7),
+    (HIDDEN, 8),
+    (STRIKE, 9),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Attrs {
+    pub fg: Color,
+    pub bg: Color,
+    pub flags: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: char,
+    // 2 for the left half of a wide char, 0 for its right half
+    pub width: u8,
+    pub attrs: Attrs,
+}
+
+impl Cell {
+    // erased cells keep the background of the pen that erased them
+    pub fn blank(attrs: Attrs) -> Self {
+        Self {
+            ch: ' ',
+            width: 1,
+            attrs: Attrs {
+                bg: attrs.bg,
+                ..Attrs::default()
+            },
+        }
+    }
+
+    pub fn is_blank(&self) -> bool {
+        self.ch == ' ' && self.width == 1 && self.attrs == Attrs::default()
+    }
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Self::blank(Attrs::default())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Row {
+    pub cells: Vec<Cell>,
+    // the last cell auto-wrapped into the next row
+    pub wrapped: bool,
+}
+
+impl Row {
+    pub fn new(cols: usize, attrs: Attrs) -> Self {
+        Self {
+            cells: vec![Cell::blank(attrs); cols],
+            wrapped: false,
+        }
+    }
+
+    pub fn resize(&mut self, cols: usize) {
+        self.cells.resize(cols, Cell::default());
+        if let Some(last) = self.cells.last_mut()
+            && last.width == 2
+        {
+            *last = Cell::default();
+        }
+    }
+
+    // number of cells worth rendering; trailing default blanks are dropped
+    pub fn used(&self) -> usize {
+        self.cells.iter().rposition(|c| !c.is_blank()).map_or(0, |i| i + 1)
+    }
+}
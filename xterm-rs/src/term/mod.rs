@@ -0,0 +1,60 @@
+mod emulator;
+mod grid;
+mod render;
+mod screen;
+
+use vte::Parser;
+
+// largest rows or cols a client may ask for; the screen is allocated in full, so this bounds memory
+pub const MAX_SIZE: u16 = 1000;
+
+// a size from a client, made one the pty and the emulator can take
+pub fn clamp_size(rows: u16, cols: u16) -> (u16, u16) {
+    (rows.clamp(1, MAX_SIZE), cols.clamp(1, MAX_SIZE))
+}
+
+// parses the pty output into a screen model so new clients get the current
+// screen instead of a replay of raw history
+pub struct Emulator {
+    parser: Parser,
+    term: emulator::Term,
+}
+
+impl Emulator {
+    pub fn new(rows: u16, cols: u16, scrollback: usize) -> Self {
+        let (rows, cols) = clamp_size(rows, cols);
+        Self {
+            parser: Parser::new(),
+            term: emulator::Term::new(rows.into(), cols.into(), scrollback),
+        }
+    }
+
+    pub fn advance(&mut self, bytes: &[u8]) {
+        self.parser.advance(&mut self.term, bytes);
+    }
+
+    pub fn resize(&mut self, rows: u16, cols: u16) {
+        let (rows, cols) = clamp_size(rows, cols);
+        self.term.resize(rows.into(), cols.into());
+    }
+
+    pub fn snapshot(&self) -> Vec<u8> {
+        render::snapshot(&self.term)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_clamped() {
+        assert_eq!(clamp_size(24, 80), (24, 80));
+        assert_eq!(clamp_size(0, u16::MAX), (1, MAX_SIZE));
+        // a resize to the largest size a client can send stays small enough to allocate
+        let mut emulator = Emulator::new(24, 80, 0);
+        emulator.resize(u16::MAX, u16::MAX);
+        emulator.advance(b"x");
+        assert!(!emulator.snapshot().is_empty());
+    }
+}
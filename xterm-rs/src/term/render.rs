@@ -0,0 +1,149 @@
+use crate::term::emulator::{Charset, Term};
+use crate::term::grid::{Attrs, Color, FLAG_SGR, Row};
+use crate::term::screen::Screen;
+use std::fmt::Write;
+
+// escape sequences that take a fresh terminal to the emulator's state
+pub fn snapshot(term: &Term) -> Vec<u8> {
+    let mut out = String::new();
+    let mut pen = Attrs::default();
+
+    // primary screen, with scrollback rows pushed off the top as they are printed
+    let primary = &term.primary;
+    let rows: Vec<&Row> = term.scrollback.iter().chain(primary.rows.iter()).collect();
+    for (i, row) in rows.iter().enumerate() {
+        let soft = row.wrapped && row.used() == primary.cols;
+        render_row(&mut out, &mut pen, row, primary.cols, soft);
+        if i + 1 < rows.len() && !soft {
+            set_pen(&mut out, &mut pen, Attrs::default());
+            out.push_str("\r\n");
+        }
+    }
+
+    if term.alt_active {
+        restore_cursor(&mut out, &mut pen, primary, false);
+        out.push_str("\x1b[?1049h");
+        let alt = &term.alternate;
+        for (i, row) in alt.rows.iter().enumerate().filter(|(_, r)| r.used() > 0) {
+            let _ = write!(out, "\x1b[{};1H", i + 1);
+            render_row(&mut out, &mut pen, row, alt.cols, false);
+        }
+    }
+
+    let screen = term.screen();
+    if let Some(saved) = screen.saved {
+        move_to(&mut out, saved.row, saved.col);
+        set_pen(&mut out, &mut pen, saved.attrs);
+        out.push_str("\x1b7");
+    }
+    if !screen.full_region() {
+        let _ = write!(out, "\x1b[{};{}r", screen.top + 1, screen.bottom + 1);
+    }
+    write_modes(&mut out, term);
+    restore_cursor(&mut out, &mut pen, screen, term.modes.origin);
+    out.push_str(&sgr(screen.cursor.attrs));
+
+    for (g, set) in term.charsets.iter().enumerate() {
+        if *set == Charset::LineDrawing {
+            out.push_str(if g == 0 { "\x1b(0" } else { "\x1b)0" });
+        }
+    }
+    if term.active_charset == 1 {
+        out.push('\x0e');
+    }
+    if !term.title.is_empty() {
+        let _ = write!(out, "\x1b]2;{}\x07", term.title);
+    }
+    out.into_bytes()
+}
+
+fn render_row(out: &mut String, pen: &mut Attrs, row: &Row, cols: usize, full: bool) {
+    let used = if full { cols } else { row.used() };
+    for cell in &row.cells[..used.min(row.cells.len())] {
+        if cell.width == 0 {
+            continue;
+        }
+        set_pen(out, pen, cell.attrs);
+        out.push(cell.ch);
+    }
+}
+
+fn restore_cursor(out: &mut String, pen: &mut Attrs, screen: &Screen, origin: bool) {
+    let cursor = screen.cursor;
+    let row = if origin { cursor.row - screen.top } else { cursor.row };
+    let cell = screen.rows[cursor.row].cells[cursor.col];
+    if cursor.pending_wrap && cell.width == 1 {
+        // reprint the last cell so the next char wraps like it would have
+        move_to(out, row, cursor.col);
+        set_pen(out, pen, cell.attrs);
+        out.push(cell.ch);
+    } else {
+        move_to(out, row, cursor.col);
+    }
+}
+
+fn move_to(out: &mut String, row: usize, col: usize) {
+    let _ = write!(out, "\x1b[{};{}H", row + 1, col + 1);
+}
+
+fn write_modes(out: &mut String, term: &Term) {
+    let modes = &term.modes;
+    let private = [
+        (1, modes.app_cursor),
+        (6, modes.origin),
+        (1004, modes.focus_events),
+        (2004, modes.bracketed_paste),
+        (modes.mouse, modes.mouse != 0),
+        (modes.mouse_encoding, modes.mouse_encoding != 0),
+    ];
+    for (mode, on) in private {
+        if on {
+            let _ = write!(out, "\x1b[?{}h", mode);
+        }
+    }
+    if !modes.autowrap {
+        out.push_str("\x1b[?7l");
+    }
+    if !modes.cursor_visible {
+        out.push_str("\x1b[?25l");
+    }
+    if modes.insert {
+        out.push_str("\x1b[4h");
+    }
+    if modes.app_keypad {
+        out.push_str("\x1b=");
+    }
+    if modes.cursor_style != 0 {
+        let _ = write!(out, "\x1b[{} q", modes.cursor_style);
+    }
+}
+
+fn set_pen(out: &mut String, pen: &mut Attrs, attrs: Attrs) {
+    if *pen != attrs {
+        out.push_str(&sgr(attrs));
+        *pen = attrs;
+    }
+}
+
+fn sgr(attrs: Attrs) -> String {
+    let mut s = String::from("\x1b[0");
+    for (flag, n) in FLAG_SGR {
+        if attrs.flags & flag != 0 {
+            let _ = write!(s, ";{}", n);
+        }
+    }
+    push_color(&mut s, attrs.fg, 30);
+    push_color(&mut s, attrs.bg, 40);
+    s.push('m');
+    s
+}
+
+fn push_color(s: &mut String, color: Color, base: u8) {
+    let _ = match color {
+        Color::Default => Ok(()),
+        Color::Indexed(n) if n < 8 => write!(s, ";{}", base + n),
+        Color::Indexed(n) if n < 16 => write!(s, ";{}", base + 60 + n - 8),
+        Color::Indexed(n) => write!(s, ";{};5;{}", base + 8, n),
+        Color::Rgb(r, g, b) => write!(s, ";{};2;{};{};{}", base + 8, r, g, b),
+    };
+}
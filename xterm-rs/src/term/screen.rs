@@ -0,0 +1,161 @@
+use crate::term::grid::{Attrs, Cell, Row};
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Cursor {
+    pub row: usize,
+    pub col: usize,
+    pub attrs: Attrs,
+    // the last column was written and the next print wraps first
+    pub pending_wrap: bool,
+}
+
+// one of the two screens (primary or alternate)
+#[derive(Debug, Clone)]
+pub struct Screen {
+    pub rows: Vec<Row>,
+    pub cols: usize,
+    pub cursor: Cursor,
+    pub saved: Option<Cursor>,
+    // scroll region, inclusive
+    pub top: usize,
+    pub bottom: usize,
+}
+
+impl Screen {
+    pub fn new(rows: usize, cols: usize) -> Self {
+        Self {
+            rows: (0..rows).map(|_| Row::new(cols, Attrs::default())).collect(),
+            cols,
+            cursor: Cursor::default(),
+            saved: None,
+            top: 0,
+            bottom: rows.saturating_sub(1),
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn full_region(&self) -> bool {
+        self.top == 0 && self.bottom + 1 == self.height()
+    }
+
+    pub fn clear(&mut self) {
+        let attrs = self.cursor.attrs;
+        for row in &mut self.rows {
+            *row = Row::new(self.cols, attrs);
+        }
+    }
+
+    // scroll the region up; rows leaving the top of the screen are returned
+    pub fn scroll_up(&mut self, n: usize) -> Vec<Row> {
+        let n = n.min(self.bottom + 1 - self.top);
+        let blank = Row::new(self.cols, self.cursor.attrs);
+        let out: Vec<Row> = self.rows.drain(self.top..self.top + n).collect();
+        for _ in 0..n {
+            self.rows.insert(self.bottom + 1 - n, blank.clone());
+        }
+        out
+    }
+
+    pub fn scroll_down(&mut self, n: usize) {
+        let n = n.min(self.bottom + 1 - self.top);
+        let blank = Row::new(self.cols, self.cursor.attrs);
+        self.rows.drain(self.bottom + 1 - n..=self.bottom);
+        for _ in 0..n {
+            self.rows.insert(self.top, blank.clone());
+        }
+    }
+
+    pub fn insert_lines(&mut self, n: usize) {
+        let row = self.cursor.row;
+        if row < self.top || row > self.bottom {
+            return;
+        }
+        let n = n.min(self.bottom + 1 - row);
+        let blank = Row::new(self.cols, self.cursor.attrs);
+        self.rows.drain(self.bottom + 1 - n..=self.bottom);
+        for _ in 0..n {
+            self.rows.insert(row, blank.clone());
+        }
+    }
+
+    pub fn delete_lines(&mut self, n: usize) {
+        let row = self.cursor.row;
+        if row < self.top || row > self.bottom {
+            return;
+        }
+        let n = n.min(self.bottom + 1 - row);
+        let blank = Row::new(self.cols, self.cursor.attrs);
+        self.rows.drain(row..row + n);
+        for _ in 0..n {
+            self.rows.insert(self.bottom + 1 - n, blank.clone());
+        }
+    }
+
+    pub fn insert_chars(&mut self, n: usize) {
+        let Cursor { row, col, attrs, .. } = self.cursor;
+        let cells = &mut self.rows[row].cells;
+        let n = n.min(self.cols - col);
+        cells.truncate(self.cols - n);
+        for _ in 0..n {
+            cells.insert(col, Cell::blank(attrs));
+        }
+    }
+
+    pub fn delete_chars(&mut self, n: usize) {
+        let Cursor { row, col, attrs, .. } = self.cursor;
+        let cells = &mut self.rows[row].cells;
+        let n = n.min(self.cols - col);
+        cells.drain(col..col + n);
+        cells.resize(self.cols, Cell::blank(attrs));
+    }
+
+    pub fn erase_cells(&mut self, row: usize, from: usize, to: usize) {
+        let attrs = self.cursor.attrs;
+        let to = to.min(self.cols);
+        for cell in &mut self.rows[row].cells[from.min(to)..to] {
+            *cell = Cell::blank(attrs);
+        }
+        if to == self.cols {
+            self.rows[row].wrapped = false;
+        }
+    }
+
+    pub fn erase_rows(&mut self, from: usize, to: usize) {
+        for row in from..to.min(self.height()) {
+            self.erase_cells(row, 0, self.cols);
+        }
+    }
+
+    // keep the cursor row visible; rows pushed off the top are returned
+    pub fn resize(&mut self, rows: usize, cols: usize) -> Vec<Row> {
+        let mut out = Vec::new();
+        for row in &mut self.rows {
+            row.resize(cols);
+        }
+        self.cols = cols;
+        while self.height() > rows {
+            if self.cursor.row + 1 < self.height() && self.rows.last().is_some_and(|r| r.used() == 0) {
+                self.rows.pop();
+            } else {
+                out.push(self.rows.remove(0));
+                self.cursor.row = self.cursor.row.saturating_sub(1);
+            }
+        }
+        while self.height() < rows {
+            self.rows.push(Row::new(cols, Attrs::default()));
+        }
+        self.top = 0;
+        self.bottom = rows.saturating_sub(1);
+        self.cursor.row = self.cursor.row.min(rows.saturating_sub(1));
+        self.cursor.col = self.cursor.col.min(cols.saturating_sub(1));
+        self.cursor.pending_wrap = false;
+        if let Some(saved) = &mut self.saved {
+            saved.row = saved.row.min(rows.saturating_sub(1));
+            saved.col = saved.col.min(cols.saturating_sub(1));
+        }
+        out
+    }
+}
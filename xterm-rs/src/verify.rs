@@ -0,0 +1,336 @@
+use crate::caster::{crypt, heartbeat, rotate, seal};
+use anyhow::{Context, bail};
+use clap::ValueHint;
+use std::{
+    collections::BTreeMap,
+    fs::{self, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    #[arg(
+        required = true,
+        value_hint = ValueHint::AnyPath,
+        long_help = "Recordings to check (.cast or .cast.zst), heartbeat logs, or directories of them\nThe segments of a recording and rotated heartbeat logs are also checked to follow each other"
+    )]
+    files: Vec<PathBuf>,
+
+    #[arg(
+        short,
+        long,
+        value_name = "KEY",
+        long_help = "Public key the server signed with, as hex or a file holding it (the .pub from keygen)\nWithout it only the hash chain is checked, which anyone could have recomputed"
+    )]
+    key: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct KeygenArgs {
+    #[arg(
+        value_hint = ValueHint::FilePath,
+        long_help = "Where to write the private key; the public key goes next to it as <path>.pub\nKeep it where the recorded user cannot read or write it"
+    )]
+    output: PathBuf,
+
+    #[arg(long, long_help = "Make an X25519 key for --encrypt-to instead of a signing key")]
+    encryption: bool,
+}
+
+// a segment that verified on its own, to be checked against the rest of its recording
+struct Checked {
+    path: PathBuf,
+    number: u32,
+    report: seal::Report,
+}
+
+pub fn verify(args: VerifyArgs) -> anyhow::Result<()> {
+    let key = args.key.as_deref().map(seal::parse_public_key).transpose()?;
+    let files = expand(&args.files)?;
+    let mut failed = 0;
+    let mut recordings: BTreeMap<PathBuf, Vec<Checked>> = BTreeMap::new();
+    let mut heartbeats: BTreeMap<PathBuf, Vec<(PathBuf, heartbeat::Report)>> = BTreeMap::new();
+    for path in &files {
+        let mut data = Vec::new();
+        rotate::open_cast(path)
+            .and_then(|mut r| r.read_to_end(&mut data))
+            .with_context(|| format!("read {}", path.display()))?;
+        if is_heartbeat(path) {
+            let report = heartbeat::verify(&data, key.as_ref());
+            match &report.fault {
+                None => {
+                    let signed = match (report.signed, &key) {
+                        (false, _) => "not signed",
+                        (true, Some(_)) => "signed",
+                        (true, None) => "signed, not checked",
+                    };
+                    println!("{}: ok, {} heartbeats ({})", path.display(), report.records, signed);
+                    let dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+                    heartbeats.entry(dir).or_default().push((path.clone(), report));
+                }
+                Some((offset, reason)) => {
+                    failed += 1;
+                    println!("{}: FAILED at offset {}: {}", path.display(), offset, reason);
+                }
+            }
+            continue;
+        }
+        let report = seal::verify(&data, key.as_ref());
+        let signed = match key {
+            Some(_) => format!("{} signed", report.signed),
+            None => format!("{} signed, not checked", report.signed),
+        };
+        match &report.fault {
+            None => println!(
+                "{}: ok, {} checkpoints ({}), all {} bytes",
+                path.display(),
+                report.checkpoints,
+                signed,
+                report.len
+            ),
+            Some((offset, reason)) => {
+                failed += 1;
+                println!(
+                    "{}: FAILED at offset {}: {}; {} of {} bytes verified",
+                    path.display(),
+                    offset,
+                    reason,
+                    report.covered,
+                    report.len
+                );
+            }
+        }
+        if let Some((name, number)) = rotate::parse_segment_path(path) {
+            let recording = path.with_file_name(name);
+            let path = path.clone();
+            recordings
+                .entry(recording)
+                .or_default()
+                .push(Checked { path, number, report });
+        }
+    }
+
+    let mut broken = 0;
+    for (recording, mut segments) in recordings {
+        segments.sort_by_key(|s| s.number);
+        match follow(&segments) {
+            Ok(()) if segments.len() > 1 => println!(
+                "{}: ok, segments {} to {} follow each other",
+                recording.display(),
+                segments[0].number,
+                segments[segments.len() - 1].number
+            ),
+            Ok(()) => {}
+            Err(reason) => {
+                broken += 1;
+                println!("{}: FAILED: {}", recording.display(), reason);
+            }
+        }
+    }
+    for (dir, mut logs) in heartbeats {
+        // rotated logs are named by when they were moved aside, the current one comes last
+        logs.sort_by_key(|(path, _)| (is_current_heartbeat(path), path.clone()));
+        for pair in logs.windows(2) {
+            let ((before, earlier), (path, later)) = (&pair[0], &pair[1]);
+            if later.prev != earlier.last {
+                broken += 1;
+                println!(
+                    "{}: FAILED: {} does not follow {}",
+                    dir.display(),
+                    path.display(),
+                    before.display()
+                );
+            }
+        }
+    }
+
+    if failed > 0 || broken > 0 {
+        bail!(
+            "{} of {} files failed verification, {} recordings or heartbeat logs do not follow on",
+            failed,
+            files.len(),
+            broken
+        );
+    }
+    Ok(())
+}
+
+// the segments of one recording, in order: numbered as their files are, signed by one key, and
+// each naming the closing checkpoint of the one before
+fn follow(segments: &[Checked]) -> Result<(), String> {
+    let key_id = &segments[0].report.meta.key_id;
+    for (i, segment) in segments.iter().enumerate() {
+        let meta = &segment.report.meta;
+        if meta.segment != segment.number {
+            return Err(format!(
+                "{} says it is segment {}",
+                segment.path.display(),
+                meta.segment
+            ));
+        }
+        if &meta.key_id != key_id {
+            return Err(format!("segment {} names another signing key", segment.number));
+        }
+        if segment.number == 0 && meta.prev.is_some() {
+            return Err("segment 0 names a segment before it".to_owned());
+        }
+        let Some(before) = i.checked_sub(1).map(|i| &segments[i]) else {
+            continue;
+        };
+        if before.number == segment.number {
+            return Err(format!("segment {} is given twice", segment.number));
+        }
+        if before.number + 1 != segment.number {
+            return Err(format!("segment {} is missing", before.number + 1));
+        }
+        // reported already; what it closed with cannot be trusted
+        if before.report.fault.is_some() {
+            continue;
+        }
+        if meta.prev != before.report.closing.map(|link| seal::hex(&link)) {
+            return Err(format!(
+                "segment {} does not follow the closing checkpoint of segment {}",
+                segment.number, before.number
+            ));
+        }
+    }
+    Ok(())
+}
+
+// directories stand for the recordings and heartbeat logs in them
+fn expand(paths: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for path in paths {
+        if !path.is_dir() {
+            files.push(path.clone());
+            continue;
+        }
+        let mut found: Vec<PathBuf> = fs::read_dir(path)
+            .with_context(|| format!("read {}", path.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_heartbeat(path) || rotate::parse_segment_path(path).is_some())
+            .collect();
+        found.sort();
+        files.extend(found);
+    }
+    Ok(files)
+}
+
+fn is_heartbeat(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name == heartbeat::HEARTBEAT_FN || (name.starts_with("heartbeat-") && name.ends_with(".log"))
+}
+
+fn is_current_heartbeat(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == heartbeat::HEARTBEAT_FN)
+}
+
+pub fn keygen(args: KeygenArgs) -> anyhow::Result<()> {
+    let (secret, public) = match args.encryption {
+        true => {
+            let secret = crypt::generate_secret()?;
+            let public = x25519_dalek::PublicKey::from(&secret);
+            (secret.to_bytes(), public.to_bytes())
+        }
+        false => {
+            let key = seal::generate_key()?;
+            (key.to_bytes(), key.verifying_key().to_bytes())
+        }
+    };
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&args.output)
+        .with_context(|| format!("create {}", args.output.display()))?;
+    writeln!(file, "{}", seal::hex(&secret))?;
+
+    let public = seal::hex(&public);
+    let mut pub_path = args.output.into_os_string();
+    pub_path.push(".pub");
+    fs::write(&pub_path, format!("{}\n", public)).with_context(|| format!("write {}", pub_path.display()))?;
+    println!("{}", public);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::caster::Metadata;
+
+    // segment `number` closing with a link of its number, naming `prev` as the one before it
+    fn segment(number: u32, prev: Option<u8>) -> Checked {
+        Checked {
+            path: PathBuf::from(format!("rec.{number}.cast")),
+            number,
+            report: seal::Report {
+                meta: Metadata {
+                    segment: number,
+                    prev: prev.map(|link| seal::hex(&[link; 32])),
+                    ..Metadata::default()
+                },
+                closing: Some([number as u8; 32]),
+                ..seal::Report::default()
+            },
+        }
+    }
+
+    #[test]
+    fn segments_follow_on() {
+        assert_eq!(
+            follow(&[segment(0, None), segment(1, Some(0)), segment(2, Some(1))]),
+            Ok(())
+        );
+        // a recording kept from a later segment on
+        assert_eq!(follow(&[segment(3, Some(2)), segment(4, Some(3))]), Ok(()));
+    }
+
+    #[test]
+    fn segment_not_following() {
+        let err = follow(&[segment(0, None), segment(1, Some(0)), segment(2, Some(0))]).unwrap_err();
+        assert_eq!(err, "segment 2 does not follow the closing checkpoint of segment 1");
+        assert_eq!(
+            follow(&[segment(0, None), segment(2, Some(1))]).unwrap_err(),
+            "segment 1 is missing"
+        );
+        assert_eq!(
+            follow(&[segment(0, None), segment(0, None)]).unwrap_err(),
+            "segment 0 is given twice"
+        );
+        assert_eq!(
+            follow(&[segment(0, Some(9))]).unwrap_err(),
+            "segment 0 names a segment before it"
+        );
+
+        let mut renamed = segment(1, Some(0));
+        renamed.number = 2;
+        assert_eq!(follow(&[renamed]).unwrap_err(), "rec.1.cast says it is segment 1");
+
+        let mut rekeyed = segment(1, Some(0));
+        rekeyed.report.meta.key_id = Some("other".to_owned());
+        assert_eq!(
+            follow(&[segment(0, None), rekeyed]).unwrap_err(),
+            "segment 1 names another signing key"
+        );
+    }
+
+    #[test]
+    fn faulty_segment_is_not_followed() {
+        // its own failure was reported, its closing link is not held against the next one
+        let mut broken = segment(0, None);
+        broken.report.fault = Some((0, "cut short".to_owned()));
+        broken.report.closing = None;
+        assert_eq!(follow(&[broken, segment(1, Some(0))]), Ok(()));
+    }
+
+    #[test]
+    fn file_kinds() {
+        assert!(is_heartbeat(Path::new("/logs/heartbeat.log")));
+        assert!(is_heartbeat(Path::new("heartbeat-1700000000000.log")));
+        assert!(!is_heartbeat(Path::new("heartbeat.log.zst")));
+        assert!(is_current_heartbeat(Path::new("/logs/heartbeat.log")));
+        assert!(!is_current_heartbeat(Path::new("heartbeat-1.log")));
+    }
+}